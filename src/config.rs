@@ -0,0 +1,219 @@
+use chrono::{SecondsFormat, Utc};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::{Client, Config};
+use log::info;
+use std::env;
+use std::error::Error;
+use std::fmt::Display;
+use std::io::Write;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Reads a boolean flag (true/false) from the environment, defaulting to false when unset
+pub(crate) fn env_flag(name: &str) -> Result<bool, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => Ok(value
+            .parse::<bool>()
+            .map_err(|err| format!("invalid {} {:?}: {}", name, value, err))?),
+        Err(_) => Ok(false),
+    }
+}
+
+/// Reads a comma-separated list from the environment, ignoring empty entries. Empty when unset
+pub(crate) fn env_list(name: &str) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/// Reads a number of seconds from the environment, defaulting to `default` when unset
+pub(crate) fn env_secs(name: &str, default: Duration) -> Result<Duration, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => {
+            Ok(Duration::from_secs(value.parse::<u64>().map_err(
+                |err| format!("invalid {} {:?}: {}", name, value, err),
+            )?))
+        }
+        Err(_) => Ok(default),
+    }
+}
+
+/// Reads a number of milliseconds from the environment, defaulting to `default` when unset
+pub(crate) fn env_millis(name: &str, default: Duration) -> Result<Duration, Box<dyn Error>> {
+    Ok(env_parse::<u64>(name)?.map_or(default, Duration::from_millis))
+}
+
+/// Reads a positive number from the environment, defaulting to `default` when unset
+pub(crate) fn env_positive(name: &str, default: usize) -> Result<usize, Box<dyn Error>> {
+    match env_parse::<usize>(name)? {
+        Some(0) => Err(format!("invalid {} 0, expected a positive number", name).into()),
+        Some(value) => Ok(value),
+        None => Ok(default),
+    }
+}
+
+/// Reads and parses a value from the environment, None when unset
+pub(crate) fn env_parse<T>(name: &str) -> Result<Option<T>, Box<dyn Error>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => {
+            Ok(Some(value.parse::<T>().map_err(|err| {
+                format!("invalid {} {:?}: {}", name, value, err)
+            })?))
+        }
+        Err(_) => Ok(None),
+    }
+}
+
+/// Sets up env_logger (filtered by RUST_LOG) with the format picked by LOG_FORMAT: `text` (env_logger's own, the
+/// default) or `json`, one object per line with the `timestamp`, `level`, `target` and `message`. A logger set up by
+/// whatever embeds the controllers is kept rather than replaced
+pub(crate) fn init_logger() -> Result<(), Box<dyn Error>> {
+    let mut builder = env_logger::Builder::from_default_env();
+    match env::var("LOG_FORMAT").as_deref() {
+        Err(_) | Ok("text") => {}
+        Ok("json") => {
+            builder.format(|buf, record| {
+                let line = serde_json::json!({
+                    "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                });
+                writeln!(buf, "{}", line)
+            });
+        }
+        Ok(other) => {
+            return Err(format!("invalid LOG_FORMAT {:?}, expected text or json", other).into())
+        }
+    }
+    let _ = builder.try_init();
+    Ok(())
+}
+
+/// Reads ACCESS_LOG: `info` (the default) logs every request to the API at info level, `off` doesn't
+pub(crate) fn access_log_enabled() -> Result<bool, Box<dyn Error>> {
+    match env::var("ACCESS_LOG").as_deref() {
+        Err(_) | Ok("info") => Ok(true),
+        Ok("off") => Ok(false),
+        Ok(other) => Err(format!("invalid ACCESS_LOG {:?}, expected off or info", other).into()),
+    }
+}
+
+/// Reads the address to listen on from LISTEN_ADDR (an ip address, 127.0.0.1 by default) and LISTEN_PORT (8080 by
+/// default)
+pub(crate) fn listen_addr() -> Result<SocketAddr, Box<dyn Error>> {
+    let ip = env_parse::<IpAddr>("LISTEN_ADDR")?.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+    let port = env_parse::<u16>("LISTEN_PORT")?.unwrap_or(8080);
+    Ok(SocketAddr::new(ip, port))
+}
+
+/// Connects to the API server. With KUBECONFIG (the kubeconfig file, or `:`-separated files to merge) or KUBE_CONTEXT
+/// set, the client is built from that kubeconfig (~/.kube/config if only KUBE_CONTEXT is set) and context (its
+/// current context if only KUBECONFIG is set). Otherwise the local kubeconfig is used if there is one, and the
+/// in-cluster service account if not, like Client::try_default. Logs which one was used and the API server's url
+pub(crate) async fn kube_client() -> Result<Client, Box<dyn Error>> {
+    let context = env::var("KUBE_CONTEXT")
+        .ok()
+        .filter(|context| !context.is_empty());
+    let kubeconfig_set = env::var_os("KUBECONFIG").is_some_and(|path| !path.is_empty());
+    let config = if kubeconfig_set || context.is_some() {
+        let kubeconfig =
+            Kubeconfig::read().map_err(|err| format!("unable to read the kubeconfig: {}", err))?;
+        let context = context.or_else(|| kubeconfig.current_context.clone());
+        let options = KubeConfigOptions {
+            context: context.clone(),
+            ..KubeConfigOptions::default()
+        };
+        let mut config = Config::from_custom_kubeconfig(kubeconfig, &options)
+            .await
+            .map_err(|err| {
+                format!(
+                    "unable to load context {:?} of the kubeconfig: {}",
+                    context, err
+                )
+            })?;
+        config.apply_debug_overrides();
+        info!(
+            "Using context {} of the kubeconfig, API server {}",
+            context.unwrap_or_default(),
+            config.cluster_url
+        );
+        config
+    } else {
+        // the same order as Config::infer, which doesn't tell which one it used
+        let mut config = match Config::from_kubeconfig(&KubeConfigOptions::default()).await {
+            Ok(config) => {
+                info!(
+                    "Using the local kubeconfig, API server {}",
+                    config.cluster_url
+                );
+                config
+            }
+            Err(kubeconfig_err) => {
+                let config = Config::from_cluster_env().map_err(|in_cluster_err| {
+                    format!(
+                        "unable to load a kubeconfig ({}) or the in-cluster config ({})",
+                        kubeconfig_err, in_cluster_err
+                    )
+                })?;
+                info!("Running in-cluster, API server {}", config.cluster_url);
+                config
+            }
+        };
+        config.apply_debug_overrides();
+        config
+    };
+    Ok(Client::try_from(config)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::testing::{lock_env, rbac_controller};
+
+    #[test]
+    fn listen_addr_from_env() {
+        let _env = lock_env();
+        env::remove_var("LISTEN_ADDR");
+        env::remove_var("LISTEN_PORT");
+        assert_eq!(listen_addr().unwrap(), "127.0.0.1:8080".parse().unwrap());
+
+        env::set_var("LISTEN_ADDR", "0.0.0.0");
+        env::set_var("LISTEN_PORT", "9443");
+        assert_eq!(listen_addr().unwrap(), "0.0.0.0:9443".parse().unwrap());
+
+        env::set_var("LISTEN_ADDR", "::");
+        assert_eq!(listen_addr().unwrap(), "[::]:9443".parse().unwrap());
+
+        env::set_var("LISTEN_PORT", "70000");
+        assert!(listen_addr().is_err());
+        env::set_var("LISTEN_PORT", "9443");
+        env::set_var("LISTEN_ADDR", "localhost");
+        assert!(listen_addr().is_err());
+
+        env::remove_var("LISTEN_ADDR");
+        env::remove_var("LISTEN_PORT");
+    }
+
+    #[test]
+    fn controllers_can_be_set_up_twice() {
+        let _env = lock_env();
+        env::set_var("LOG_FORMAT", "json");
+        for _ in 0..2 {
+            init_logger().unwrap();
+            rbac_controller(vec![], vec![], vec![], vec![]);
+        }
+        env::remove_var("LOG_FORMAT");
+    }
+}
@@ -0,0 +1,67 @@
+use crate::config::env_secs;
+use actix_web::rt;
+use kube::Client;
+use log::warn;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// how often the api server is pinged when API_CHECK_INTERVAL_SECS isn't set
+const DEFAULT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// how long the api server may go without answering before readiness fails, when API_CHECK_STALE_SECS isn't set
+const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// Periodically pings the api server (through its /version endpoint) and remembers when it last answered, so that
+/// readiness reflects whether the api server is reachable right now and not just whether the watches synced once
+#[derive(Debug, Clone)]
+pub struct ApiServerCheck {
+    /// when the api server last answered, None if it hasn't yet
+    last_success: Arc<Mutex<Option<Instant>>>,
+    /// how long the api server may go without answering before it's considered unreachable
+    stale_after: Duration,
+}
+
+impl ApiServerCheck {
+    /// Starts pinging the api server. API_CHECK_INTERVAL_SECS controls how often, API_CHECK_STALE_SECS how long it
+    /// may go without answering
+    pub fn start(
+        client: Client,
+        shutdown: CancellationToken,
+    ) -> Result<ApiServerCheck, Box<dyn Error>> {
+        let interval = env_secs("API_CHECK_INTERVAL_SECS", DEFAULT_CHECK_INTERVAL)?;
+        let stale_after = env_secs("API_CHECK_STALE_SECS", DEFAULT_STALE_AFTER)?;
+        let check = ApiServerCheck {
+            last_success: Arc::new(Mutex::new(None)),
+            stale_after,
+        };
+        rt::spawn(shutdown.run_until_cancelled_owned(ping_api_server(
+            client,
+            interval,
+            check.last_success.clone(),
+        )));
+        Ok(check)
+    }
+
+    /// whether the api server answered within the last stale_after
+    pub(crate) fn is_reachable(&self) -> bool {
+        match *self.last_success.lock().unwrap() {
+            Some(last_success) => last_success.elapsed() <= self.stale_after,
+            None => false,
+        }
+    }
+}
+
+async fn ping_api_server(
+    client: Client,
+    interval: Duration,
+    last_success: Arc<Mutex<Option<Instant>>>,
+) {
+    loop {
+        match client.apiserver_version().await {
+            Ok(_) => *last_success.lock().unwrap() = Some(Instant::now()),
+            Err(err) => warn!("api server check failed: {}", err),
+        }
+        rt::time::sleep(interval).await;
+    }
+}
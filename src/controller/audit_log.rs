@@ -0,0 +1,220 @@
+use crate::controller::rbac_grant::{GrantSubject, RBACGrant};
+use chrono::{SecondsFormat, Utc};
+use log::{error, info};
+use serde::Serialize;
+use std::env;
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// size the audit log may grow to before it's rotated, when AUDIT_LOG_MAX_BYTES isn't set
+const DEFAULT_MAX_BYTES: u64 = 10 * 1024 * 1024;
+
+/// whether a subject gained or lost a grant
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditEventKind {
+    Added,
+    Removed,
+}
+
+/// one line of the audit log
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    timestamp: String,
+    event: AuditEventKind,
+    subject: &'a GrantSubject,
+    grant: &'a RBACGrant,
+}
+
+/// Optional append-only file recording every (subject, grant) pair the grant controller adds or removes, as one json
+/// object per line. Once the file reaches max_bytes it's moved to `<path>.1` (replacing the previous one) and a new
+/// file is started
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    max_bytes: u64,
+    /// the open log file and its current size, None until the first write or after a failed one
+    file: Mutex<Option<(File, u64)>>,
+    /// number of entries which couldn't be written
+    write_failures: AtomicU64,
+}
+
+impl AuditLog {
+    /// Reads the audit log config from the environment. Returns None (no audit log) unless AUDIT_LOG_PATH is set.
+    /// AUDIT_LOG_MAX_BYTES controls the size at which the log is rotated
+    pub fn from_env() -> Result<Option<AuditLog>, Box<dyn Error>> {
+        let path = match env::var("AUDIT_LOG_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => return Ok(None),
+        };
+        let max_bytes = match env::var("AUDIT_LOG_MAX_BYTES") {
+            Ok(value) => value
+                .parse::<u64>()
+                .map_err(|err| format!("invalid AUDIT_LOG_MAX_BYTES {:?}: {}", value, err))?,
+            Err(_) => DEFAULT_MAX_BYTES,
+        };
+        info!("Writing grant changes to {}", path.display());
+        Ok(Some(AuditLog {
+            path,
+            max_bytes,
+            file: Mutex::new(None),
+            write_failures: AtomicU64::new(0),
+        }))
+    }
+
+    /// appends an entry for each of the changes. Failures are logged and counted, but never stop the controller
+    pub(crate) fn record(&self, kind: AuditEventKind, changes: &[(GrantSubject, RBACGrant)]) {
+        if changes.is_empty() {
+            return;
+        }
+        let timestamp = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        let mut file = self.file.lock().unwrap();
+        for (subject, grant) in changes {
+            let entry = AuditEntry {
+                timestamp: timestamp.clone(),
+                event: kind,
+                subject,
+                grant,
+            };
+            if let Err(err) = self.write_entry(&mut file, &entry) {
+                error!(
+                    "Unable to write to the audit log {}: {}",
+                    self.path.display(),
+                    err
+                );
+                self.write_failures.fetch_add(1, Ordering::Relaxed);
+                // the file is reopened on the next write, in case it was moved or deleted
+            }
+        }
+    }
+
+    /// number of entries which couldn't be written since startup
+    pub(crate) fn write_failures(&self) -> u64 {
+        self.write_failures.load(Ordering::Relaxed)
+    }
+
+    fn write_entry(
+        &self,
+        file: &mut Option<(File, u64)>,
+        entry: &AuditEntry,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let (mut handle, mut size) = match file.take() {
+            Some(open) => open,
+            None => self.open()?,
+        };
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            drop(handle);
+            fs::rename(&self.path, self.rotated_path())?;
+            (handle, size) = self.open()?;
+        }
+        handle.write_all(&line)?;
+        *file = Some((handle, size + line.len() as u64));
+        Ok(())
+    }
+
+    /// opens the log for appending, along with its current size
+    fn open(&self) -> Result<(File, u64), Box<dyn Error>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    fn rotated_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".1");
+        PathBuf::from(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::metrics::{Metrics, StateCounts};
+    use crate::controller::testing::{role_binding, subject};
+
+    fn audit_log(path: PathBuf, max_bytes: u64) -> AuditLog {
+        AuditLog {
+            path,
+            max_bytes,
+            file: Mutex::new(None),
+            write_failures: AtomicU64::new(0),
+        }
+    }
+
+    fn change(name: &str) -> (GrantSubject, RBACGrant) {
+        let binding = role_binding(
+            "default",
+            "view",
+            "ClusterRole",
+            "view",
+            vec![subject("User", name, None)],
+        );
+        (
+            GrantSubject::from_subject(&subject("User", name, None), None),
+            RBACGrant::from_role_binding(&binding),
+        )
+    }
+
+    fn lines(path: &PathBuf) -> Vec<String> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn full_log_is_rotated() {
+        let dir =
+            std::env::temp_dir().join(format!("user-manifest-audit-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log = audit_log(dir.join("audit.log"), 1);
+
+        log.record(AuditEventKind::Added, &[change("alice")]);
+        log.record(AuditEventKind::Added, &[change("bob")]);
+        let current = lines(&log.path);
+        let rotated = lines(&log.rotated_path());
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the first entry filled the log, so it was moved aside before the second one was written to a new file
+        assert_eq!(rotated.len(), 1);
+        assert!(rotated[0].contains("alice"), "{}", rotated[0]);
+        assert_eq!(current.len(), 1);
+        assert!(current[0].contains("bob"), "{}", current[0]);
+        assert_eq!(log.write_failures(), 0);
+    }
+
+    #[test]
+    fn write_failures_are_exported() {
+        // a directory can't be opened for appending
+        let log = audit_log(std::env::temp_dir(), DEFAULT_MAX_BYTES);
+        log.record(AuditEventKind::Added, &[change("alice"), change("bob")]);
+        assert_eq!(log.write_failures(), 2);
+
+        let metrics = Metrics::new().unwrap();
+        for _ in 0..2 {
+            let output = metrics
+                .render(StateCounts {
+                    grants: 0,
+                    permissions: 0,
+                    subjects: 0,
+                    audit_log_write_failures: Some(log.write_failures()),
+                })
+                .unwrap();
+            assert!(
+                output.contains("user_manifest_audit_log_write_failures_total 2"),
+                "{}",
+                output
+            );
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use flate2::read::GzDecoder;
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// how often the controllers persist their state when CACHE_PERSIST_INTERVAL_SECS isn't set
+const DEFAULT_PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+/// version of the format of the stored snapshots, bumped whenever the stored types change incompatibly.
+/// Version 1 snapshots are the bare data, without the versioned envelope
+const SCHEMA_VERSION: u32 = 2;
+/// first bytes of a gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// envelope the snapshots are stored in, recording the format they were written in
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Snapshot<T> {
+    schema_version: u32,
+    data: T,
+}
+
+/// Optional on-disk copy of the controllers' state, so that a restarted controller can serve (stale) data right
+/// away instead of waiting on the initial list of every watch
+#[derive(Debug, Clone)]
+pub struct Cache {
+    /// directory the cache files are stored in
+    dir: PathBuf,
+    /// how often the controllers persist their state
+    pub(crate) persist_interval: Duration,
+}
+
+impl Cache {
+    /// Reads the cache config from the environment. Returns None (no caching) unless CACHE_DIR is set.
+    /// CACHE_PERSIST_INTERVAL_SECS controls how often state is persisted
+    pub fn from_env() -> Result<Option<Cache>, Box<dyn Error>> {
+        let dir = match env::var("CACHE_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => return Ok(None),
+        };
+        let persist_interval = match env::var("CACHE_PERSIST_INTERVAL_SECS") {
+            Ok(value) => Duration::from_secs(value.parse::<u64>().map_err(|err| {
+                format!("invalid CACHE_PERSIST_INTERVAL_SECS {:?}: {}", value, err)
+            })?),
+            Err(_) => DEFAULT_PERSIST_INTERVAL,
+        };
+        fs::create_dir_all(&dir)?;
+        info!("Caching state in {}", dir.display());
+        Ok(Some(Cache {
+            dir,
+            persist_interval,
+        }))
+    }
+
+    /// a cache in the directory, persisted at the default interval
+    #[cfg(test)]
+    pub(crate) fn in_dir(dir: PathBuf) -> Cache {
+        Cache {
+            dir,
+            persist_interval: DEFAULT_PERSIST_INTERVAL,
+        }
+    }
+
+    /// Loads a previously stored value, returning None if there isn't one or it can't be read. Gzipped snapshots
+    /// (e.g. restored from a compressed backup) are decompressed
+    pub(crate) fn load<T: DeserializeOwned>(&self, file_name: &str) -> Option<T> {
+        let path = self.dir.join(file_name);
+        let contents = match fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) => {
+                info!("No cache loaded from {}: {}", path.display(), err);
+                return None;
+            }
+        };
+        match parse_snapshot(&contents) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                warn!("Ignoring unreadable cache {}: {}", path.display(), err);
+                None
+            }
+        }
+    }
+
+    /// Stores the value, replacing the previous one. Writes to a temporary file first so that a crash mid-write
+    /// never leaves a truncated cache behind
+    pub(crate) fn store<T: Serialize>(&self, file_name: &str, value: &T) {
+        let path = self.dir.join(file_name);
+        let tmp_path = self.dir.join(format!("{}.tmp", file_name));
+        let snapshot = Snapshot {
+            schema_version: SCHEMA_VERSION,
+            data: value,
+        };
+        let result =
+            write_json(&tmp_path, &snapshot).and_then(|_| Ok(fs::rename(&tmp_path, &path)?));
+        if let Err(err) = result {
+            error!("Unable to persist cache {}: {}", path.display(), err);
+        }
+    }
+}
+
+/// parses a stored snapshot, checking that it was written in a format this version understands
+fn parse_snapshot<T: DeserializeOwned>(contents: &[u8]) -> Result<T, Box<dyn Error>> {
+    let mut decompressed = Vec::new();
+    let contents = if contents.starts_with(&GZIP_MAGIC) {
+        GzDecoder::new(contents).read_to_end(&mut decompressed)?;
+        &decompressed[..]
+    } else {
+        contents
+    };
+    let value: serde_json::Value = serde_json::from_slice(contents)?;
+    let schema_version = match value.get("schemaVersion") {
+        Some(version) => version
+            .as_u64()
+            .ok_or_else(|| format!("invalid schemaVersion {}", version))?,
+        // version 1 snapshots are the bare data, which is still stored the same way within the envelope
+        None => return Ok(serde_json::from_value(value)?),
+    };
+    if schema_version != SCHEMA_VERSION as u64 {
+        return Err(format!(
+            "snapshot has schemaVersion {}, but only versions 1 and {} are supported - it was written by an incompatible version",
+            schema_version, SCHEMA_VERSION
+        )
+        .into());
+    }
+    let snapshot: Snapshot<T> = serde_json::from_value(value)?;
+    Ok(snapshot.data)
+}
+
+fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use serde_json::json;
+
+    type Data = Vec<(String, u32)>;
+
+    fn data() -> Data {
+        vec![("alice".to_string(), 1), ("bob".to_string(), 2)]
+    }
+
+    #[test]
+    fn bare_v1_snapshot_loads() {
+        let contents = json!([["alice", 1], ["bob", 2]]).to_string();
+        assert_eq!(parse_snapshot::<Data>(contents.as_bytes()).unwrap(), data());
+    }
+
+    #[test]
+    fn v2_envelope_loads() {
+        let contents = json!({"schemaVersion": 2, "data": [["alice", 1], ["bob", 2]]}).to_string();
+        assert_eq!(parse_snapshot::<Data>(contents.as_bytes()).unwrap(), data());
+    }
+
+    #[test]
+    fn gzipped_v2_envelope_loads() {
+        let contents = json!({"schemaVersion": 2, "data": [["alice", 1], ["bob", 2]]}).to_string();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(contents.as_bytes()).unwrap();
+        let compressed = encoder.finish().unwrap();
+        assert_eq!(parse_snapshot::<Data>(&compressed).unwrap(), data());
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let contents = json!({"schemaVersion": 3, "data": [["alice", 1], ["bob", 2]]}).to_string();
+        let err = parse_snapshot::<Data>(contents.as_bytes()).unwrap_err();
+        assert!(err.to_string().contains("incompatible version"), "{}", err);
+    }
+}
@@ -0,0 +1,58 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Monotonic counter of the changes made to the controllers' state, shared by every controller. Comparing the
+/// generation two responses were served at tells whether they reflect the same state
+#[derive(Debug, Clone, Default)]
+pub struct Generation {
+    shared: Arc<Counters>,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    /// number of changes completed
+    completed: AtomicU64,
+    /// number of changes currently being made
+    in_progress: AtomicU64,
+}
+
+/// a change being made to the state, completed when dropped
+pub(crate) struct Change<'a> {
+    counters: &'a Counters,
+}
+
+impl Generation {
+    /// starts a change, which counts towards the generation once the returned guard is dropped. Changes made through
+    /// several separate locks should hold the guard throughout, so readers never see a generation for a partial change
+    pub(crate) fn begin_change(&self) -> Change<'_> {
+        self.shared.in_progress.fetch_add(1, Ordering::SeqCst);
+        Change {
+            counters: &self.shared,
+        }
+    }
+
+    /// the current generation, None while a change is being made
+    pub(crate) fn snapshot(&self) -> Option<u64> {
+        if self.shared.in_progress.load(Ordering::SeqCst) > 0 {
+            return None;
+        }
+        Some(self.shared.completed.load(Ordering::SeqCst))
+    }
+
+    /// the generation, if nothing changed since the snapshot was taken - everything read in between reflects exactly
+    /// that generation
+    pub(crate) fn unchanged_since(&self, snapshot: Option<u64>) -> Option<u64> {
+        let snapshot = snapshot?;
+        match self.snapshot() {
+            Some(current) if current == snapshot => Some(current),
+            _ => None,
+        }
+    }
+}
+
+impl Drop for Change<'_> {
+    fn drop(&mut self) {
+        self.counters.completed.fetch_add(1, Ordering::SeqCst);
+        self.counters.in_progress.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,16 +1,32 @@
-use crate::controller::rbac_grant::{GrantSubject, GrantType, RBACGrant};
+use crate::controller::audit_log::{AuditEventKind, AuditLog};
+use crate::controller::cache::Cache;
+use crate::controller::generation::Generation;
+use crate::controller::metrics::Metrics;
+use crate::controller::rbac_grant::{
+    GrantSubject, GrantType, RBACGrant, RBACId, SubjectKind, MANAGED_BY_LABEL,
+};
+use crate::controller::watch_scope::{
+    WatchBackoff, WatchScope, CLUSTER_ROLE_BINDINGS, ROLE_BINDINGS,
+};
 use actix_web::rt;
-use futures::{pin_mut, TryStreamExt};
-use k8s_openapi::api::rbac::v1::{ClusterRoleBinding, RoleBinding};
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use futures::future::BoxFuture;
+use futures::{pin_mut, FutureExt, Stream, TryStreamExt};
+use k8s_openapi::api::rbac::v1::{ClusterRoleBinding, RoleBinding, Subject};
 use kube::runtime::watcher::Event;
 use kube::{
     api::{Api, ListParams},
     runtime::watcher,
-    Client,
+    Client, Resource, ResourceExt,
 };
-use log::info;
+use log::{info, warn};
+use serde::de::DeserializeOwned;
 use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
+use std::fmt::Debug;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 
 // structure heavily influenced by https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs
 // TODO: Reduce/remove the use of .unwrap()
@@ -20,206 +36,1507 @@ pub struct GrantController {
     shared: Arc<Shared>,
 }
 
+/// file the grants are cached in, within the cache dir
+const GRANT_CACHE_FILE: &str = "grants.json";
+/// how long removed grants are remembered for, change queries further back than this get the full set of grants
+const REMOVED_GRANT_RETENTION_SECS: i64 = 60 * 60;
+/// changes buffered for each subscriber, a subscriber falling further behind misses the oldest ones
+const CHANGE_CHANNEL_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 struct Shared {
-    /// Shared state guarded by a mutex
-    state: Mutex<State>,
+    /// Shared state guarded by a read/write lock, so that the (many) readers don't wait on each other
+    state: RwLock<State>,
+    /// grant types which are watched, the state is synced once all of these have been listed
+    watched_types: Vec<GrantType>,
+    /// on-disk copy of the state, if caching is enabled
+    cache: Option<Cache>,
+    /// skip ServiceAccount subjects without a namespace instead of storing them
+    strict_subjects: bool,
+    /// file every added/removed (subject, grant) pair is recorded in, if enabled
+    audit_log: Option<AuditLog>,
+    /// counts the changes to the state, shared with the other controllers
+    generation: Generation,
+    /// counts the errors/restarts of the watches
+    metrics: Metrics,
+    /// subjects whose grants changed, see GrantController::subscribe
+    changes: broadcast::Sender<GrantSubject>,
 }
 
 #[derive(Debug)]
 struct State {
+    /// grants of each subject as listed in the bindings. Groups are stored as the single group subject and never
+    /// expanded into their members, so a binding to a broad group such as system:authenticated is one entry here.
+    /// Membership is resolved at query time instead (see GrantSubject::implicit_groups)
     user_to_grant: HashMap<GrantSubject, HashSet<RBACGrant>>,
+    /// subjects listed in each grant, likewise holding broad groups as a single subject
     grant_to_user: HashMap<RBACGrant, HashSet<GrantSubject>>,
+    /// grant types which have been fully listed since startup
+    synced_types: HashSet<GrantType>,
+    /// when each grant was last added or had its subjects changed
+    modified: HashMap<RBACGrant, DateTime<Utc>>,
+    /// when each grant was removed, kept for REMOVED_GRANT_RETENTION_SECS
+    removed: HashMap<RBACGrant, DateTime<Utc>>,
+    /// bindings which were skipped because they can never grant anything, with the reason why
+    malformed: HashMap<RBACGrant, &'static str>,
+    /// subjects left out of each grant in strict mode, see Shared::convert_subjects
+    skipped_subjects: HashMap<RBACGrant, Vec<GrantSubject>>,
+    /// the app.kubernetes.io/managed-by label of each grant's binding, for those which have one
+    managed_by: HashMap<RBACGrant, String>,
+    /// changes before this time aren't known - it's the startup time, or the time removals were last forgotten
+    tracked_since: DateTime<Utc>,
 }
 
-impl GrantController {
-    pub(crate) fn new(client: Client) -> GrantController {
-        let shared = Arc::new(Shared {
-            state: Mutex::new(State {
-                user_to_grant: HashMap::new(),
-                grant_to_user: HashMap::new(),
-            }),
-        });
+/// (subject, grant) pairs which were added or removed together
+type SubjectGrantPairs = Vec<(GrantSubject, RBACGrant)>;
+
+/// Everything recorded from the bindings of one grant type when they're all listed, see Shared::replace_all_of_type
+#[derive(Debug, Default)]
+struct ListedGrants {
+    grants: Vec<(GrantSubject, RBACGrant)>,
+    malformed: Vec<(RBACGrant, &'static str)>,
+    skipped_subjects: Vec<(RBACGrant, Vec<GrantSubject>)>,
+    managed_by: Vec<(RBACGrant, String)>,
+}
 
-        rt::spawn(refresh_role_bindings(client.clone(), shared.clone()));
-        rt::spawn(refresh_cluster_role_bindings(
-            client.clone(),
-            shared.clone(),
+/// Grants which changed since some time, see GrantController::get_changes_since
+#[derive(Debug, Clone)]
+pub struct GrantChanges {
+    /// whether changes reach back far enough, if not `changed` holds every grant and the previous state should be
+    /// replaced rather than updated
+    pub complete: bool,
+    /// grants added or changed, with their current subjects and when they were last modified
+    pub changed: Vec<(RBACGrant, HashSet<GrantSubject>, DateTime<Utc>)>,
+    /// grants removed
+    pub removed: Vec<RBACGrant>,
+}
+
+impl GrantController {
+    /// strict_subjects skips ServiceAccount subjects whose namespace can't be resolved, rather than storing them
+    /// without a namespace. Grants loaded from the cache aren't recorded in the audit log, changes the watches see
+    /// from then on are. Cancelling shutdown stops the watches
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        client: Client,
+        scope: &WatchScope,
+        cache: Option<Cache>,
+        strict_subjects: bool,
+        audit_log: Option<AuditLog>,
+        generation: Generation,
+        metrics: Metrics,
+        shutdown: CancellationToken,
+    ) -> GrantController {
+        let sources = grant_sources(scope);
+        let watched_types = sources.iter().map(|source| source.grant_type()).collect();
+        let shared = Arc::new(Shared::new(
+            watched_types,
+            cache,
+            strict_subjects,
+            audit_log,
+            generation,
+            metrics,
         ));
 
+        if let Some(cache) = &shared.cache {
+            // serve the cached grants until the watches have synced
+            shared.load_cache(cache);
+            rt::spawn(
+                shutdown.clone().run_until_cancelled_owned(persist_grants(
+                    cache.persist_interval,
+                    shared.clone(),
+                )),
+            );
+        }
+
+        for source in sources {
+            rt::spawn(
+                shutdown
+                    .clone()
+                    .run_until_cancelled_owned(source.watch(client.clone(), shared.clone())),
+            );
+        }
+
         GrantController { shared }
     }
-    pub(crate) fn get_grants_for_subject(
+
+    /// a controller holding the grants of the bindings as if they were just listed, without watching anything
+    #[cfg(test)]
+    pub(crate) fn from_bindings(
+        role_bindings: Vec<RoleBinding>,
+        cluster_role_bindings: Vec<ClusterRoleBinding>,
+    ) -> GrantController {
+        let shared = Shared::new(
+            vec![GrantType::RoleBinding, GrantType::ClusterRoleBinding],
+            None,
+            false,
+            None,
+            Generation::default(),
+            Metrics::new().unwrap(),
+        );
+        apply_event(&shared, Event::Restarted(role_bindings));
+        apply_event(&shared, Event::Restarted(cluster_role_bindings));
+        GrantController {
+            shared: Arc::new(shared),
+        }
+    }
+
+    /// Grants of each of the subjects, unioned with those of the groups given along with it, None for subjects
+    /// without any, read under a single lock
+    pub(crate) fn get_grants_for_each_subject(
+        &self,
+        subjects: &[(GrantSubject, Vec<GrantSubject>)],
+    ) -> Vec<(GrantSubject, Option<HashSet<RBACGrant>>)> {
+        let state = self.shared.state.read().unwrap();
+        subjects
+            .iter()
+            .map(|(subject, groups)| {
+                let grants: HashSet<RBACGrant> = std::iter::once(subject)
+                    .chain(groups)
+                    .filter_map(|subject| state.user_to_grant.get(subject))
+                    .flatten()
+                    .cloned()
+                    .collect();
+                (
+                    subject.clone(),
+                    Some(grants).filter(|grants| !grants.is_empty()),
+                )
+            })
+            .collect()
+    }
+
+    /// Grants of the subject unioned with those of the groups given along with it, read under a single lock. None if
+    /// neither the subject nor any of the groups has a grant
+    pub(crate) fn get_grants_of_subject_and_groups(
         &self,
         subject: &GrantSubject,
+        groups: &[GrantSubject],
     ) -> Option<HashSet<RBACGrant>> {
-        let mut state = self.shared.state.lock().unwrap();
-        let state = &mut *state;
-        match state.user_to_grant.get(subject) {
-            Some(grants) => Some(grants.clone()),
-            None => None,
+        let state = self.shared.state.read().unwrap();
+        let grants: HashSet<RBACGrant> = std::iter::once(subject)
+            .chain(groups)
+            .filter_map(|subject| state.user_to_grant.get(subject))
+            .flatten()
+            .cloned()
+            .collect();
+        if grants.is_empty() {
+            return None;
+        }
+        Some(grants)
+    }
+
+    /// Union of the grants of all of the subjects, read under a single lock
+    pub(crate) fn get_grants_for_subjects(&self, subjects: &[GrantSubject]) -> HashSet<RBACGrant> {
+        let state = self.shared.state.read().unwrap();
+        subjects
+            .iter()
+            .filter_map(|subject| state.user_to_grant.get(subject))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// The n subjects with the most grants along with their number of grants, most grants first
+    pub(crate) fn get_top_subjects(
+        &self,
+        n: usize,
+        excluded_managers: &[String],
+    ) -> Vec<(GrantSubject, usize)> {
+        let state = self.shared.state.read().unwrap();
+        let mut counts: Vec<(&GrantSubject, usize)> = state
+            .user_to_grant
+            .iter()
+            .map(|(subject, grants)| {
+                let count = grants
+                    .iter()
+                    .filter(|grant| !state.is_managed_by(grant, excluded_managers))
+                    .count();
+                (subject, count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        // most grants first, ties broken by kind, namespace and name so the output is stable - subjects of different
+        // kinds or namespaces can share a name
+        let compare = |a: &(&GrantSubject, usize), b: &(&GrantSubject, usize)| {
+            b.1.cmp(&a.1)
+                .then_with(|| a.0.kind.to_string().cmp(&b.0.kind.to_string()))
+                .then_with(|| a.0.namespace.cmp(&b.0.namespace))
+                .then_with(|| a.0.name.cmp(&b.0.name))
+        };
+        // only the top n need to be sorted, partition around the nth element first
+        if n < counts.len() {
+            if n > 0 {
+                counts.select_nth_unstable_by(n - 1, compare);
+            }
+            counts.truncate(n);
+        }
+        counts.sort_unstable_by(compare);
+        counts
+            .into_iter()
+            .map(|(subject, count)| (subject.clone(), count))
+            .collect()
+    }
+
+    /// grants added, changed or removed since the given time. If the changes from then aren't all known (it's before
+    /// startup, or removals from then have been forgotten) every grant is returned, as changed
+    pub(crate) fn get_changes_since(&self, since: DateTime<Utc>) -> GrantChanges {
+        let state = self.shared.state.read().unwrap();
+        let complete = since >= state.tracked_since;
+        let changed = state
+            .grant_to_user
+            .iter()
+            .filter_map(|(grant, subjects)| {
+                let modified = *state.modified.get(grant).unwrap_or(&state.tracked_since);
+                if complete && modified < since {
+                    return None;
+                }
+                Some((grant.clone(), subjects.clone(), modified))
+            })
+            .collect();
+        let removed = match complete {
+            true => state
+                .removed
+                .iter()
+                .filter(|(_, removed)| **removed >= since)
+                .map(|(grant, _)| grant.clone())
+                .collect(),
+            false => Vec::new(),
+        };
+        GrantChanges {
+            complete,
+            changed,
+            removed,
+        }
+    }
+
+    /// the grant with this identity, along with its subjects
+    pub(crate) fn get_grant(
+        &self,
+        grant_type: &GrantType,
+        namespace: &Option<String>,
+        name: &str,
+    ) -> Option<(RBACGrant, HashSet<GrantSubject>)> {
+        let state = self.shared.state.read().unwrap();
+        state
+            .grant_to_user
+            .iter()
+            .find(|(grant, _)| {
+                &grant.grant_type == grant_type
+                    && &grant.namespace == namespace
+                    && grant.name == name
+            })
+            .map(|(grant, subjects)| (grant.clone(), subjects.clone()))
+    }
+
+    /// bindings which were skipped because they can never grant anything, with the reason why
+    pub(crate) fn get_malformed_grants(&self) -> Vec<(RBACGrant, &'static str)> {
+        let state = self.shared.state.read().unwrap();
+        state
+            .malformed
+            .iter()
+            .map(|(grant, reason)| (grant.clone(), *reason))
+            .collect()
+    }
+
+    /// subjects which were left out of grants in strict mode, because their namespace couldn't be resolved
+    pub(crate) fn get_skipped_subjects(&self) -> Vec<(RBACGrant, GrantSubject)> {
+        let state = self.shared.state.read().unwrap();
+        state
+            .skipped_subjects
+            .iter()
+            .flat_map(|(grant, subjects)| {
+                subjects
+                    .iter()
+                    .map(|subject| (grant.clone(), subject.clone()))
+            })
+            .collect()
+    }
+
+    /// number of (subject, grant) pairs for each (grant namespace, subject kind), computed under the lock rather than
+    /// cloning the grants. Cluster-wide grants are counted under None
+    pub(crate) fn get_grant_breakdown(&self) -> HashMap<(Option<String>, SubjectKind), usize> {
+        let state = self.shared.state.read().unwrap();
+        let mut counts: HashMap<(Option<String>, SubjectKind), usize> = HashMap::new();
+        for (subject, grants) in &state.user_to_grant {
+            for grant in grants {
+                *counts
+                    .entry((grant.namespace.clone(), subject.kind.clone()))
+                    .or_default() += 1;
+            }
+        }
+        counts
+    }
+
+    /// the number of grants of each type and of subjects of each kind, read under a single lock
+    pub(crate) fn get_type_counts(
+        &self,
+    ) -> (HashMap<GrantType, usize>, HashMap<SubjectKind, usize>) {
+        let state = self.shared.state.read().unwrap();
+        let mut grant_types: HashMap<GrantType, usize> = HashMap::new();
+        for grant in state.grant_to_user.keys() {
+            *grant_types.entry(grant.grant_type.clone()).or_default() += 1;
+        }
+        let mut subject_kinds: HashMap<SubjectKind, usize> = HashMap::new();
+        for subject in state.user_to_grant.keys() {
+            *subject_kinds.entry(subject.kind.clone()).or_default() += 1;
+        }
+        (grant_types, subject_kinds)
+    }
+
+    /// the grants referencing the role, and the distinct subjects of those grants, read under a single lock
+    pub(crate) fn get_grants_for_role(
+        &self,
+        id: &RBACId,
+    ) -> (Vec<RBACGrant>, HashSet<GrantSubject>) {
+        let state = self.shared.state.read().unwrap();
+        let mut grants = Vec::new();
+        let mut subjects = HashSet::new();
+        for (grant, grant_subjects) in &state.grant_to_user {
+            if &grant.permissions_id == id {
+                grants.push(grant.clone());
+                subjects.extend(grant_subjects.iter().cloned());
+            }
+        }
+        (grants, subjects)
+    }
+
+    /// subjects bound to roles of the same name both by a RoleBinding and by a ClusterRoleBinding, which usually
+    /// means one of them is left over or misconfigured. Returns each such subject with the role name and the grants
+    /// of either type referencing a role of that name, read under a single lock
+    pub(crate) fn get_conflicting_grants(&self) -> Vec<(GrantSubject, String, Vec<RBACGrant>)> {
+        let state = self.shared.state.read().unwrap();
+        let mut conflicts = Vec::new();
+        for (subject, grants) in &state.user_to_grant {
+            let mut by_role_name: HashMap<&str, Vec<&RBACGrant>> = HashMap::new();
+            for grant in grants {
+                by_role_name
+                    .entry(grant.permissions_id.name.as_str())
+                    .or_default()
+                    .push(grant);
+            }
+            for (role_name, grants) in by_role_name {
+                let has_type = |grant_type: GrantType| {
+                    grants.iter().any(|grant| grant.grant_type == grant_type)
+                };
+                if has_type(GrantType::RoleBinding) && has_type(GrantType::ClusterRoleBinding) {
+                    conflicts.push((
+                        subject.clone(),
+                        role_name.to_string(),
+                        grants.into_iter().cloned().collect(),
+                    ));
+                }
+            }
         }
+        conflicts
+    }
+
+    /// ids of the roles referenced by any grant
+    pub(crate) fn get_referenced_permission_ids(&self) -> HashSet<RBACId> {
+        let state = self.shared.state.read().unwrap();
+        state
+            .grant_to_user
+            .keys()
+            .map(|grant| grant.permissions_id.clone())
+            .collect()
+    }
+
+    /// the number of subjects with grants, and whether every watched grant type has been listed - until then grants
+    /// may be missing or, if they were loaded from the cache, stale. Read under a single lock without copying the grants
+    pub(crate) fn get_status(&self) -> (usize, bool) {
+        let state = self.shared.state.read().unwrap();
+        let synced = self
+            .shared
+            .watched_types
+            .iter()
+            .all(|grant_type| state.synced_types.contains(grant_type));
+        (state.user_to_grant.len(), synced)
+    }
+
+    /// like get_grants, leaving out the grants whose binding is managed by (has an app.kubernetes.io/managed-by label
+    /// of) any of excluded_managers. Subjects left without grants are dropped
+    pub(crate) fn get_grants_excluding_managed_by(
+        &self,
+        excluded_managers: &[String],
+    ) -> HashMap<GrantSubject, HashSet<RBACGrant>> {
+        let state = self.shared.state.read().unwrap();
+        state
+            .user_to_grant
+            .iter()
+            .map(|(subject, grants)| {
+                let grants: HashSet<RBACGrant> = grants
+                    .iter()
+                    .filter(|grant| !state.is_managed_by(grant, excluded_managers))
+                    .cloned()
+                    .collect();
+                (subject.clone(), grants)
+            })
+            .filter(|(_, grants)| !grants.is_empty())
+            .collect()
+    }
+
+    /// number of audit log entries which couldn't be written, None if the audit log isn't enabled
+    pub(crate) fn get_audit_log_write_failures(&self) -> Option<u64> {
+        self.shared.audit_log.as_ref().map(AuditLog::write_failures)
+    }
+
+    /// the number of grants, without copying them
+    pub(crate) fn get_grant_count(&self) -> usize {
+        let state = self.shared.state.read().unwrap();
+        state.grant_to_user.len()
+    }
+
+    /// every subject with grants, without copying the grants
+    pub(crate) fn get_subjects(&self) -> Vec<GrantSubject> {
+        let state = self.shared.state.read().unwrap();
+        state.user_to_grant.keys().cloned().collect()
     }
 
     pub(crate) fn get_grants(&self) -> HashMap<GrantSubject, HashSet<RBACGrant>> {
-        let mut state = self.shared.state.lock().unwrap();
-        let state = &mut *state;
+        let state = self.shared.state.read().unwrap();
         state.user_to_grant.clone()
     }
+
+    /// receives every subject which gains or loses a grant from now on, once the change is applied. Like the audit
+    /// log, relists only publish the subjects whose grants actually changed, and loading the cache publishes nothing.
+    /// Receivers which fall behind by more than CHANGE_CHANNEL_CAPACITY changes miss the oldest ones
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<GrantSubject> {
+        self.shared.changes.subscribe()
+    }
 }
 
 impl Shared {
-    fn remove_grant_for_subject(&self, subject: &GrantSubject, grant: &RBACGrant) {
-        // as outlined in the mini-redis, necessary to acquire lock/access state
-        let mut state = self.state.lock().unwrap();
-        let state = &mut *state;
-        match state.user_to_grant.get_mut(subject) {
-            Some(grants) => grants.remove(grant),
-            // no need to remove grants if we don't have any for this user
-            // hack to make the match arms match return type
-            None => false,
+    /// empty state, synced once every one of watched_types has been listed
+    fn new(
+        watched_types: Vec<GrantType>,
+        cache: Option<Cache>,
+        strict_subjects: bool,
+        audit_log: Option<AuditLog>,
+        generation: Generation,
+        metrics: Metrics,
+    ) -> Shared {
+        Shared {
+            state: RwLock::new(State {
+                user_to_grant: HashMap::new(),
+                grant_to_user: HashMap::new(),
+                synced_types: HashSet::new(),
+                modified: HashMap::new(),
+                removed: HashMap::new(),
+                malformed: HashMap::new(),
+                skipped_subjects: HashMap::new(),
+                managed_by: HashMap::new(),
+                tracked_since: Utc::now(),
+            }),
+            watched_types,
+            cache,
+            strict_subjects,
+            audit_log,
+            generation,
+            metrics,
+            changes: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    fn is_synced(&self) -> bool {
+        let state = self.state.read().unwrap();
+        self.watched_types
+            .iter()
+            .all(|grant_type| state.synced_types.contains(grant_type))
+    }
+
+    /// records that grant_type has been fully listed. Once everything is synced, the cache is rewritten right away so
+    /// that it no longer holds the state from before the restart
+    fn mark_synced(&self, grant_type: GrantType) {
+        {
+            let mut state = self.state.write().unwrap();
+            state.synced_types.insert(grant_type);
+        }
+        if self.is_synced() {
+            self.persist();
+        }
+    }
+
+    /// adds the grants stored in the cache, if it has any. Their role ids are normalized, as older versions stored
+    /// ids which don't match those of the roles
+    fn load_cache(&self, cache: &Cache) {
+        let cached = match cache.load::<Vec<(GrantSubject, HashSet<RBACGrant>)>>(GRANT_CACHE_FILE) {
+            Some(cached) => cached,
+            None => return,
         };
-        match state.grant_to_user.get_mut(grant) {
-            Some(users) => users.remove(subject),
-            // hack to make the match arms match return type
-            None => false,
+        let _change = self.generation.begin_change();
+        info!("Loaded grants for {} subjects from the cache", cached.len());
+        for (subject, grants) in cached {
+            for mut grant in grants {
+                grant.permissions_id = grant.permissions_id.normalized();
+                self.add_grant_for_subject(&subject, &grant);
+            }
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(cache) = &self.cache {
+            let grants: Vec<(GrantSubject, HashSet<RBACGrant>)> = {
+                let state = self.state.read().unwrap();
+                state.user_to_grant.clone().into_iter().collect()
+            };
+            cache.store(GRANT_CACHE_FILE, &grants);
+        }
+    }
+
+    /// stores an added or changed grant with its subjects, the subjects which were left out of it and its binding's
+    /// managed-by label. This happens under a single lock (like replace_all_of_type) so readers never see the grant
+    /// with only part of its old or new subjects. Returns the subjects it had before, the changes are recorded
+    /// afterwards by the caller, see record_subject_changes
+    fn apply_grant(
+        &self,
+        grant: &RBACGrant,
+        subjects: &[GrantSubject],
+        skipped: Vec<GrantSubject>,
+        managed_by: Option<String>,
+    ) -> HashSet<GrantSubject> {
+        let mut state = self.state.write().unwrap();
+        let state = &mut *state;
+        // the binding may have been malformed before this change
+        state.malformed.remove(grant);
+        let previous = state.grant_to_user.remove(grant).unwrap_or_default();
+        for subject in &previous {
+            state.remove_grant_for_subject(subject, grant);
+        }
+        // grants without subjects are still tracked, e.g. for get_changes_since
+        state.grant_to_user.entry(grant.clone()).or_default();
+        for subject in subjects {
+            state.add_grant_for_subject(subject, grant);
+        }
+        if skipped.is_empty() {
+            state.skipped_subjects.remove(grant);
+        } else {
+            insert_grant(&mut state.skipped_subjects, grant, skipped);
+        }
+        match managed_by {
+            Some(managed_by) => insert_grant(&mut state.managed_by, grant, managed_by),
+            None => state.managed_by.remove(grant),
         };
+        state.mark_modified(grant, Utc::now());
+        previous
     }
 
     fn add_grant_for_subject(&self, subject: &GrantSubject, grant: &RBACGrant) {
         // as outlined in the mini-redis, necessary to acquire lock/access state
-        let mut state = self.state.lock().unwrap();
-        let state = &mut *state;
-        // provide defaults for grants/users in case we don't have a record for this user yet
-        let current_grants = state
-            .user_to_grant
-            .entry(subject.clone())
-            .or_insert(HashSet::new());
-        current_grants.insert(grant.clone());
+        let mut state = self.state.write().unwrap();
+        state.add_grant_for_subject(subject, grant);
+    }
 
-        let current_users = state
-            .grant_to_user
-            .entry(grant.clone())
-            .or_insert(HashSet::new());
-        current_users.insert(subject.clone());
+    /// records the changes in the audit log, if it's enabled. Called without holding the state lock
+    fn audit(&self, kind: AuditEventKind, changes: &[(GrantSubject, RBACGrant)]) {
+        if let Some(audit_log) = &self.audit_log {
+            audit_log.record(kind, changes);
+        }
     }
 
-    fn get_current_subjects_for_grant(&self, grant: &RBACGrant) -> Option<HashSet<GrantSubject>> {
-        let mut state = self.state.lock().unwrap();
-        let state = &mut *state;
-        match state.grant_to_user.get(grant) {
-            Some(subjects) => Some(subjects.clone()),
-            None => None,
+    /// records the added and removed (subject, grant) pairs in the audit log, and notifies the subscribers of each
+    /// subject whose grants changed. Called without holding the state lock
+    fn record_changes(
+        &self,
+        added: &[(GrantSubject, RBACGrant)],
+        removed: &[(GrantSubject, RBACGrant)],
+    ) {
+        self.audit(AuditEventKind::Added, added);
+        self.audit(AuditEventKind::Removed, removed);
+        let mut published: HashSet<&GrantSubject> = HashSet::new();
+        for (subject, _) in added.iter().chain(removed) {
+            // sending only fails when nobody is subscribed, which is fine
+            if published.insert(subject) {
+                let _ = self.changes.send(subject.clone());
+            }
         }
     }
 
-    fn remove_grant(&self, grant: &RBACGrant) {
-        let mut state = self.state.lock().unwrap();
-        let state = &mut *state;
-        let default: HashSet<GrantSubject> = HashSet::new();
-        let subjects = match state.grant_to_user.get(grant) {
-            Some(subs) => subs,
-            None => &default,
-        };
-        for sub in subjects {
-            state.user_to_grant.entry(sub.clone()).and_modify(|e| {
-                _ = e.remove(grant);
+    /// whether anything records the added/removed pairs, if not they don't need to be worked out
+    fn records_changes(&self) -> bool {
+        self.audit_log.is_some() || self.changes.receiver_count() > 0
+    }
+
+    /// records the subjects a grant gained and lost when its binding changed, see record_changes
+    fn record_subject_changes(
+        &self,
+        grant: &RBACGrant,
+        previous: &HashSet<GrantSubject>,
+        current: &[GrantSubject],
+    ) {
+        let added: SubjectGrantPairs = current
+            .iter()
+            .filter(|subject| !previous.contains(*subject))
+            .map(|subject| (subject.clone(), grant.clone()))
+            .collect();
+        let removed: SubjectGrantPairs = previous
+            .iter()
+            .filter(|subject| !current.contains(*subject))
+            .map(|subject| (subject.clone(), grant.clone()))
+            .collect();
+        self.record_changes(&added, &removed);
+    }
+
+    /// converts the subjects of a binding, returning the subjects to store and those which were skipped. In strict
+    /// mode ServiceAccounts whose namespace can't be resolved (from the subject or the binding) are skipped, as it's
+    /// ambiguous which ServiceAccount they are
+    fn convert_subjects(
+        &self,
+        grant: &RBACGrant,
+        subjects: &[Subject],
+        binding_namespace: Option<String>,
+    ) -> (Vec<GrantSubject>, Vec<GrantSubject>) {
+        let (skipped, accepted) = subjects
+            .iter()
+            .map(|subject| GrantSubject::from_subject(subject, binding_namespace.clone()))
+            .partition::<Vec<_>, _>(|subject| {
+                self.strict_subjects
+                    && subject.kind == SubjectKind::ServiceAccount
+                    && subject.namespace.is_none()
             });
+        for subject in &skipped {
+            warn!(
+                "skipping ServiceAccount {} of {} {}, its namespace can't be resolved",
+                subject.name, grant.grant_type, grant.name
+            );
         }
-        state.grant_to_user.remove(grant);
+        (accepted, skipped)
+    }
+
+    /// records a binding which was skipped because it can never grant anything
+    fn mark_malformed(&self, grant: &RBACGrant, reason: &'static str) {
+        let mut state = self.state.write().unwrap();
+        insert_grant(&mut state.malformed, grant, reason);
+    }
+
+    fn remove_grant(&self, grant: &RBACGrant) {
+        let removed: SubjectGrantPairs = {
+            let mut state = self.state.write().unwrap();
+            let state = &mut *state;
+            // malformed bindings were never added
+            if state.malformed.remove(grant).is_some() {
+                return;
+            }
+            state.skipped_subjects.remove(grant);
+            state.managed_by.remove(grant);
+            let subjects = state.grant_to_user.remove(grant).unwrap_or_default();
+            for sub in &subjects {
+                state.remove_grant_for_subject(sub, grant);
+            }
+            state.mark_removed(grant, Utc::now());
+            subjects
+                .into_iter()
+                .map(|subject| (subject, grant.clone()))
+                .collect()
+        };
+        self.record_changes(&[], &removed);
     }
 
-    fn remove_all_of_type(&self, grant_type: GrantType) {
-        let mut state = self.state.lock().unwrap();
+    /// replaces every grant of grant_type with the listed (subject, grant) pairs, and everything else recorded about
+    /// the bindings of grant_type with what was listed. This happens under a single lock so readers never see a partially rebuilt
+    /// state, and two restarts of the same type can't interleave - the last one to run fully determines the grants of
+    /// its type. The (subject, grant) pairs added and removed by the relist are recorded afterwards, see record_changes
+    fn replace_all_of_type(&self, grant_type: GrantType, listed: ListedGrants) {
+        let (added, removed) = self.replace_all_of_type_locked(grant_type, listed);
+        self.record_changes(&added, &removed);
+    }
+
+    /// does the replacement for replace_all_of_type, returning the added and removed (subject, grant) pairs if they're
+    /// recorded
+    fn replace_all_of_type_locked(
+        &self,
+        grant_type: GrantType,
+        listed: ListedGrants,
+    ) -> (SubjectGrantPairs, SubjectGrantPairs) {
+        let mut state = self.state.write().unwrap();
         let state = &mut *state;
+        state.malformed.retain(|k, _| k.grant_type != grant_type);
+        state.malformed.extend(listed.malformed);
+        state
+            .skipped_subjects
+            .retain(|k, _| k.grant_type != grant_type);
+        state.skipped_subjects.extend(
+            listed
+                .skipped_subjects
+                .into_iter()
+                .filter(|(_, subjects)| !subjects.is_empty()),
+        );
+        state.managed_by.retain(|k, _| k.grant_type != grant_type);
+        state.managed_by.extend(listed.managed_by);
         let user_grants = state.user_to_grant.values_mut();
         for grants in user_grants {
             grants.retain(|k| k.grant_type != grant_type);
         }
-        state
+        let mut previous: HashMap<RBACGrant, HashSet<GrantSubject>> = HashMap::new();
+        state.grant_to_user.retain(|k, subjects| {
+            if k.grant_type != grant_type {
+                return true;
+            }
+            previous.insert(k.clone(), std::mem::take(subjects));
+            false
+        });
+        for (subject, grant) in listed.grants {
+            state.add_grant_for_subject(&subject, &grant);
+        }
+        // subjects which only had grants of grant_type, and weren't listed again, have none left
+        state.user_to_grant.retain(|_, grants| !grants.is_empty());
+        // only grants which actually differ from before the restart count as changed
+        let now = Utc::now();
+        let current: Vec<RBACGrant> = state
             .grant_to_user
-            .retain(|k, _| k.grant_type != grant_type);
+            .iter()
+            .filter(|(grant, subjects)| {
+                grant.grant_type == grant_type && previous.get(*grant) != Some(*subjects)
+            })
+            .map(|(grant, _)| grant.clone())
+            .collect();
+        for grant in current {
+            state.mark_modified(&grant, now);
+        }
+        for grant in previous.keys() {
+            if !state.grant_to_user.contains_key(grant) {
+                state.mark_removed(grant, now);
+            }
+        }
+        if !self.records_changes() {
+            return (Vec::new(), Vec::new());
+        }
+        let no_subjects = HashSet::new();
+        let mut added = Vec::new();
+        for (grant, subjects) in &state.grant_to_user {
+            if grant.grant_type != grant_type {
+                continue;
+            }
+            let previous_subjects = previous.get(grant).unwrap_or(&no_subjects);
+            for subject in subjects.difference(previous_subjects) {
+                added.push((subject.clone(), grant.clone()));
+            }
+        }
+        let mut removed = Vec::new();
+        for (grant, previous_subjects) in &previous {
+            let subjects = state.grant_to_user.get(grant).unwrap_or(&no_subjects);
+            for subject in previous_subjects.difference(subjects) {
+                removed.push((subject.clone(), grant.clone()));
+            }
+        }
+        (added, removed)
     }
 }
 
-async fn refresh_role_bindings(client: Client, shared: Arc<Shared>) {
-    info!("Starting role binding controller");
-    let role_binding_api = Api::<RoleBinding>::all(client.clone());
-    let role_binding_watcher = watcher(role_binding_api, ListParams::default());
-    pin_mut!(role_binding_watcher);
-    while let Ok(Some(event)) = role_binding_watcher.try_next().await {
-        match event {
-            Event::Applied(role_binding) => {
-                let subjects = role_binding.clone().subjects.unwrap_or_default();
-                let grant = RBACGrant::from_role_binding(&role_binding);
-                let previous_subjects = match shared.get_current_subjects_for_grant(&grant) {
-                    Some(subs) => subs,
-                    None => HashSet::new(),
-                };
-                for previous_subject in previous_subjects {
-                    shared.remove_grant_for_subject(&previous_subject, &grant);
-                }
-                for subject in subjects {
-                    let grant_subject = GrantSubject::from_subject(&subject);
-                    shared.add_grant_for_subject(&grant_subject, &grant);
-                }
+impl State {
+    /// whether the grant's binding is managed by any of the managers
+    fn is_managed_by(&self, grant: &RBACGrant, managers: &[String]) -> bool {
+        match self.managed_by.get(grant) {
+            Some(managed_by) => managers.contains(managed_by),
+            None => false,
+        }
+    }
+
+    fn add_grant_for_subject(&mut self, subject: &GrantSubject, grant: &RBACGrant) {
+        // provide defaults for grants/users in case we don't have a record for this user yet
+        let current_grants = self.user_to_grant.entry(subject.clone()).or_default();
+        // an equal grant may reference another role, replace it with the current version
+        current_grants.replace(grant.clone());
+
+        let current_users = self.grant_to_user.entry(grant.clone()).or_default();
+        current_users.insert(subject.clone());
+    }
+
+    /// removes the grant from the subject's grants, and the subject once it has none left. Leaves grant_to_user as is
+    fn remove_grant_for_subject(&mut self, subject: &GrantSubject, grant: &RBACGrant) {
+        if let Some(grants) = self.user_to_grant.get_mut(subject) {
+            grants.remove(grant);
+            if grants.is_empty() {
+                self.user_to_grant.remove(subject);
             }
-            Event::Restarted(role_bindings) => {
-                shared.remove_all_of_type(GrantType::RoleBinding);
-                for binding in role_bindings {
-                    let grant = RBACGrant::from_role_binding(&binding);
-                    let subjects = binding.clone().subjects.unwrap_or_default();
-                    for subject in subjects {
-                        let grant_subject = GrantSubject::from_subject(&subject);
-                        shared.add_grant_for_subject(&grant_subject, &grant)
-                    }
+        }
+    }
+
+    fn mark_modified(&mut self, grant: &RBACGrant, now: DateTime<Utc>) {
+        self.removed.remove(grant);
+        insert_grant(&mut self.modified, grant, now);
+    }
+
+    /// records the removal, forgetting removals older than REMOVED_GRANT_RETENTION_SECS. Changes from before the
+    /// oldest remembered removal are no longer fully known, so tracked_since moves up to it
+    fn mark_removed(&mut self, grant: &RBACGrant, now: DateTime<Utc>) {
+        self.modified.remove(grant);
+        insert_grant(&mut self.removed, grant, now);
+        let horizon = now - ChronoDuration::seconds(REMOVED_GRANT_RETENTION_SECS);
+        let before = self.removed.len();
+        self.removed.retain(|_, removed| *removed >= horizon);
+        if self.removed.len() != before && self.tracked_since < horizon {
+            self.tracked_since = horizon;
+        }
+    }
+}
+
+/// inserts the value for the grant, also replacing the key: grants are equal regardless of the role they reference, and
+/// HashMap::insert would keep the previous version of the grant as the key
+fn insert_grant<V>(map: &mut HashMap<RBACGrant, V>, grant: &RBACGrant, value: V) -> Option<V> {
+    let previous = map.remove(grant);
+    map.insert(grant.clone(), value);
+    previous
+}
+
+/// A kind of binding grants are made by. Each source watches its bindings and keeps the grants of its grant type up
+/// to date in the shared state, so that other kinds of bindings can be watched without another copy of the watch
+trait GrantSource: Send + Sync {
+    /// the type of the grants the bindings make
+    fn grant_type(&self) -> GrantType;
+
+    /// watches the bindings for as long as the future runs, restarting the watch whenever it ends
+    fn watch(&self, client: Client, shared: Arc<Shared>) -> BoxFuture<'static, ()>;
+}
+
+/// RoleBindings, of the watched namespace or of every namespace
+struct RoleBindingSource {
+    scope: WatchScope,
+}
+
+impl GrantSource for RoleBindingSource {
+    fn grant_type(&self) -> GrantType {
+        GrantType::RoleBinding
+    }
+
+    fn watch(&self, client: Client, shared: Arc<Shared>) -> BoxFuture<'static, ()> {
+        watch_bindings(
+            self.scope.namespaced_api::<RoleBinding>(client),
+            self.scope.list_params(),
+            shared,
+        )
+        .boxed()
+    }
+}
+
+/// ClusterRoleBindings
+struct ClusterRoleBindingSource {
+    list_params: ListParams,
+}
+
+impl GrantSource for ClusterRoleBindingSource {
+    fn grant_type(&self) -> GrantType {
+        GrantType::ClusterRoleBinding
+    }
+
+    fn watch(&self, client: Client, shared: Arc<Shared>) -> BoxFuture<'static, ()> {
+        watch_bindings(
+            Api::<ClusterRoleBinding>::all(client),
+            self.list_params.clone(),
+            shared,
+        )
+        .boxed()
+    }
+}
+
+/// the sources of the bindings the scope watches
+fn grant_sources(scope: &WatchScope) -> Vec<Box<dyn GrantSource>> {
+    let mut sources: Vec<Box<dyn GrantSource>> = Vec::new();
+    if scope.watches(ROLE_BINDINGS) {
+        sources.push(Box::new(RoleBindingSource {
+            scope: scope.clone(),
+        }));
+    }
+    if scope.watches(CLUSTER_ROLE_BINDINGS) {
+        sources.push(Box::new(ClusterRoleBindingSource {
+            list_params: scope.list_params(),
+        }));
+    }
+    sources
+}
+
+/// A k8s resource which binds subjects to a role, as read by watch_bindings
+trait Binding: Resource<DynamicType = ()> + Clone + DeserializeOwned + Debug + Send + 'static {
+    const GRANT_TYPE: GrantType;
+    /// the WATCH_RESOURCES name of the bindings, which their watch metrics are labelled with
+    const RESOURCE: &'static str;
+    /// how the bindings are called in the logs
+    const DESCRIPTION: &'static str;
+
+    fn to_grant(&self) -> RBACGrant;
+
+    fn subjects(&self) -> Vec<Subject>;
+}
+
+impl Binding for RoleBinding {
+    const GRANT_TYPE: GrantType = GrantType::RoleBinding;
+    const RESOURCE: &'static str = ROLE_BINDINGS;
+    const DESCRIPTION: &'static str = "role binding";
+
+    fn to_grant(&self) -> RBACGrant {
+        RBACGrant::from_role_binding(self)
+    }
+
+    fn subjects(&self) -> Vec<Subject> {
+        self.subjects.clone().unwrap_or_default()
+    }
+}
+
+impl Binding for ClusterRoleBinding {
+    const GRANT_TYPE: GrantType = GrantType::ClusterRoleBinding;
+    const RESOURCE: &'static str = CLUSTER_ROLE_BINDINGS;
+    const DESCRIPTION: &'static str = "cluster role binding";
+
+    fn to_grant(&self) -> RBACGrant {
+        RBACGrant::from_cluster_role_binding(self)
+    }
+
+    fn subjects(&self) -> Vec<Subject> {
+        self.subjects.clone().unwrap_or_default()
+    }
+}
+
+async fn watch_bindings<K: Binding>(api: Api<K>, list_params: ListParams, shared: Arc<Shared>) {
+    watch_binding_events(|| watcher(api.clone(), list_params.clone()), shared).await
+}
+
+/// applies the events of the watches start_watch creates, starting another one (after a backoff) whenever one ends.
+/// Errors don't end a watch, it's polled again after the backoff
+async fn watch_binding_events<K, S>(mut start_watch: impl FnMut() -> S, shared: Arc<Shared>)
+where
+    K: Binding,
+    S: Stream<Item = Result<Event<K>, watcher::Error>>,
+{
+    info!("Starting {} controller", K::DESCRIPTION);
+    let mut backoff = WatchBackoff::default();
+    loop {
+        let binding_watcher = start_watch();
+        pin_mut!(binding_watcher);
+        loop {
+            let event = match binding_watcher.try_next().await {
+                Ok(Some(event)) => {
+                    backoff.reset();
+                    event
                 }
-            }
-            Event::Deleted(role_binding) => {
-                let grant = RBACGrant::from_role_binding(&role_binding);
-                shared.remove_grant(&grant);
-            }
+                Ok(None) => break,
+                Err(err) => {
+                    // polling the watcher again resumes from the last resource version/bookmark it saw, it only
+                    // relists (and sends Restarted) if that version is too old
+                    shared.metrics.watch_error(K::RESOURCE);
+                    warn!(
+                        "{} watch failed, resuming in {:?}: {}",
+                        K::DESCRIPTION,
+                        backoff.delay(),
+                        err
+                    );
+                    backoff.wait().await;
+                    continue;
+                }
+            };
+            apply_event(&shared, event);
         }
+        // the new watcher relists, the state is only replaced once that list arrives (as Restarted)
+        shared.metrics.watch_restart(K::RESOURCE);
+        warn!(
+            "{} watch ended, restarting it in {:?}",
+            K::DESCRIPTION,
+            backoff.delay()
+        );
+        backoff.wait().await;
     }
 }
 
-async fn refresh_cluster_role_bindings(client: Client, shared: Arc<Shared>) {
-    info!("Starting cluster role binding controller");
-    let binding_api = Api::<ClusterRoleBinding>::all(client.clone());
-    let binding_watcher = watcher(binding_api, ListParams::default());
-    pin_mut!(binding_watcher);
-    while let Ok(Some(event)) = binding_watcher.try_next().await {
-        match event {
-            Event::Applied(binding) => {
-                let subjects = binding.clone().subjects.unwrap_or_default();
-                let grant = RBACGrant::from_cluster_role_binding(&binding);
-                let previous_subjects = match shared.get_current_subjects_for_grant(&grant) {
-                    Some(subs) => subs,
-                    None => HashSet::new(),
-                };
-                for previous_subject in previous_subjects {
-                    shared.remove_grant_for_subject(&previous_subject, &grant);
+/// applies a watch event to the state
+fn apply_event<K: Binding>(shared: &Shared, event: Event<K>) {
+    // the event may take several locks, readers only see its generation once it's fully applied
+    let _change = shared.generation.begin_change();
+    match event {
+        Event::Applied(binding) => {
+            let subjects = binding.subjects();
+            let grant = binding.to_grant();
+            if let Some(reason) = grant.malformed_reason() {
+                warn!(
+                    "skipping {} {}, it can't grant anything: {}",
+                    K::DESCRIPTION,
+                    grant.name,
+                    reason
+                );
+                shared.mark_malformed(&grant, reason);
+                return;
+            }
+            let (grant_subjects, skipped) =
+                shared.convert_subjects(&grant, &subjects, binding.namespace());
+            let previous_subjects =
+                shared.apply_grant(&grant, &grant_subjects, skipped, managed_by_label(&binding));
+            shared.record_subject_changes(&grant, &previous_subjects, &grant_subjects);
+        }
+        Event::Restarted(bindings) => {
+            let mut listed = ListedGrants::default();
+            for binding in bindings {
+                let grant = binding.to_grant();
+                if let Some(reason) = grant.malformed_reason() {
+                    warn!(
+                        "skipping {} {}, it can't grant anything: {}",
+                        K::DESCRIPTION,
+                        grant.name,
+                        reason
+                    );
+                    listed.malformed.push((grant, reason));
+                    continue;
                 }
-                for subject in subjects {
-                    let grant_subject = GrantSubject::from_subject(&subject);
-                    shared.add_grant_for_subject(&grant_subject, &grant);
+                let subjects = binding.subjects();
+                let (grant_subjects, skipped) =
+                    shared.convert_subjects(&grant, &subjects, binding.namespace());
+                for grant_subject in grant_subjects {
+                    listed.grants.push((grant_subject, grant.clone()));
                 }
+                if let Some(managed_by) = managed_by_label(&binding) {
+                    listed.managed_by.push((grant.clone(), managed_by));
+                }
+                listed.skipped_subjects.push((grant, skipped));
             }
-            Event::Restarted(bindings) => {
-                shared.remove_all_of_type(GrantType::ClusterRoleBinding);
-                for binding in bindings {
-                    let grant = RBACGrant::from_cluster_role_binding(&binding);
-                    let subjects = binding.clone().subjects.unwrap_or_default();
-                    for subject in subjects {
-                        let grant_subject = GrantSubject::from_subject(&subject);
-                        shared.add_grant_for_subject(&grant_subject, &grant)
+            shared.replace_all_of_type(K::GRANT_TYPE, listed);
+            shared.mark_synced(K::GRANT_TYPE);
+        }
+        Event::Deleted(binding) => {
+            let grant = binding.to_grant();
+            shared.remove_grant(&grant);
+        }
+    }
+}
+
+/// the app.kubernetes.io/managed-by label of a binding
+fn managed_by_label<K: ResourceExt>(binding: &K) -> Option<String> {
+    binding.labels().get(MANAGED_BY_LABEL).cloned()
+}
+
+/// periodically persists the grants to the cache, once they're synced (so stale cached state isn't rewritten)
+async fn persist_grants(interval: Duration, shared: Arc<Shared>) {
+    loop {
+        rt::time::sleep(interval).await;
+        if shared.is_synced() {
+            shared.persist();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::metrics::StateCounts;
+    use crate::controller::permission_controller::PermissionController;
+    use crate::controller::rbac_grant::{IDType, ImplicitGroups, AUTHENTICATED_GROUP};
+    use crate::controller::testing::{
+        cluster_role, cluster_role_binding, role_binding, rule, subject,
+    };
+    use futures::stream::{self, BoxStream, StreamExt};
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn shared() -> Shared {
+        Shared::new(
+            vec![GrantType::RoleBinding, GrantType::ClusterRoleBinding],
+            None,
+            false,
+            None,
+            Generation::default(),
+            Metrics::new().unwrap(),
+        )
+    }
+
+    fn user(name: &str) -> GrantSubject {
+        GrantSubject::from_subject(&subject("User", name, None), None)
+    }
+
+    #[test]
+    fn applied_binding_replaces_its_previous_version() {
+        let shared = shared();
+        let malformed = role_binding(
+            "default",
+            "view",
+            "Role",
+            "",
+            vec![subject("User", "alice", None)],
+        );
+        apply_event(&shared, Event::Applied(malformed));
+        let fixed = role_binding(
+            "default",
+            "view",
+            "Role",
+            "viewer",
+            vec![subject("User", "alice", None)],
+        );
+        apply_event(&shared, Event::Applied(fixed));
+        let changed = role_binding(
+            "default",
+            "view",
+            "Role",
+            "viewer",
+            vec![subject("User", "bob", None)],
+        );
+        apply_event(&shared, Event::Applied(changed.clone()));
+        {
+            let state = shared.state.read().unwrap();
+            assert!(state.malformed.is_empty());
+            assert!(!state.user_to_grant.contains_key(&user("alice")));
+            assert_eq!(state.user_to_grant[&user("bob")].len(), 1);
+        }
+
+        apply_event(&shared, Event::Deleted(changed));
+        let state = shared.state.read().unwrap();
+        assert!(state.user_to_grant.is_empty());
+    }
+
+    #[test]
+    fn grant_is_removed_after_its_role_ref_changed() {
+        let shared = shared();
+        let alice = vec![subject("User", "alice", None)];
+        let viewer = role_binding("default", "access", "Role", "viewer", alice.clone());
+        apply_event(&shared, Event::Applied(viewer));
+        let editor = role_binding("default", "access", "ClusterRole", "editor", alice);
+        apply_event(&shared, Event::Applied(editor.clone()));
+        {
+            let state = shared.state.read().unwrap();
+            let grants = &state.user_to_grant[&user("alice")];
+            assert_eq!(grants.len(), 1);
+            let grant = grants.iter().next().unwrap();
+            assert_eq!(grant.permissions_id.rbac_type, IDType::ClusterRole);
+            assert_eq!(grant.permissions_id.name, "editor");
+        }
+
+        apply_event(&shared, Event::Deleted(editor));
+        let state = shared.state.read().unwrap();
+        assert!(state.user_to_grant.is_empty());
+        assert!(state.grant_to_user.is_empty());
+    }
+
+    #[test]
+    fn same_role_name_from_both_binding_types_conflicts() {
+        let controller = GrantController::from_bindings(
+            vec![
+                role_binding(
+                    "default",
+                    "admin",
+                    "ClusterRole",
+                    "admin",
+                    vec![subject("User", "alice", None)],
+                ),
+                role_binding(
+                    "default",
+                    "view",
+                    "Role",
+                    "view",
+                    vec![subject("User", "bob", None)],
+                ),
+            ],
+            vec![
+                cluster_role_binding(
+                    "admin-all",
+                    "ClusterRole",
+                    "admin",
+                    vec![subject("User", "alice", None)],
+                ),
+                cluster_role_binding(
+                    "edit-all",
+                    "ClusterRole",
+                    "edit",
+                    vec![subject("User", "bob", None)],
+                ),
+            ],
+        );
+        let conflicts = controller.get_conflicting_grants();
+        assert_eq!(conflicts.len(), 1);
+        let (subject, role_name, grants) = &conflicts[0];
+        assert_eq!(subject, &user("alice"));
+        assert_eq!(role_name, "admin");
+        let mut names: Vec<&str> = grants.iter().map(|grant| grant.name.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["admin", "admin-all"]);
+    }
+
+    #[test]
+    fn top_subject_ties_are_sorted_by_kind_namespace_and_name() {
+        let devs = || {
+            vec![
+                subject("User", "devs", None),
+                subject("Group", "devs", None),
+                subject("ServiceAccount", "devs", Some("prod")),
+                subject("ServiceAccount", "devs", Some("dev")),
+            ]
+        };
+        let controller = GrantController::from_bindings(
+            vec![role_binding("default", "view", "Role", "view", devs())],
+            vec![cluster_role_binding(
+                "view-all",
+                "ClusterRole",
+                "view",
+                devs(),
+            )],
+        );
+        for n in [4, 2] {
+            let top: Vec<(String, Option<String>)> = controller
+                .get_top_subjects(n, &[])
+                .into_iter()
+                .map(|(subject, _)| (subject.kind.to_string(), subject.namespace))
+                .collect();
+            let expected = [
+                ("Group".to_string(), None),
+                ("ServiceAccount".to_string(), Some("dev".to_string())),
+                ("ServiceAccount".to_string(), Some("prod".to_string())),
+                ("User".to_string(), None),
+            ];
+            assert_eq!(top, expected[..n]);
+        }
+    }
+
+    #[test]
+    fn cached_binding_to_cluster_role_resolves() {
+        let dir =
+            std::env::temp_dir().join(format!("user-manifest-grant-cache-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // as stored by a version which gave ClusterRole ids the namespace of their RoleBinding
+        let alice = user("alice");
+        let mut grant = RBACGrant::from_role_binding(&role_binding(
+            "default",
+            "view",
+            "ClusterRole",
+            "view",
+            vec![subject("User", "alice", None)],
+        ));
+        grant.permissions_id.namespace = Some("default".to_string());
+        let snapshot = serde_json::json!({"schemaVersion": 2, "data": [[alice, [grant]]]});
+        std::fs::write(dir.join(GRANT_CACHE_FILE), snapshot.to_string()).unwrap();
+
+        let shared = shared();
+        shared.load_cache(&Cache::in_dir(dir.clone()));
+        std::fs::remove_dir_all(&dir).unwrap();
+        let grants = shared.state.read().unwrap().user_to_grant[&alice].clone();
+        assert_eq!(grants.len(), 1);
+
+        let view = rule(&[""], &["pods"], &["get"]);
+        let permissions = PermissionController::from_roles(
+            vec![],
+            vec![cluster_role("view", vec![view.clone()])],
+        );
+        let resolved =
+            permissions.get_permissions_for_ids(grants.iter().map(|grant| &grant.permissions_id));
+        assert_eq!(resolved.into_values().collect::<Vec<_>>(), vec![vec![view]]);
+    }
+
+    #[test]
+    fn restart_replaces_previous_list() {
+        let shared = shared();
+        apply_event(
+            &shared,
+            Event::Restarted(vec![
+                role_binding(
+                    "default",
+                    "admins",
+                    "Role",
+                    "admin",
+                    vec![subject("User", "alice", None)],
+                ),
+                role_binding(
+                    "default",
+                    "viewers",
+                    "Role",
+                    "view",
+                    vec![subject("User", "bob", None)],
+                ),
+            ]),
+        );
+        apply_event(
+            &shared,
+            Event::Restarted(vec![role_binding(
+                "default",
+                "admins",
+                "Role",
+                "admin",
+                vec![subject("User", "carol", None)],
+            )]),
+        );
+
+        let state = shared.state.read().unwrap();
+        assert_eq!(
+            state.user_to_grant.keys().collect::<Vec<_>>(),
+            vec![&user("carol")]
+        );
+        let grant_names: Vec<&str> = state
+            .grant_to_user
+            .keys()
+            .map(|grant| grant.name.as_str())
+            .collect();
+        assert_eq!(grant_names, vec!["admins"]);
+        assert_eq!(
+            state.grant_to_user.values().next(),
+            Some(&HashSet::from([user("carol")]))
+        );
+        assert!(state.removed.keys().any(|grant| grant.name == "viewers"));
+    }
+
+    #[test]
+    fn broad_group_is_stored_once() {
+        let controller = GrantController::from_bindings(
+            vec![],
+            vec![cluster_role_binding(
+                "discovery",
+                "ClusterRole",
+                "system:discovery",
+                vec![subject("Group", AUTHENTICATED_GROUP, None)],
+            )],
+        );
+        let group = GrantSubject::group(AUTHENTICATED_GROUP);
+        assert_eq!(controller.get_subjects(), vec![group.clone()]);
+        assert_eq!(controller.get_grant_count(), 1);
+
+        // each user receives the grant through the group at query time
+        for name in ["alice", "bob"] {
+            let user = user(name);
+            let mut subjects = user.implicit_groups(&ImplicitGroups::default());
+            subjects.push(user);
+            let grants = controller.get_grants_for_subjects(&subjects);
+            assert_eq!(grants.len(), 1);
+        }
+        assert_eq!(controller.get_subjects(), vec![group]);
+    }
+
+    #[test]
+    fn readers_proceed_in_parallel() {
+        let controller = GrantController::from_bindings(
+            vec![role_binding(
+                "default",
+                "view",
+                "Role",
+                "viewer",
+                vec![subject("User", "alice", None)],
+            )],
+            vec![],
+        );
+        // a slow reader holding the lock doesn't hold up the others
+        let _reader = controller.shared.state.read().unwrap();
+        let other = controller.clone();
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || sender.send(other.get_grants()).unwrap());
+        let grants = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(grants.len(), 1);
+    }
+
+    #[test]
+    fn concurrent_restarts_are_never_seen_partially() {
+        // the lists share binding names with different subjects and differ in length, so any mix of two of them or
+        // leftovers of a longer one would show
+        let lists: Vec<Vec<RoleBinding>> = (0..4)
+            .map(|list| {
+                (0..5 + list)
+                    .map(|binding| {
+                        role_binding(
+                            "default",
+                            &format!("binding-{}", binding),
+                            "Role",
+                            "view",
+                            vec![subject("User", &format!("user-{}-{}", list, binding), None)],
+                        )
+                    })
+                    .collect()
+            })
+            .collect();
+        let complete: Vec<HashMap<GrantSubject, HashSet<RBACGrant>>> = lists
+            .iter()
+            .map(|list| GrantController::from_bindings(list.clone(), vec![]).get_grants())
+            .collect();
+        let controller = GrantController::from_bindings(vec![], vec![]);
+        let done = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let controller = controller.clone();
+                let complete = complete.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        let grants = controller.get_grants();
+                        assert!(
+                            grants.is_empty() || complete.contains(&grants),
+                            "saw a partial restart: {:?}",
+                            grants
+                        );
                     }
+                })
+            })
+            .collect();
+        let writers: Vec<_> = lists
+            .into_iter()
+            .map(|list| {
+                let controller = controller.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        apply_event(&controller.shared, Event::Restarted(list.clone()));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+
+        // the restart which took the lock last replaced everything the others left behind
+        let grants = controller.get_grants();
+        assert!(complete.contains(&grants), "{:?}", grants);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn watch_recovers_from_errors_and_restarts() {
+        let shared = Arc::new(shared());
+        // the first watch fails once and ends after its list, the second one only sees an update and stays open
+        let mut watches: VecDeque<Vec<Result<Event<RoleBinding>, watcher::Error>>> =
+            VecDeque::from([
+                vec![
+                    Err(watcher::Error::TooManyObjects),
+                    Ok(Event::Restarted(vec![role_binding(
+                        "default",
+                        "view",
+                        "Role",
+                        "viewer",
+                        vec![subject("User", "alice", None)],
+                    )])),
+                ],
+                vec![Ok(Event::Applied(role_binding(
+                    "default",
+                    "edit",
+                    "Role",
+                    "editor",
+                    vec![subject("User", "bob", None)],
+                )))],
+            ]);
+        let start_watch =
+            move || -> BoxStream<'static, Result<Event<RoleBinding>, watcher::Error>> {
+                let events = watches.pop_front().unwrap_or_default();
+                if watches.is_empty() {
+                    stream::iter(events).chain(stream::pending()).boxed()
+                } else {
+                    stream::iter(events).boxed()
                 }
-            }
-            Event::Deleted(binding) => {
-                let grant = RBACGrant::from_cluster_role_binding(&binding);
-                shared.remove_grant(&grant);
-            }
+            };
+        // the watch never returns, it's stopped once the backoffs have long passed
+        let watch = watch_binding_events(start_watch, shared.clone());
+        assert!(tokio::time::timeout(Duration::from_secs(120), watch)
+            .await
+            .is_err());
+
+        {
+            let state = shared.state.read().unwrap();
+            assert!(state.user_to_grant.contains_key(&user("alice")));
+            assert!(state.user_to_grant.contains_key(&user("bob")));
         }
+        let metrics = shared
+            .metrics
+            .render(StateCounts {
+                grants: 0,
+                permissions: 0,
+                subjects: 0,
+                audit_log_write_failures: None,
+            })
+            .unwrap();
+        let errors = format!(
+            "user_manifest_watch_errors_total{{resource=\"{}\"}} 1",
+            ROLE_BINDINGS
+        );
+        let restarts = format!(
+            "user_manifest_watch_restarts_total{{resource=\"{}\"}} 1",
+            ROLE_BINDINGS
+        );
+        assert!(metrics.contains(&errors), "{}", metrics);
+        assert!(metrics.contains(&restarts), "{}", metrics);
     }
 }
@@ -0,0 +1,106 @@
+use prometheus::{Encoder, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::fmt;
+
+/// Prometheus metrics of the controllers. The watch loops update the counters as they go, the gauges are set from the
+/// controllers' state right before each scrape (see render)
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    /// errors returned by each resource's watch
+    watch_errors: IntCounterVec,
+    /// times each resource's watch ended and was started again
+    watch_restarts: IntCounterVec,
+    /// entries the audit log couldn't write, caught up with the audit log's own count at scrape time
+    audit_log_write_failures: IntCounter,
+    grants: IntGauge,
+    permissions: IntGauge,
+    subjects: IntGauge,
+}
+
+/// the current size of the controllers' state, read at scrape time
+pub(crate) struct StateCounts {
+    pub(crate) grants: usize,
+    pub(crate) permissions: usize,
+    pub(crate) subjects: usize,
+    /// entries the audit log couldn't write since startup, None without an audit log
+    pub(crate) audit_log_write_failures: Option<u64>,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        let registry = Registry::new();
+        let watch_errors = IntCounterVec::new(
+            Opts::new(
+                "user_manifest_watch_errors_total",
+                "errors returned by the watch of each resource",
+            ),
+            &["resource"],
+        )?;
+        let watch_restarts = IntCounterVec::new(
+            Opts::new(
+                "user_manifest_watch_restarts_total",
+                "times the watch of each resource ended and was restarted",
+            ),
+            &["resource"],
+        )?;
+        let audit_log_write_failures = IntCounter::new(
+            "user_manifest_audit_log_write_failures_total",
+            "entries which couldn't be written to the audit log",
+        )?;
+        let grants = IntGauge::new(
+            "user_manifest_grants",
+            "number of grants (bindings) tracked",
+        )?;
+        let permissions = IntGauge::new(
+            "user_manifest_permissions",
+            "number of roles/cluster roles with rules tracked",
+        )?;
+        let subjects = IntGauge::new("user_manifest_subjects", "number of subjects with grants")?;
+        registry.register(Box::new(watch_errors.clone()))?;
+        registry.register(Box::new(watch_restarts.clone()))?;
+        registry.register(Box::new(audit_log_write_failures.clone()))?;
+        registry.register(Box::new(grants.clone()))?;
+        registry.register(Box::new(permissions.clone()))?;
+        registry.register(Box::new(subjects.clone()))?;
+        Ok(Metrics {
+            registry,
+            watch_errors,
+            watch_restarts,
+            audit_log_write_failures,
+            grants,
+            permissions,
+            subjects,
+        })
+    }
+
+    /// counts an error returned by the watch of the resource (see watch_scope for the resource names)
+    pub(crate) fn watch_error(&self, resource: &str) {
+        self.watch_errors.with_label_values(&[resource]).inc();
+    }
+
+    /// counts the watch of the resource ending and being started again
+    pub(crate) fn watch_restart(&self, resource: &str) {
+        self.watch_restarts.with_label_values(&[resource]).inc();
+    }
+
+    /// sets the gauges to the counts, catches the audit log failures counter up, and renders every metric in the Prometheus text format
+    pub(crate) fn render(&self, counts: StateCounts) -> Result<String, prometheus::Error> {
+        self.grants.set(counts.grants as i64);
+        self.permissions.set(counts.permissions as i64);
+        self.subjects.set(counts.subjects as i64);
+        if let Some(failures) = counts.audit_log_write_failures {
+            // the audit log's count only grows, the counter only needs to be incremented by what's new
+            self.audit_log_write_failures
+                .inc_by(failures.saturating_sub(self.audit_log_write_failures.get()));
+        }
+        let mut output = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut output)?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
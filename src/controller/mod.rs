@@ -1,4 +1,13 @@
+pub mod api_server_check;
+pub mod audit_log;
+pub mod cache;
+pub mod generation;
 pub mod rbac_controller;
 pub mod rbac_grant;
 pub mod grant_controller;
-pub mod permission_controller;
\ No newline at end of file
+pub mod metrics;
+pub mod permission_controller;
+pub mod rules;
+pub mod watch_scope;
+#[cfg(test)]
+pub(crate) mod testing;
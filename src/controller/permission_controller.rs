@@ -1,12 +1,21 @@
+use crate::controller::cache::Cache;
+use crate::controller::generation::Generation;
+use crate::controller::metrics::Metrics;
 use crate::controller::rbac_grant::{RBACId, IDType};
+use crate::controller::rules::mixes_resource_kinds;
+use crate::controller::watch_scope::{WatchBackoff, WatchScope, CLUSTER_ROLES, ROLES};
 use k8s_openapi::api::rbac::v1::{PolicyRule, Role, ClusterRole};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelector;
 use kube::{api::{Api, ListParams}, runtime::watcher, Client};
-use log::info;
-use std::collections::HashMap;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use actix_web::rt;
 use futures::{pin_mut, TryStreamExt};
 use kube::runtime::watcher::Event;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
 
 // structure heavily influenced by https://github.com/tokio-rs/mini-redis/blob/master/src/db.rs
 // TODO: Reduce/remove the use of .unwrap()
@@ -16,127 +25,716 @@ pub struct PermissionController {
     shared: Arc<Shared>,
 }
 
+/// file the permissions are cached in, within the cache dir
+const PERMISSION_CACHE_FILE: &str = "permissions.json";
+/// changes buffered for each subscriber, a subscriber falling further behind misses the oldest ones
+const CHANGE_CHANNEL_CAPACITY: usize = 1024;
+
 #[derive(Debug)]
 struct Shared {
     /// Shared state guarded by a mutex
     state: Mutex<State>,
+    /// id types which are watched, the state is synced once all of these have been listed
+    watched_types: Vec<IDType>,
+    /// on-disk copy of the state, if caching is enabled
+    cache: Option<Cache>,
+    /// counts the changes to the state, shared with the other controllers
+    generation: Generation,
+    /// counts the errors/restarts of the watches
+    metrics: Metrics,
+    /// ids whose rules changed, see PermissionController::subscribe
+    changes: broadcast::Sender<RBACId>,
 }
 
 #[derive(Debug)]
 struct State {
-    id_to_permissions: HashMap<RBACId, Vec<PolicyRule>>
+    id_to_permissions: HashMap<RBACId, Vec<PolicyRule>>,
+    /// id types which have been fully listed since startup
+    synced_types: HashSet<IDType>,
+    /// labels of every cluster role, matched against the selectors of the aggregated cluster roles
+    cluster_role_labels: HashMap<RBACId, BTreeMap<String, String>>,
+    /// cluster roles with an aggregation rule
+    aggregated_roles: HashMap<RBACId, AggregatedRole>,
+}
+
+/// a cluster role with an aggregation rule - its rules are those of every cluster role matching one of its selectors
+#[derive(Debug)]
+struct AggregatedRole {
+    selectors: Vec<LabelSelector>,
+    /// the rules as listed on the role itself. Usually kubernetes has already aggregated them, but we can't rely on it
+    own_rules: Vec<PolicyRule>,
 }
 
 impl PermissionController {
-    pub(crate) fn new(client: Client) -> PermissionController {
-        let shared = Arc::new(Shared {
-            state: Mutex::new(State {
-                id_to_permissions: HashMap::new(),
-            })
-        });
+    pub(crate) fn new(client: Client, scope: &WatchScope, cache: Option<Cache>, generation: Generation, metrics: Metrics, shutdown: CancellationToken) -> PermissionController {
+        let mut watched_types = Vec::new();
+        if scope.watches(ROLES){
+            watched_types.push(IDType::Role);
+        }
+        if scope.watches(CLUSTER_ROLES){
+            watched_types.push(IDType::ClusterRole);
+        }
+        let shared = Arc::new(Shared::new(watched_types, cache, generation, metrics));
+
+        if let Some(cache) = &shared.cache{
+            // serve the cached permissions until the watches have synced
+            shared.load_cache(cache);
+            rt::spawn(shutdown.clone().run_until_cancelled_owned(persist_permissions(cache.persist_interval, shared.clone())));
+        }
 
-        rt::spawn(refresh_roles(client.clone(), shared.clone()));
-        rt::spawn(refresh_cluster_role(client.clone(), shared.clone()));
+        if scope.watches(ROLES){
+            rt::spawn(shutdown.clone().run_until_cancelled_owned(refresh_roles(scope.namespaced_api(client.clone()), scope.list_params(), shared.clone())));
+        }
+        if scope.watches(CLUSTER_ROLES){
+            rt::spawn(shutdown.run_until_cancelled_owned(refresh_cluster_role(client.clone(), scope.list_params(), shared.clone())));
+        }
 
         PermissionController{shared}
     }
+
+    /// a controller holding the roles as if they were just listed, only watching roles like in namespaced mode
+    #[cfg(test)]
+    pub(crate) fn namespaced_from_roles(roles: Vec<Role>) -> PermissionController {
+        let shared = Shared::new(vec![IDType::Role], None, Generation::default(), Metrics::new().unwrap());
+        let permissions = roles.into_iter()
+            .map(|role| (RBACId::from_role(&role), role.rules.unwrap_or_default()))
+            .collect();
+        shared.replace_all_of_type(IDType::Role, permissions);
+        shared.mark_synced(IDType::Role);
+        PermissionController{shared: Arc::new(shared)}
+    }
+
+    /// a controller holding the roles and cluster roles as if they were just listed, without watching anything
+    #[cfg(test)]
+    pub(crate) fn from_roles(roles: Vec<Role>, cluster_roles: Vec<ClusterRole>) -> PermissionController {
+        let shared = Shared::new(vec![IDType::Role, IDType::ClusterRole], None, Generation::default(), Metrics::new().unwrap());
+        let permissions = roles.into_iter()
+            .map(|role| (RBACId::from_role(&role), role.rules.unwrap_or_default()))
+            .collect();
+        shared.replace_all_of_type(IDType::Role, permissions);
+        shared.mark_synced(IDType::Role);
+        shared.replace_cluster_roles(cluster_roles);
+        shared.mark_synced(IDType::ClusterRole);
+        PermissionController{shared: Arc::new(shared)}
+    }
     pub(crate) fn get_permission_for_id(&self, id: &RBACId) -> Option<Vec<PolicyRule>>{
         let mut state = self.shared.state.lock().unwrap();
         let state = &mut *state;
-        match state.id_to_permissions.get(id){
-            Some(permissions) => Some(permissions.clone()),
-            None => None,
+        state.id_to_permissions.get(id).cloned()
+    }
+
+    /// Rules of each of the ids which we have rules for, read under a single lock. The rules are cloned so that callers
+    /// (e.g. handlers serializing a response) never hold the lock while working with them
+    pub(crate) fn get_permissions_for_ids<'a>(&self, ids: impl IntoIterator<Item = &'a RBACId>) -> HashMap<RBACId, Vec<PolicyRule>>{
+        let state = self.shared.state.lock().unwrap();
+        ids.into_iter()
+            .filter_map(|id| state.id_to_permissions.get(id).map(|rules| (id.clone(), rules.clone())))
+            .collect()
+    }
+
+    /// the number of roles with rules, and whether every watched id type has been listed - until then permissions may
+    /// be missing or, if they were loaded from the cache, stale. Read under a single lock without copying the rules
+    pub(crate) fn get_status(&self) -> (usize, bool){
+        let state = self.shared.state.lock().unwrap();
+        let synced = self.shared.watched_types.iter().all(|id_type| state.synced_types.contains(id_type));
+        (state.id_to_permissions.len(), synced)
+    }
+
+    /// the number of roles with rules of each type
+    pub(crate) fn get_type_counts(&self) -> HashMap<IDType, usize>{
+        let state = self.shared.state.lock().unwrap();
+        let mut counts: HashMap<IDType, usize> = HashMap::new();
+        for id in state.id_to_permissions.keys(){
+            *counts.entry(id.rbac_type.clone()).or_default() += 1;
         }
+        counts
     }
 
-    pub(crate) fn get_permissions(&self) -> HashMap<RBACId, Vec<PolicyRule>>{
-        let mut state = self.shared.state.lock().unwrap();
-        let state = &mut *state;
-        state.id_to_permissions.clone()
+    /// roles with rules mixing resources and non resource urls, along with those rules. They're stored (and evaluated)
+    /// like any other rule, this only flags them
+    pub(crate) fn get_mixed_rules(&self) -> Vec<(RBACId, Vec<PolicyRule>)>{
+        let state = self.shared.state.lock().unwrap();
+        state.id_to_permissions.iter()
+            .filter_map(|(id, rules)| {
+                let mixed: Vec<PolicyRule> = rules.iter().filter(|rule| mixes_resource_kinds(rule)).cloned().collect();
+                if mixed.is_empty(){
+                    return None;
+                }
+                Some((id.clone(), mixed))
+            })
+            .collect()
+    }
+
+    /// whether any roles/cluster roles are watched. If not, there are no rules to resolve grants into permissions with
+    pub(crate) fn resolves_permissions(&self) -> bool{
+        !self.shared.watched_types.is_empty()
+    }
+
+    /// whether roles of the type are watched. Grants referencing roles of other types never resolve
+    pub(crate) fn watches(&self, id_type: &IDType) -> bool{
+        self.shared.watched_types.contains(id_type)
+    }
+
+    /// receives the id of every role/cluster role whose rules changed (including ones added or removed) from now on,
+    /// published once the change is applied. Relists only publish the roles which actually changed. Receivers which
+    /// fall behind by more than CHANGE_CHANNEL_CAPACITY changes miss the oldest ones
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<RBACId>{
+        self.shared.changes.subscribe()
+    }
+
+    /// ids of every role/cluster role we have rules for
+    pub(crate) fn get_ids(&self) -> HashSet<RBACId>{
+        let state = self.shared.state.lock().unwrap();
+        state.id_to_permissions.keys().cloned().collect()
     }
 }
 
 impl Shared {
+    /// empty state, synced once every one of watched_types has been listed
+    fn new(watched_types: Vec<IDType>, cache: Option<Cache>, generation: Generation, metrics: Metrics) -> Shared{
+        Shared {
+            state: Mutex::new(State {
+                id_to_permissions: HashMap::new(),
+                synced_types: HashSet::new(),
+                cluster_role_labels: HashMap::new(),
+                aggregated_roles: HashMap::new(),
+            }),
+            watched_types,
+            cache,
+            generation,
+            metrics,
+            changes: broadcast::channel(CHANGE_CHANNEL_CAPACITY).0,
+        }
+    }
+
+    fn is_synced(&self) -> bool{
+        let state = self.state.lock().unwrap();
+        self.watched_types.iter().all(|id_type| state.synced_types.contains(id_type))
+    }
+
+    /// records that id_type has been fully listed. Once everything is synced, the cache is rewritten right away so
+    /// that it no longer holds the state from before the restart
+    fn mark_synced(&self, id_type: IDType){
+        {
+            let mut state = self.state.lock().unwrap();
+            state.synced_types.insert(id_type);
+        }
+        if self.is_synced(){
+            self.persist();
+        }
+    }
+
+    /// stores the rules held in the cache, if it has any, under their normalized ids
+    fn load_cache(&self, cache: &Cache){
+        let cached = match cache.load::<Vec<(RBACId, Vec<PolicyRule>)>>(PERMISSION_CACHE_FILE){
+            Some(cached) => cached,
+            None => return,
+        };
+        let _change = self.generation.begin_change();
+        info!("Loaded permissions for {} roles from the cache", cached.len());
+        for (id, rules) in cached{
+            self.store_permission_id(&id.normalized(), &rules);
+        }
+    }
+
+    fn persist(&self){
+        if let Some(cache) = &self.cache{
+            let permissions: Vec<(RBACId, Vec<PolicyRule>)> = {
+                let state = self.state.lock().unwrap();
+                state.id_to_permissions.clone().into_iter().collect()
+            };
+            cache.store(PERMISSION_CACHE_FILE, &permissions);
+        }
+    }
+
+    /// notifies the subscribers of the changed ids. Sending only fails when nobody is subscribed, which is fine
+    fn publish(&self, changed: Vec<RBACId>){
+        for id in changed{
+            let _ = self.changes.send(id);
+        }
+    }
+
     fn remove_permission_id(&self, id: &RBACId){
-        // as outlined in the mini-redis, necessary to acquire lock/access state
-        let mut state =  self.state.lock().unwrap();
-        let state = &mut *state;
-        state.id_to_permissions.remove(id);
+        let removed = {
+            // as outlined in the mini-redis, necessary to acquire lock/access state
+            let mut state =  self.state.lock().unwrap();
+            let state = &mut *state;
+            state.id_to_permissions.remove(id).is_some()
+        };
+        if removed{
+            self.publish(vec![id.clone()]);
+        }
     }
 
-    fn store_permission_id(&self, id: &RBACId, rules: &Vec<PolicyRule>){
-        // as outlined in the mini-redis, necessary to acquire lock/access state
-        let mut state =  self.state.lock().unwrap();
-        let state = &mut *state;
-        state.id_to_permissions.insert(id.clone(), rules.clone());
+    /// stores the rules of the id, replacing any previous ones under the same lock so that readers never see it missing
+    fn store_permission_id(&self, id: &RBACId, rules: &[PolicyRule]){
+        warn_mixed_rules(id, rules);
+        let previous = {
+            // as outlined in the mini-redis, necessary to acquire lock/access state
+            let mut state =  self.state.lock().unwrap();
+            let state = &mut *state;
+            state.id_to_permissions.insert(id.clone(), rules.to_vec())
+        };
+        if previous.as_deref() != Some(rules){
+            self.publish(vec![id.clone()]);
+        }
     }
 
-    fn remove_all_of_type(&self, id_type: IDType){
-        // as outlined in the mini-redis, necessary to acquire lock/access state
-        let mut state =  self.state.lock().unwrap();
-        let state = &mut *state;
-        // keep only the entries which do not have the specified id type (or remove all that are
-        // of the specified id type)
-        state.id_to_permissions.retain(|k, _| k.rbac_type != id_type);
+    /// replaces every permission of id_type with the given ones. This happens under a single lock so readers never
+    /// see a partially rebuilt state, and two restarts of the same type can't interleave - the last one to run fully
+    /// determines the permissions of its type
+    fn replace_all_of_type(&self, id_type: IDType, permissions: Vec<(RBACId, Vec<PolicyRule>)>){
+        let changed = {
+            // as outlined in the mini-redis, necessary to acquire lock/access state
+            let mut state =  self.state.lock().unwrap();
+            let state = &mut *state;
+            // keep only the entries which do not have the specified id type (or remove all that are
+            // of the specified id type)
+            let before = state.take_all_of_type(&id_type);
+            for (id, rules) in &permissions{
+                warn_mixed_rules(id, rules);
+            }
+            state.id_to_permissions.extend(permissions);
+            state.changed_ids_of_type(&before, &id_type)
+        };
+        self.publish(changed);
+    }
+
+    /// stores the cluster role, re-aggregating the aggregated cluster roles under the same lock since it may be (or
+    /// have been) one of their children
+    fn store_cluster_role(&self, cluster_role: ClusterRole){
+        let id = RBACId::from_cluster_role(&cluster_role);
+        let rules = cluster_role.rules.unwrap_or_default();
+        warn_mixed_rules(&id, &rules);
+        let changed = {
+            let mut state =  self.state.lock().unwrap();
+            let state = &mut *state;
+            let affected = state.aggregation_affected_by(&id);
+            let before = state.rules_of(&affected);
+            state.insert_cluster_role(id, cluster_role.metadata.labels.unwrap_or_default(), cluster_role.aggregation_rule.and_then(|rule| rule.cluster_role_selectors), rules);
+            state.aggregate();
+            state.changed_ids(&before, affected)
+        };
+        self.publish(changed);
+    }
+
+    fn remove_cluster_role(&self, id: &RBACId){
+        let changed = {
+            let mut state =  self.state.lock().unwrap();
+            let state = &mut *state;
+            let affected = state.aggregation_affected_by(id);
+            let before = state.rules_of(&affected);
+            state.id_to_permissions.remove(id);
+            state.cluster_role_labels.remove(id);
+            state.aggregated_roles.remove(id);
+            state.aggregate();
+            state.changed_ids(&before, affected)
+        };
+        self.publish(changed);
+    }
+
+    /// replaces every cluster role with the given ones under a single lock, see replace_all_of_type
+    fn replace_cluster_roles(&self, cluster_roles: Vec<ClusterRole>){
+        let changed = {
+            let mut state =  self.state.lock().unwrap();
+            let state = &mut *state;
+            let before = state.take_all_of_type(&IDType::ClusterRole);
+            state.cluster_role_labels.clear();
+            state.aggregated_roles.clear();
+            for cluster_role in cluster_roles{
+                let id = RBACId::from_cluster_role(&cluster_role);
+                let rules = cluster_role.rules.unwrap_or_default();
+                warn_mixed_rules(&id, &rules);
+                state.insert_cluster_role(id, cluster_role.metadata.labels.unwrap_or_default(), cluster_role.aggregation_rule.and_then(|rule| rule.cluster_role_selectors), rules);
+            }
+            state.aggregate();
+            state.changed_ids_of_type(&before, &IDType::ClusterRole)
+        };
+        self.publish(changed);
+    }
+}
+
+impl State {
+    /// removes every entry of id_type, returning them
+    fn take_all_of_type(&mut self, id_type: &IDType) -> HashMap<RBACId, Vec<PolicyRule>>{
+        let (taken, kept) = std::mem::take(&mut self.id_to_permissions).into_iter()
+            .partition(|(id, _)| id.rbac_type == *id_type);
+        self.id_to_permissions = kept;
+        taken
     }
+
+    /// copies of the rules of the ids, leaving out ids without rules
+    fn rules_of(&self, ids: &[RBACId]) -> HashMap<RBACId, Vec<PolicyRule>>{
+        ids.iter()
+            .filter_map(|id| self.id_to_permissions.get(id).map(|rules| (id.clone(), rules.clone())))
+            .collect()
+    }
+
+    /// the ids out of candidates whose rules differ from those in before, ids without rules counting as missing
+    fn changed_ids(&self, before: &HashMap<RBACId, Vec<PolicyRule>>, candidates: impl IntoIterator<Item = RBACId>) -> Vec<RBACId>{
+        candidates.into_iter()
+            .filter(|id| before.get(id) != self.id_to_permissions.get(id))
+            .collect()
+    }
+
+    /// the ids of id_type whose rules differ from those in before (every id of the type before the change)
+    fn changed_ids_of_type(&self, before: &HashMap<RBACId, Vec<PolicyRule>>, id_type: &IDType) -> Vec<RBACId>{
+        let candidates: HashSet<RBACId> = before.keys()
+            .chain(self.id_to_permissions.keys().filter(|id| id.rbac_type == *id_type))
+            .cloned()
+            .collect();
+        self.changed_ids(before, candidates)
+    }
+
+    /// the cluster roles whose rules a change to the cluster role id can change - itself and every aggregated one
+    fn aggregation_affected_by(&self, id: &RBACId) -> Vec<RBACId>{
+        let mut affected: Vec<RBACId> = self.aggregated_roles.keys().filter(|aggregated| *aggregated != id).cloned().collect();
+        affected.push(id.clone());
+        affected
+    }
+
+    /// stores the cluster role as-is, aggregated cluster roles (those with selectors) still need to be aggregated
+    fn insert_cluster_role(&mut self, id: RBACId, labels: BTreeMap<String, String>, selectors: Option<Vec<LabelSelector>>, rules: Vec<PolicyRule>){
+        match selectors{
+            Some(selectors) => {
+                self.aggregated_roles.insert(id.clone(), AggregatedRole{selectors, own_rules: rules.clone()});
+            },
+            None => {
+                self.aggregated_roles.remove(&id);
+            },
+        }
+        self.cluster_role_labels.insert(id.clone(), labels);
+        self.id_to_permissions.insert(id, rules);
+    }
+
+    /// sets the rules of every aggregated cluster role to its own rules plus those of the cluster roles matching any
+    /// of its selectors, without duplicates. Children are taken in name order so the result is stable. Aggregated
+    /// roles can be children of others (e.g. edit of admin), so this repeats until nothing changes, starting over from
+    /// their own rules so that rules of removed children don't linger
+    fn aggregate(&mut self){
+        for (id, role) in &self.aggregated_roles{
+            self.id_to_permissions.insert(id.clone(), role.own_rules.clone());
+        }
+        // every round settles at least one more level of nesting
+        for _ in 0..=self.aggregated_roles.len(){
+            let mut changed = Vec::new();
+            for (id, role) in &self.aggregated_roles{
+                let mut children: Vec<&RBACId> = self.cluster_role_labels.iter()
+                    .filter(|(child, labels)| *child != id && role.selectors.iter().any(|selector| selector_matches(selector, labels)))
+                    .map(|(child, _)| child)
+                    .collect();
+                children.sort_by(|a, b| a.name.cmp(&b.name));
+                let mut rules = self.id_to_permissions.get(id).cloned().unwrap_or_default();
+                let known = rules.len();
+                for child in children{
+                    for rule in self.id_to_permissions.get(child).into_iter().flatten(){
+                        if !rules.contains(rule){
+                            rules.push(rule.clone());
+                        }
+                    }
+                }
+                if rules.len() != known{
+                    changed.push((id.clone(), rules));
+                }
+            }
+            if changed.is_empty(){
+                break;
+            }
+            self.id_to_permissions.extend(changed);
+        }
+    }
+}
+
+/// whether the labels match the selector - all of its match labels and match expressions. Like in kubernetes, an empty
+/// selector matches everything
+fn selector_matches(selector: &LabelSelector, labels: &BTreeMap<String, String>) -> bool{
+    let labels_match = selector.match_labels.iter().flatten()
+        .all(|(key, value)| labels.get(key) == Some(value));
+    let expressions_match = selector.match_expressions.iter().flatten()
+        .all(|requirement| {
+            let values = requirement.values.as_deref().unwrap_or_default();
+            match requirement.operator.as_str(){
+                "In" => labels.get(&requirement.key).is_some_and(|value| values.contains(value)),
+                "NotIn" => labels.get(&requirement.key).is_none_or(|value| !values.contains(value)),
+                "Exists" => labels.contains_key(&requirement.key),
+                "DoesNotExist" => !labels.contains_key(&requirement.key),
+                // unknown operators are rejected by the api server, never match them
+                _ => false,
+            }
+        });
+    labels_match && expressions_match
 }
 
-async fn refresh_roles(client: Client, shared: Arc<Shared>){
+/// warns about rules mixing resources and non resource urls. They're kept as-is, see PermissionController::get_mixed_rules
+fn warn_mixed_rules(id: &RBACId, rules: &[PolicyRule]){
+    let mixed = rules.iter().filter(|rule| mixes_resource_kinds(rule)).count();
+    if mixed > 0{
+        warn!("{} {} has {} rule(s) mixing resources and non resource urls", id.rbac_type, id.name, mixed);
+    }
+}
+
+async fn refresh_roles(role_api: Api<Role>, list_params: ListParams, shared: Arc<Shared>){
     info!("Starting role controller");
-    let role_api = Api::<Role>::all(client.clone());
-    let role_watcher = watcher(role_api, ListParams::default());
-    pin_mut!(role_watcher);
-    while let Ok(Some(event)) = role_watcher.try_next().await{
-       match event{
-           Event::Applied(role) => {
-               let rbac_id = RBACId::from_role(&role);
-               // remove the current permission and store the new ones in case our permissions changed
-               shared.remove_permission_id(&rbac_id);
-               shared.store_permission_id(&rbac_id, &role.rules.unwrap_or_default());
-           },
-           Event::Restarted(roles) => {
-               // watch restarted, remove all current records and refill with new ones
-               shared.remove_all_of_type(IDType::Role);
-               for role in roles{
+    let mut backoff = WatchBackoff::default();
+    loop{
+        let role_watcher = watcher(role_api.clone(), list_params.clone());
+        pin_mut!(role_watcher);
+        loop{
+            let event = match role_watcher.try_next().await{
+                Ok(Some(event)) => {
+                    backoff.reset();
+                    event
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    // polling the watcher again resumes from the last resource version/bookmark it saw, it only
+                    // relists (and sends Restarted) if that version is too old
+                    shared.metrics.watch_error(ROLES);
+                    warn!("role watch failed, resuming in {:?}: {}", backoff.delay(), err);
+                    backoff.wait().await;
+                    continue;
+                }
+            };
+           // the event may take several locks, readers only see its generation once it's fully applied
+           let _change = shared.generation.begin_change();
+           match event{
+               Event::Applied(role) => {
                    let rbac_id = RBACId::from_role(&role);
                    shared.store_permission_id(&rbac_id, &role.rules.unwrap_or_default());
-               }
-           },
-           Event::Deleted(role) => {
-               // remove our current record of this role since it's now deleted
-               let rbac_id = RBACId::from_role(&role);
-               shared.remove_permission_id(&rbac_id);
-           },
-       }
+               },
+               Event::Restarted(roles) => {
+                   // watch restarted, replace all current records with the new ones
+                   let permissions = roles.into_iter()
+                       .map(|role| (RBACId::from_role(&role), role.rules.unwrap_or_default()))
+                       .collect();
+                   shared.replace_all_of_type(IDType::Role, permissions);
+                   shared.mark_synced(IDType::Role);
+               },
+               Event::Deleted(role) => {
+                   // remove our current record of this role since it's now deleted
+                   let rbac_id = RBACId::from_role(&role);
+                   shared.remove_permission_id(&rbac_id);
+               },
+           }
+        }
+        // the new watcher relists, the state is only replaced once that list arrives (as Restarted)
+        shared.metrics.watch_restart(ROLES);
+        warn!("role watch ended, restarting it in {:?}", backoff.delay());
+        backoff.wait().await;
     }
 }
 
-async fn refresh_cluster_role(client: Client, shared: Arc<Shared>){
+async fn refresh_cluster_role(client: Client, list_params: ListParams, shared: Arc<Shared>){
     info!("Starting cluster role controller");
     let cluster_role_api = Api::<ClusterRole>::all(client.clone());
-    let cluster_role_watcher = watcher(cluster_role_api, ListParams::default());
-    pin_mut!(cluster_role_watcher);
-    while let Ok(Some(event)) = cluster_role_watcher.try_next().await{
-       match event{
-           Event::Applied(cluster_role) => {
-               let rbac_id = RBACId::from_cluster_role(&cluster_role);
-               // remove stale permission and re-add
-               shared.remove_permission_id(&rbac_id);
-               shared.store_permission_id(&rbac_id, &cluster_role.rules.unwrap_or_default())
-           },
-           Event::Restarted(cluster_roles) => {
-               // watch restarted, purge current events and refill
-               shared.remove_all_of_type(IDType::ClusterRole);
-               for cluster_role in cluster_roles{
+    let mut backoff = WatchBackoff::default();
+    loop{
+        let cluster_role_watcher = watcher(cluster_role_api.clone(), list_params.clone());
+        pin_mut!(cluster_role_watcher);
+        loop{
+            let event = match cluster_role_watcher.try_next().await{
+                Ok(Some(event)) => {
+                    backoff.reset();
+                    event
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    // polling the watcher again resumes from the last resource version/bookmark it saw, it only
+                    // relists (and sends Restarted) if that version is too old
+                    shared.metrics.watch_error(CLUSTER_ROLES);
+                    warn!("cluster role watch failed, resuming in {:?}: {}", backoff.delay(), err);
+                    backoff.wait().await;
+                    continue;
+                }
+            };
+           // the event may take several locks, readers only see its generation once it's fully applied
+           let _change = shared.generation.begin_change();
+           match event{
+               Event::Applied(cluster_role) => {
+                   shared.store_cluster_role(cluster_role)
+               },
+               Event::Restarted(cluster_roles) => {
+                   // watch restarted, replace current records with the new ones
+                   shared.replace_cluster_roles(cluster_roles);
+                   shared.mark_synced(IDType::ClusterRole);
+               },
+               Event::Deleted(cluster_role) => {
+                   // remove our current record since this permission is deleted
                    let rbac_id = RBACId::from_cluster_role(&cluster_role);
-                   shared.store_permission_id(&rbac_id, &cluster_role.rules.unwrap_or_default());
-               }
-           },
-           Event::Deleted(cluster_role) => {
-               // remove our current record since this permission is deleted
-               let rbac_id = RBACId::from_cluster_role(&cluster_role);
-               shared.remove_permission_id(&rbac_id);
-           },
-       }
+                   shared.remove_cluster_role(&rbac_id);
+               },
+           }
+        }
+        // the new watcher relists, the state is only replaced once that list arrives (as Restarted)
+        shared.metrics.watch_restart(CLUSTER_ROLES);
+        warn!("cluster role watch ended, restarting it in {:?}", backoff.delay());
+        backoff.wait().await;
+    }
+}
+
+/// periodically persists the permissions to the cache, once they're synced (so stale cached state isn't rewritten)
+async fn persist_permissions(interval: Duration, shared: Arc<Shared>){
+    loop{
+        rt::time::sleep(interval).await;
+        if shared.is_synced(){
+            shared.persist();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controller::testing::{cluster_role, role, rule};
+    use k8s_openapi::api::rbac::v1::AggregationRule;
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::LabelSelectorRequirement;
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    fn labels(pairs: &[(&str, &str)]) -> BTreeMap<String, String>{
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    fn match_labels(pairs: &[(&str, &str)]) -> LabelSelector{
+        LabelSelector{match_labels: Some(labels(pairs)), ..LabelSelector::default()}
+    }
+
+    fn match_expression(key: &str, operator: &str, values: &[&str]) -> LabelSelector{
+        let requirement = LabelSelectorRequirement{
+            key: key.to_string(),
+            operator: operator.to_string(),
+            values: Some(values.iter().map(|value| value.to_string()).collect()),
+        };
+        LabelSelector{match_expressions: Some(vec![requirement]), ..LabelSelector::default()}
+    }
+
+    fn labeled(mut cluster_role: ClusterRole, pairs: &[(&str, &str)]) -> ClusterRole{
+        cluster_role.metadata.labels = Some(labels(pairs));
+        cluster_role
+    }
+
+    /// a cluster role aggregating the cluster roles labeled with aggregate-to-<name>
+    fn aggregating(name: &str, rules: Vec<PolicyRule>) -> ClusterRole{
+        ClusterRole{
+            aggregation_rule: Some(AggregationRule{cluster_role_selectors: Some(vec![match_labels(&[(&format!("aggregate-to-{}", name), "true")])])}),
+            ..cluster_role(name, rules)
+        }
+    }
+
+    fn rules_of(controller: &PermissionController, name: &str) -> Vec<PolicyRule>{
+        controller.get_permission_for_id(&RBACId::from_cluster_role(&cluster_role(name, vec![]))).unwrap()
+    }
+
+    #[test]
+    fn only_changed_rules_are_published(){
+        let controller = PermissionController::from_roles(vec![], vec![]);
+        let mut changes = controller.subscribe();
+        let reader = role("default", "pod-reader", vec![rule(&[""], &["pods"], &["get"])]);
+        let id = RBACId::from_role(&reader);
+        let rules = reader.rules.unwrap();
+
+        controller.shared.store_permission_id(&id, &rules);
+        assert_eq!(changes.try_recv(), Ok(id.clone()));
+        // storing the same rules again changes nothing
+        controller.shared.store_permission_id(&id, &rules);
+        assert_eq!(changes.try_recv(), Err(TryRecvError::Empty));
+
+        controller.shared.remove_permission_id(&id);
+        assert_eq!(changes.try_recv(), Ok(id.clone()));
+        controller.shared.remove_permission_id(&id);
+        assert_eq!(changes.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn matching_children_are_aggregated(){
+        let own = rule(&[""], &["configmaps"], &["get"]);
+        let child = rule(&[""], &["pods"], &["get"]);
+        let controller = PermissionController::from_roles(vec![], vec![
+            aggregating("view", vec![own.clone()]),
+            labeled(cluster_role("pod-reader", vec![child.clone()]), &[("aggregate-to-view", "true")]),
+            labeled(cluster_role("secret-reader", vec![rule(&[""], &["secrets"], &["get"])]), &[("aggregate-to-view", "false")]),
+        ]);
+
+        assert_eq!(rules_of(&controller, "view"), vec![own, child]);
+    }
+
+    #[test]
+    fn stored_children_are_aggregated(){
+        let own = rule(&[""], &["configmaps"], &["get"]);
+        let controller = PermissionController::from_roles(vec![], vec![aggregating("view", vec![own.clone()])]);
+        let mut changes = controller.subscribe();
+        let view_id = RBACId::from_cluster_role(&cluster_role("view", vec![]));
+
+        let pods = rule(&[""], &["pods"], &["get"]);
+        controller.shared.store_cluster_role(labeled(cluster_role("pod-reader", vec![pods.clone()]), &[("aggregate-to-view", "true")]));
+        assert_eq!(rules_of(&controller, "view"), vec![own.clone(), pods]);
+        let changed: HashSet<RBACId> = std::iter::from_fn(|| changes.try_recv().ok()).collect();
+        assert!(changed.contains(&view_id));
+
+        // an updated child replaces its previous rules in the aggregation
+        let services = rule(&[""], &["services"], &["get"]);
+        controller.shared.store_cluster_role(labeled(cluster_role("pod-reader", vec![services.clone()]), &[("aggregate-to-view", "true")]));
+        assert_eq!(rules_of(&controller, "view"), vec![own.clone(), services]);
+
+        // as does a child which no longer matches
+        controller.shared.store_cluster_role(cluster_role("pod-reader", vec![rule(&[""], &["pods"], &["get"])]));
+        assert_eq!(rules_of(&controller, "view"), vec![own]);
+    }
+
+    #[test]
+    fn removed_children_are_no_longer_aggregated(){
+        let own = rule(&[""], &["configmaps"], &["get"]);
+        let pod_reader = labeled(cluster_role("pod-reader", vec![rule(&[""], &["pods"], &["get"])]), &[("aggregate-to-view", "true")]);
+        let controller = PermissionController::from_roles(vec![], vec![aggregating("view", vec![own.clone()]), pod_reader.clone()]);
+        assert_eq!(rules_of(&controller, "view").len(), 2);
+
+        controller.shared.remove_cluster_role(&RBACId::from_cluster_role(&pod_reader));
+        assert_eq!(rules_of(&controller, "view"), vec![own]);
+    }
+
+    #[test]
+    fn nested_aggregated_roles_are_aggregated(){
+        let view_rule = rule(&[""], &["pods"], &["get"]);
+        let edit_rule = rule(&[""], &["pods"], &["update"]);
+        let admin_rule = rule(&["rbac.authorization.k8s.io"], &["roles"], &["create"]);
+        let controller = PermissionController::from_roles(vec![], vec![
+            aggregating("admin", vec![admin_rule.clone()]),
+            labeled(aggregating("edit", vec![edit_rule.clone()]), &[("aggregate-to-admin", "true")]),
+            labeled(aggregating("view", vec![]), &[("aggregate-to-edit", "true")]),
+            labeled(cluster_role("pod-reader", vec![view_rule.clone()]), &[("aggregate-to-view", "true")]),
+        ]);
+
+        assert_eq!(rules_of(&controller, "view"), vec![view_rule.clone()]);
+        assert_eq!(rules_of(&controller, "edit"), vec![edit_rule.clone(), view_rule.clone()]);
+        assert_eq!(rules_of(&controller, "admin"), vec![admin_rule, edit_rule, view_rule]);
+    }
+
+    #[test]
+    fn selector_matches_in(){
+        let selector = match_expression("tier", "In", &["web", "db"]);
+        assert!(selector_matches(&selector, &labels(&[("tier", "db")])));
+        assert!(!selector_matches(&selector, &labels(&[("tier", "cache")])));
+        assert!(!selector_matches(&selector, &labels(&[])));
+    }
+
+    #[test]
+    fn selector_matches_not_in(){
+        let selector = match_expression("tier", "NotIn", &["web", "db"]);
+        assert!(!selector_matches(&selector, &labels(&[("tier", "db")])));
+        assert!(selector_matches(&selector, &labels(&[("tier", "cache")])));
+        assert!(selector_matches(&selector, &labels(&[])));
+    }
+
+    #[test]
+    fn selector_matches_exists(){
+        let selector = match_expression("tier", "Exists", &[]);
+        assert!(selector_matches(&selector, &labels(&[("tier", "")])));
+        assert!(!selector_matches(&selector, &labels(&[("app", "web")])));
+    }
+
+    #[test]
+    fn selector_matches_does_not_exist(){
+        let selector = match_expression("tier", "DoesNotExist", &[]);
+        assert!(!selector_matches(&selector, &labels(&[("tier", "")])));
+        assert!(selector_matches(&selector, &labels(&[("app", "web")])));
     }
 }
@@ -1,7 +1,41 @@
 use crate::controller::grant_controller::GrantController;
 use crate::controller::permission_controller::PermissionController;
+use crate::controller::rbac_grant::RBACId;
+use actix_web::rt;
+use log::{debug, log_enabled, warn, Level};
+use std::sync::Arc;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tokio_util::sync::CancellationToken;
 
 pub struct RBACController{
     pub(crate) grant_controller: GrantController,
     pub(crate) permission_controller: PermissionController
-}
\ No newline at end of file
+}
+
+impl RBACController{
+    /// re-resolves the subjects affected by each change to a role's rules and logs them (at debug level), until
+    /// shutdown is cancelled
+    pub(crate) fn report_permission_changes(self: &Arc<Self>, shutdown: CancellationToken){
+        let changes = self.permission_controller.subscribe();
+        rt::spawn(shutdown.run_until_cancelled_owned(report_affected_subjects(self.clone(), changes)));
+    }
+}
+
+async fn report_affected_subjects(controller: Arc<RBACController>, mut changes: Receiver<RBACId>){
+    loop{
+        let id = match changes.recv().await{
+            Ok(id) => id,
+            Err(RecvError::Lagged(missed)) => {
+                warn!("missed {} role change(s) while reporting affected subjects", missed);
+                continue;
+            },
+            Err(RecvError::Closed) => return,
+        };
+        // looking up the grants of a role goes through every grant, skip it when it wouldn't be logged anyway
+        if !log_enabled!(Level::Debug){
+            continue;
+        }
+        let (grants, subjects) = controller.grant_controller.get_grants_for_role(&id);
+        debug!("rules of {} {} changed, affecting {} subject(s) through {} grant(s)", id.rbac_type, id.name, subjects.len(), grants.len());
+    }
+}
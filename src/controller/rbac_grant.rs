@@ -1,12 +1,40 @@
 use std::fmt;
 use std::fmt::Formatter;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use k8s_openapi::api::rbac::v1::{Role, ClusterRole, RoleBinding, ClusterRoleBinding, Subject};
 use kube::ResourceExt;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// api group of users/groups (and of the RBAC resources themselves)
+pub const RBAC_API_GROUP: &str = "rbac.authorization.k8s.io";
+/// group containing every authenticated user/service account
+pub const AUTHENTICATED_GROUP: &str = "system:authenticated";
+/// group containing every service account, system:serviceaccounts:<namespace> contains those of one namespace
+pub const SERVICE_ACCOUNTS_GROUP: &str = "system:serviceaccounts";
+/// group containing unauthenticated requests, which k8s treats as the system:anonymous user
+pub const UNAUTHENTICATED_GROUP: &str = "system:unauthenticated";
+/// user unauthenticated requests are made as
+pub const ANONYMOUS_USER: &str = "system:anonymous";
+/// label set by the tool (helm, an operator, ...) which manages a resource
+pub const MANAGED_BY_LABEL: &str = "app.kubernetes.io/managed-by";
+/// prefix of the user name ServiceAccounts authenticate as, system:serviceaccount:<namespace>:<name>
+pub const SERVICE_ACCOUNT_USER_PREFIX: &str = "system:serviceaccount:";
+
+/// reason given for grants whose roleRef has no name
+pub const EMPTY_ROLE_REF_NAME: &str = "EmptyRoleRefName";
+
+/// the namespace as stored internally - None is cluster-scoped and Some is always a real namespace, so an empty
+/// namespace (as k8s objects and user input may carry) is treated as no namespace
+pub fn non_empty_namespace(namespace: Option<String>) -> Option<String>{
+    namespace.filter(|namespace| !namespace.is_empty())
+}
 
 /// Generic form of an identifier for an RBAC resource (role/cluster role). Does not contain rules
 /// To avoid re-storing rules in memory
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+/// rbac_type is part of the key, so a Role and a ClusterRole with the same name are always distinct ids - lookups only
+/// resolve to the kind of role the binding's roleRef names
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct RBACId{
     /// type of resource which holds permissions - e.x. role or cluster_role
     pub(crate) rbac_type: IDType,
@@ -20,23 +48,36 @@ impl RBACId {
     pub fn from_role(role: &Role) -> RBACId{
         RBACId{
             rbac_type: IDType::Role,
-            namespace: role.metadata.namespace.clone(),
+            namespace: non_empty_namespace(role.metadata.namespace.clone()),
             name: role.metadata.name.clone().unwrap_or_default(),
         }
     }
+    /// ClusterRoles are cluster-scoped, so their id has no namespace
     pub fn from_cluster_role(cluster_role: &ClusterRole) -> RBACId{
         RBACId{
             rbac_type: IDType::ClusterRole,
-            namespace: cluster_role.metadata.namespace.clone(),
+            namespace: None,
             name: cluster_role.metadata.name.clone().unwrap_or_default()
         }
     }
+    /// the id as from_role/from_cluster_role build it, for ids stored by versions which gave ClusterRole ids the
+    /// namespace of their RoleBinding, or an empty one - these would never match the ClusterRole's own id
+    pub fn normalized(self) -> RBACId{
+        let namespace = match self.rbac_type{
+            IDType::ClusterRole => None,
+            _ => non_empty_namespace(self.namespace),
+        };
+        RBACId{
+            namespace,
+            ..self
+        }
+    }
 }
 
-/// Object which grants RBAC permissions. Generic form of role_binding/cluster_role_binding
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+/// Object which grants RBAC permissions. Generic form of role_binding/cluster_role_binding. Grants are identified by
+/// their type, namespace and name only, see the Hash/PartialEq impls
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct RBACGrant {
-    // TODO: Custom hash (and maybe eq?) function which ignores permissions_id.
     /// type of resource which grants RBAC permissions - e.x. role_binding or cluster_role_binding
     pub(crate) grant_type: GrantType,
     /// namespace which the permission grant occurs in - may be none if the grant is cluster-wide
@@ -47,59 +88,91 @@ pub struct RBACGrant {
     pub(crate) permissions_id: RBACId,
 }
 
+/// the permissions_id is left out, so that a grant whose binding now references another role is still the same grant,
+/// and replaces the entries of its previous version rather than being stored next to them
+impl PartialEq for RBACGrant {
+    fn eq(&self, other: &RBACGrant) -> bool {
+        self.grant_type == other.grant_type && self.namespace == other.namespace && self.name == other.name
+    }
+}
+
+impl Eq for RBACGrant {}
+
+impl Hash for RBACGrant {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.grant_type.hash(state);
+        self.namespace.hash(state);
+        self.name.hash(state);
+    }
+}
+
 impl RBACGrant {
     pub fn from_role_binding(role_binding: &RoleBinding) -> RBACGrant{
+        let namespace = non_empty_namespace(role_binding.metadata.namespace.clone());
         let rbac_id = match role_binding.role_ref.kind.as_str(){
             "Role" => RBACId{
                     rbac_type: IDType::Role,
-                    namespace: role_binding.metadata.namespace.clone(),
+                    namespace: namespace.clone(),
                     name: role_binding.role_ref.name.clone(),
                 },
+            // ClusterRoles are cluster-scoped, so the id matches RBACId::from_cluster_role's whatever namespace the
+            // binding is in
             "ClusterRole" => RBACId{
                     rbac_type: IDType::ClusterRole,
-                    namespace: Some("".to_string()),
+                    namespace: None,
                     name: role_binding.role_ref.name.clone(),
             },
             _ => RBACId{
                 rbac_type: IDType::Unknown,
-                namespace: role_binding.metadata.namespace.clone(),
+                namespace: namespace.clone(),
                 name: role_binding.role_ref.name.clone(),
             }
         };
 
         RBACGrant{
             grant_type: GrantType::RoleBinding,
-            namespace: role_binding.metadata.namespace.clone(),
+            namespace,
             name: role_binding.metadata.name.clone().unwrap_or_default(),
             permissions_id: rbac_id
         }
     }
 
+    /// why the grant can never resolve to a role, None if it's well formed
+    pub fn malformed_reason(&self) -> Option<&'static str>{
+        if self.permissions_id.name.is_empty(){
+            return Some(EMPTY_ROLE_REF_NAME);
+        }
+        None
+    }
+
     pub fn from_cluster_role_binding(binding: &ClusterRoleBinding) -> RBACGrant{
+        let namespace = non_empty_namespace(binding.namespace());
         let rbac_id = match binding.role_ref.kind.as_str(){
+            // ClusterRoles are cluster-scoped, like in from_role_binding the id never carries a namespace
             "ClusterRole" => RBACId{
                 rbac_type: IDType::ClusterRole,
-                namespace: binding.namespace(),
+                namespace: None,
                 name: binding.role_ref.name.clone()
             },
             _ => RBACId{
                 rbac_type: IDType::Unknown,
-                namespace: binding.namespace(),
-                name: binding.name(),
+                namespace: namespace.clone(),
+                name: binding.role_ref.name.clone(),
             }
         };
 
         RBACGrant{
             grant_type: GrantType::ClusterRoleBinding,
-            namespace: binding.namespace(),
+            namespace,
             name: binding.name(),
             permissions_id: rbac_id
         }
     }
 }
 
-/// Enum for the Types of Grants - Can be expanded to support other sources of permissions
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+/// Enum for the Types of Grants - Can be expanded to support other sources of permissions, each watched by a
+/// GrantSource (see grant_controller)
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub enum GrantType{
     RoleBinding,
     ClusterRoleBinding,
@@ -119,7 +192,7 @@ impl fmt::Display for GrantType{
 }
 
 /// Enum for the Type of RBAC resources - Can be expanded to other resources which hold RBAC rules
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize, ToSchema)]
 pub enum IDType{
     Role,
     ClusterRole,
@@ -144,7 +217,7 @@ impl fmt::Display for IDType{
 
 /// User/ServiceAccount/Group that a binding applies to. Re-implemented form of a k8s subject so that we
 /// can hash it for use in our maps
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub struct GrantSubject{
     /// kind of the subject - User/Group/ServiceAccount
     pub kind: SubjectKind,
@@ -157,28 +230,103 @@ pub struct GrantSubject{
 }
 
 impl GrantSubject {
-    pub fn from_subject(subject: &Subject) -> GrantSubject{
-        let binding_kind = match subject.kind.as_str(){
-            "User" => SubjectKind::User,
-            "Group" => SubjectKind::Group,
-            "ServiceAccount" => SubjectKind::ServiceAccount,
-            _ => SubjectKind::Unknown,
-        };
+    /// Creates a GrantSubject from a k8s subject. binding_namespace is the namespace of the binding which
+    /// lists the subject, and is only used for ServiceAccounts which don't specify their own namespace
+    pub fn from_subject(subject: &Subject, binding_namespace: Option<String>) -> GrantSubject{
+        let binding_kind = SubjectKind::from_kind(&subject.kind);
+        // normalize the api group the same way k8s defaults it, so that a subject which omits the api group is the
+        // same subject as one that specifies it
         let api_group = match subject.api_group.clone(){
-            Some(group) => group,
-            None => "".to_string(),
+            Some(group) if !group.is_empty() => group,
+            _ => binding_kind.default_api_group(),
+        };
+        // a ServiceAccount always lives in a namespace - k8s uses the SA's own namespace when given (even if
+        // it differs from the binding's) and falls back to the binding's namespace otherwise
+        let namespace = match binding_kind{
+            SubjectKind::ServiceAccount => non_empty_namespace(subject.namespace.clone())
+                .or_else(|| non_empty_namespace(binding_namespace)),
+            _ => non_empty_namespace(subject.namespace.clone()),
         };
         GrantSubject{
             kind: binding_kind,
             name: subject.name.clone(),
-            namespace: subject.namespace.clone(),
+            namespace,
             api_group
         }
     }
+
+    /// Creates a GrantSubject for a group, such as the groups k8s implicitly places users in
+    pub fn group(name: &str) -> GrantSubject{
+        GrantSubject{
+            kind: SubjectKind::Group,
+            name: name.to_string(),
+            namespace: None,
+            api_group: SubjectKind::Group.default_api_group(),
+        }
+    }
+
+    /// Creates a GrantSubject for a user name as seen by authentication. ServiceAccounts authenticate as
+    /// system:serviceaccount:<namespace>:<name>, while RBAC stores them as ServiceAccount subjects, so such names
+    /// are turned into the ServiceAccount subject
+    pub fn from_user_name(name: &str) -> GrantSubject{
+        let service_account = name.strip_prefix(SERVICE_ACCOUNT_USER_PREFIX)
+            .and_then(|rest| rest.split_once(':'))
+            .filter(|(namespace, name)| !namespace.is_empty() && !name.is_empty() && !name.contains(':'));
+        match service_account{
+            Some((namespace, name)) => GrantSubject{
+                kind: SubjectKind::ServiceAccount,
+                name: name.to_string(),
+                namespace: Some(namespace.to_string()),
+                api_group: SubjectKind::ServiceAccount.default_api_group(),
+            },
+            None => GrantSubject{
+                kind: SubjectKind::User,
+                name: name.to_string(),
+                namespace: None,
+                api_group: SubjectKind::User.default_api_group(),
+            },
+        }
+    }
+
+    /// Groups that k8s places this subject in through authentication, so it also receives their grants:
+    /// - every User is in the `users` groups of the config (system:authenticated by default), except system:anonymous which is
+    ///   only in system:unauthenticated
+    /// - every ServiceAccount is in system:authenticated, system:serviceaccounts and system:serviceaccounts:<namespace>
+    pub fn implicit_groups(&self, config: &ImplicitGroups) -> Vec<GrantSubject>{
+        match self.kind{
+            SubjectKind::User if self.name == ANONYMOUS_USER => vec![GrantSubject::group(UNAUTHENTICATED_GROUP)],
+            SubjectKind::User => config.users.iter().map(|group| GrantSubject::group(group)).collect(),
+            SubjectKind::ServiceAccount => {
+                let mut groups = vec![
+                    GrantSubject::group(AUTHENTICATED_GROUP),
+                    GrantSubject::group(SERVICE_ACCOUNTS_GROUP),
+                ];
+                if let Some(namespace) = &self.namespace{
+                    groups.push(GrantSubject::group(&format!("{}:{}", SERVICE_ACCOUNTS_GROUP, namespace)));
+                }
+                groups
+            },
+            _ => vec![],
+        }
+    }
+}
+
+/// Groups users are implicitly placed in when resolving their permissions, from USER_IMPLICIT_GROUPS
+#[derive(Clone, Debug)]
+pub struct ImplicitGroups{
+    pub users: Vec<String>,
+}
+
+impl Default for ImplicitGroups{
+    fn default() -> Self{
+        ImplicitGroups{
+            users: vec![AUTHENTICATED_GROUP.to_string()],
+        }
+    }
 }
 
 /// Enum for the ptotential kinds of subjects
-#[derive(Eq, PartialEq, Hash, Clone, Debug)]
+#[derive(Eq, PartialEq, Hash, Clone, Debug, Serialize, Deserialize)]
 pub enum SubjectKind{
     User,
     Group,
@@ -186,6 +334,26 @@ pub enum SubjectKind{
     Unknown
 }
 
+impl SubjectKind{
+    /// parses the kind of a k8s subject
+    pub fn from_kind(kind: &str) -> SubjectKind{
+        match kind{
+            "User" => SubjectKind::User,
+            "Group" => SubjectKind::Group,
+            "ServiceAccount" => SubjectKind::ServiceAccount,
+            _ => SubjectKind::Unknown,
+        }
+    }
+
+    /// the api group k8s defaults subjects of this kind to
+    pub fn default_api_group(&self) -> String{
+        match self{
+            SubjectKind::User | SubjectKind::Group => RBAC_API_GROUP.to_string(),
+            _ => "".to_string(),
+        }
+    }
+}
+
 impl fmt::Display for SubjectKind{
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self{
@@ -204,3 +372,141 @@ impl fmt::Display for SubjectKind{
         }
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controller::testing::{cluster_role, cluster_role_binding, role, role_binding};
+
+    fn subject(kind: &str, name: &str, namespace: Option<&str>, api_group: Option<&str>) -> Subject{
+        Subject{
+            kind: kind.to_string(),
+            name: name.to_string(),
+            namespace: namespace.map(|namespace| namespace.to_string()),
+            api_group: api_group.map(|group| group.to_string()),
+        }
+    }
+
+    #[test]
+    fn service_account_keeps_its_own_namespace(){
+        let account = subject("ServiceAccount", "deployer", Some("ci"), None);
+        let grant_subject = GrantSubject::from_subject(&account, Some("production".to_string()));
+        assert_eq!(grant_subject.kind, SubjectKind::ServiceAccount);
+        assert_eq!(grant_subject.namespace, Some("ci".to_string()));
+
+        let account = subject("ServiceAccount", "deployer", None, None);
+        let grant_subject = GrantSubject::from_subject(&account, Some("production".to_string()));
+        assert_eq!(grant_subject.namespace, Some("production".to_string()));
+    }
+
+    #[test]
+    fn user_api_group_is_defaulted(){
+        let with_group = subject("User", "alice", None, Some(RBAC_API_GROUP));
+        let without_group = subject("User", "alice", None, None);
+        let empty_group = subject("User", "alice", None, Some(""));
+        let expected = GrantSubject::from_subject(&with_group, None);
+        assert_eq!(expected.api_group, RBAC_API_GROUP);
+        assert_eq!(GrantSubject::from_subject(&without_group, None), expected);
+        assert_eq!(GrantSubject::from_subject(&empty_group, Some("default".to_string())), expected);
+    }
+
+    #[test]
+    fn empty_role_ref_name_is_malformed(){
+        let grant = RBACGrant::from_role_binding(&role_binding("default", "nameless", "Role", "", vec![]));
+        assert_eq!(grant.malformed_reason(), Some(EMPTY_ROLE_REF_NAME));
+        let grant = RBACGrant::from_cluster_role_binding(&cluster_role_binding("nameless", "ClusterRole", "", vec![]));
+        assert_eq!(grant.malformed_reason(), Some(EMPTY_ROLE_REF_NAME));
+        // the name of the binding doesn't stand in for the missing role name
+        let grant = RBACGrant::from_cluster_role_binding(&cluster_role_binding("nameless", "Role", "", vec![]));
+        assert_eq!(grant.permissions_id.rbac_type, IDType::Unknown);
+        assert_eq!(grant.malformed_reason(), Some(EMPTY_ROLE_REF_NAME));
+
+        let grant = RBACGrant::from_role_binding(&role_binding("default", "named", "Role", "view", vec![]));
+        assert_eq!(grant.malformed_reason(), None);
+    }
+
+    #[test]
+    fn service_account_user_names_are_service_accounts(){
+        let account = GrantSubject::from_user_name("system:serviceaccount:ci:deployer");
+        assert_eq!(account.kind, SubjectKind::ServiceAccount);
+        assert_eq!(account.namespace, Some("ci".to_string()));
+        assert_eq!(account.name, "deployer");
+        assert_eq!(account, GrantSubject::from_subject(&subject("ServiceAccount", "deployer", Some("ci"), None), None));
+
+        // names with too few (or too many) segments stay users
+        for name in ["system:serviceaccount:ci", "system:serviceaccount:ci:", "system:serviceaccount::deployer", "system:serviceaccount:ci:a:b"]{
+            let user = GrantSubject::from_user_name(name);
+            assert_eq!(user.kind, SubjectKind::User, "{}", name);
+            assert_eq!(user.name, name);
+            assert_eq!(user.namespace, None);
+        }
+    }
+
+    #[test]
+    fn empty_namespaces_are_cluster_scoped(){
+        let mut namespaced_role = role("default", "view", vec![]);
+        assert_eq!(RBACId::from_role(&namespaced_role).namespace, Some("default".to_string()));
+        namespaced_role.metadata.namespace = Some("".to_string());
+        assert_eq!(RBACId::from_role(&namespaced_role).namespace, None);
+        assert_eq!(RBACId::from_cluster_role(&cluster_role("view", vec![])).namespace, None);
+
+        let mut binding = role_binding("default", "view", "ClusterRole", "view", vec![]);
+        let grant = RBACGrant::from_role_binding(&binding);
+        assert_eq!(grant.namespace, Some("default".to_string()));
+        assert_eq!(grant.permissions_id, RBACId::from_cluster_role(&cluster_role("view", vec![])));
+        binding.metadata.namespace = Some("".to_string());
+        let grant = RBACGrant::from_role_binding(&binding);
+        assert_eq!(grant.namespace, None);
+
+        let stored = RBACId{ rbac_type: IDType::Role, namespace: Some("".to_string()), name: "view".to_string() };
+        assert_eq!(stored.normalized().namespace, None);
+    }
+
+    #[test]
+    fn cluster_role_binding_id_matches_cluster_role(){
+        let grant = RBACGrant::from_cluster_role_binding(&cluster_role_binding("admins", "ClusterRole", "admin", vec![]));
+        assert_eq!(grant.permissions_id, RBACId::from_cluster_role(&cluster_role("admin", vec![])));
+        assert_eq!(grant.namespace, None);
+    }
+
+    #[test]
+    fn grants_are_equal_regardless_of_their_role(){
+        use std::collections::hash_map::DefaultHasher;
+        let hash = |grant: &RBACGrant|{
+            let mut hasher = DefaultHasher::new();
+            grant.hash(&mut hasher);
+            hasher.finish()
+        };
+        let view = RBACGrant::from_role_binding(&role_binding("default", "access", "Role", "view", vec![]));
+        let edit = RBACGrant::from_role_binding(&role_binding("default", "access", "ClusterRole", "edit", vec![]));
+        assert_ne!(view.permissions_id, edit.permissions_id);
+        assert_eq!(view, edit);
+        assert_eq!(hash(&view), hash(&edit));
+
+        let other = RBACGrant::from_role_binding(&role_binding("other", "access", "Role", "view", vec![]));
+        assert_ne!(view, other);
+    }
+
+    #[test]
+    fn implicit_groups_of_each_kind(){
+        let names = |subject: GrantSubject, config: &ImplicitGroups| -> Vec<String>{
+            subject.implicit_groups(config).into_iter().map(|group| group.name).collect()
+        };
+        let defaults = ImplicitGroups::default();
+        assert_eq!(names(GrantSubject::from_user_name("alice"), &defaults), vec![AUTHENTICATED_GROUP]);
+        assert_eq!(names(GrantSubject::from_user_name(ANONYMOUS_USER), &defaults), vec![UNAUTHENTICATED_GROUP]);
+        let account = GrantSubject::from_subject(&subject("ServiceAccount", "deployer", Some("ci"), None), None);
+        assert_eq!(names(account, &defaults), vec![AUTHENTICATED_GROUP, SERVICE_ACCOUNTS_GROUP, "system:serviceaccounts:ci"]);
+        let group = GrantSubject::group("devs");
+        assert!(names(group, &defaults).is_empty());
+
+        // USER_IMPLICIT_GROUPS only changes the groups of users
+        let custom = ImplicitGroups{ users: vec!["employees".to_string(), AUTHENTICATED_GROUP.to_string()] };
+        assert_eq!(names(GrantSubject::from_user_name("alice"), &custom), vec!["employees", AUTHENTICATED_GROUP]);
+        assert_eq!(names(GrantSubject::from_user_name(ANONYMOUS_USER), &custom), vec![UNAUTHENTICATED_GROUP]);
+        let account = GrantSubject::from_subject(&subject("ServiceAccount", "deployer", Some("ci"), None), None);
+        assert_eq!(names(account, &custom).len(), 3);
+        let group = GrantSubject::group("devs");
+        assert!(names(group, &custom).is_empty());
+    }
+}
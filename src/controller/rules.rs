@@ -0,0 +1,148 @@
+use k8s_openapi::api::rbac::v1::PolicyRule;
+
+/// Wildcard which matches any verb/resource/api group in a PolicyRule
+pub const WILDCARD: &str = "*";
+/// verbs which modify resources, the wildcard included
+pub const WRITE_VERBS: &[&str] = &["create", "update", "patch", "delete", "deletecollection", WILDCARD];
+
+/// Checks if a rule grants the verb on the resource in the api group, respecting wildcards. Only considers
+/// resource rules - rules which only list non_resource_urls never match
+pub fn rule_matches(rule: &PolicyRule, verb: &str, resource: &str, api_group: &str) -> bool{
+    let resources = match &rule.resources{
+        Some(resources) => resources,
+        None => return false,
+    };
+    let api_groups = match &rule.api_groups{
+        Some(api_groups) => api_groups,
+        None => return false,
+    };
+    contains_or_wildcard(&rule.verbs, verb)
+        && contains_or_wildcard(resources, resource)
+        && contains_or_wildcard(api_groups, api_group)
+}
+
+/// a rule without resource names applies to every object, one with resource names only to the named objects. So an
+/// action on every object (without a resource name) is only allowed by rules without resource names
+pub fn resource_name_matches(rule: &PolicyRule, resource_name: Option<&str>) -> bool{
+    let names = match &rule.resource_names{
+        Some(names) if !names.is_empty() => names,
+        _ => return true,
+    };
+    match resource_name{
+        Some(name) => names.iter().any(|n| n == name),
+        None => false,
+    }
+}
+
+/// merges rules which only differ in their verbs into one rule allowing the verbs of all of them, which also collapses
+/// identical rules. Merged rules keep the position of the first of them
+pub fn merge_rules(rules: Vec<PolicyRule>) -> Vec<PolicyRule>{
+    let mut merged: Vec<PolicyRule> = Vec::new();
+    for mut rule in rules{
+        let rule_verbs = std::mem::take(&mut rule.verbs);
+        let index = match merged.iter().position(|existing| same_targets(existing, &rule)){
+            Some(index) => index,
+            None => {
+                merged.push(rule);
+                merged.len() - 1
+            },
+        };
+        let verbs = &mut merged[index].verbs;
+        for verb in rule_verbs{
+            if !verbs.contains(&verb){
+                verbs.push(verb);
+            }
+        }
+    }
+    merged
+}
+
+/// whether the rules apply to the same things, so that they only differ in their verbs
+fn same_targets(a: &PolicyRule, b: &PolicyRule) -> bool{
+    a.api_groups == b.api_groups
+        && a.resources == b.resources
+        && a.resource_names == b.resource_names
+        && a.non_resource_urls == b.non_resource_urls
+}
+
+fn contains_or_wildcard(values: &[String], value: &str) -> bool{
+    values.iter().any(|v| v == WILDCARD || v == value)
+}
+
+/// whether the rule mixes resource access (resources/api groups) with non resource urls, which a single rule shouldn't
+pub fn mixes_resource_kinds(rule: &PolicyRule) -> bool{
+    let has_resources = rule.resources.as_ref().is_some_and(|resources| !resources.is_empty())
+        || rule.api_groups.as_ref().is_some_and(|api_groups| !api_groups.is_empty());
+    let has_non_resource_urls = rule.non_resource_urls.as_ref().is_some_and(|urls| !urls.is_empty());
+    has_resources && has_non_resource_urls
+}
+
+/// the part of the rule which allows writes - the rule with only its write verbs, None if it has none
+pub fn write_rule(rule: &PolicyRule) -> Option<PolicyRule>{
+    let verbs: Vec<String> = rule.verbs.iter().filter(|verb| WRITE_VERBS.contains(&verb.as_str())).cloned().collect();
+    if verbs.is_empty(){
+        return None;
+    }
+    Some(PolicyRule{
+        verbs,
+        ..rule.clone()
+    })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controller::testing::rule;
+
+    fn named_rule(names: &[&str]) -> PolicyRule{
+        PolicyRule{
+            resource_names: Some(names.iter().map(|name| name.to_string()).collect()),
+            ..rule(&[""], &["secrets"], &["get"])
+        }
+    }
+
+    #[test]
+    fn named_object_needs_unrestricted_or_naming_rule(){
+        assert!(resource_name_matches(&named_rule(&[]), Some("token")));
+        assert!(resource_name_matches(&rule(&[""], &["secrets"], &["get"]), Some("token")));
+        assert!(resource_name_matches(&named_rule(&["token", "tls"]), Some("token")));
+        assert!(!resource_name_matches(&named_rule(&["tls"]), Some("token")));
+    }
+
+    #[test]
+    fn every_object_needs_unrestricted_rule(){
+        assert!(resource_name_matches(&rule(&[""], &["secrets"], &["list"]), None));
+        assert!(resource_name_matches(&named_rule(&[]), None));
+        assert!(!resource_name_matches(&named_rule(&["token"]), None));
+    }
+
+    #[test]
+    fn exact_rules_match_only_their_targets(){
+        let pods = rule(&[""], &["pods"], &["get", "list"]);
+        assert!(rule_matches(&pods, "get", "pods", ""));
+        assert!(rule_matches(&pods, "list", "pods", ""));
+        assert!(!rule_matches(&pods, "delete", "pods", ""));
+        assert!(!rule_matches(&pods, "get", "secrets", ""));
+        assert!(!rule_matches(&pods, "get", "pods", "apps"));
+    }
+
+    #[test]
+    fn wildcards_match_anything(){
+        assert!(rule_matches(&rule(&["*"], &["*"], &["*"]), "delete", "secrets", "apps"));
+        let any_verb = rule(&["apps"], &["deployments"], &["*"]);
+        assert!(rule_matches(&any_verb, "patch", "deployments", "apps"));
+        assert!(!rule_matches(&any_verb, "patch", "deployments", ""));
+        assert!(rule_matches(&rule(&[""], &["*"], &["get"]), "get", "configmaps", ""));
+        assert!(rule_matches(&rule(&["*"], &["pods"], &["get"]), "get", "pods", "metrics.k8s.io"));
+    }
+
+    #[test]
+    fn non_resource_rules_never_match(){
+        let urls = PolicyRule{
+            non_resource_urls: Some(vec!["/healthz".to_string()]),
+            verbs: vec!["*".to_string()],
+            ..PolicyRule::default()
+        };
+        assert!(!rule_matches(&urls, "get", "pods", ""));
+    }
+}
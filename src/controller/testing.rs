@@ -0,0 +1,131 @@
+//! Builders of the k8s objects the controllers are fed, for the unit tests
+
+use k8s_openapi::api::rbac::v1::{
+    ClusterRole, ClusterRoleBinding, PolicyRule, Role, RoleBinding, RoleRef, Subject,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::controller::grant_controller::GrantController;
+use crate::controller::permission_controller::PermissionController;
+use crate::controller::rbac_controller::RBACController;
+use crate::controller::rbac_grant::RBAC_API_GROUP;
+
+/// held by tests which set environment variables, so that they don't see each other's values
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+/// locks the environment for a test setting environment variables, see ENV_LOCK. A test failing while holding it
+/// doesn't fail the others
+pub(crate) fn lock_env() -> MutexGuard<'static, ()> {
+    ENV_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// a controller holding the bindings and roles as if they were just listed
+pub(crate) fn rbac_controller(
+    role_bindings: Vec<RoleBinding>,
+    cluster_role_bindings: Vec<ClusterRoleBinding>,
+    roles: Vec<Role>,
+    cluster_roles: Vec<ClusterRole>,
+) -> RBACController {
+    RBACController {
+        grant_controller: GrantController::from_bindings(role_bindings, cluster_role_bindings),
+        permission_controller: PermissionController::from_roles(roles, cluster_roles),
+    }
+}
+
+/// a controller as in namespaced mode, watching only role bindings and roles
+pub(crate) fn namespaced_rbac_controller(
+    role_bindings: Vec<RoleBinding>,
+    roles: Vec<Role>,
+) -> RBACController {
+    RBACController {
+        grant_controller: GrantController::from_bindings(role_bindings, vec![]),
+        permission_controller: PermissionController::namespaced_from_roles(roles),
+    }
+}
+
+/// a User, Group or ServiceAccount subject, without an api group
+pub(crate) fn subject(kind: &str, name: &str, namespace: Option<&str>) -> Subject {
+    Subject {
+        kind: kind.to_string(),
+        name: name.to_string(),
+        namespace: namespace.map(|namespace| namespace.to_string()),
+        api_group: None,
+    }
+}
+
+/// a RoleBinding in namespace binding the subjects to the role_kind (Role or ClusterRole) named role_name
+pub(crate) fn role_binding(
+    namespace: &str,
+    name: &str,
+    role_kind: &str,
+    role_name: &str,
+    subjects: Vec<Subject>,
+) -> RoleBinding {
+    RoleBinding {
+        metadata: metadata(Some(namespace), name),
+        role_ref: role_ref(role_kind, role_name),
+        subjects: Some(subjects),
+    }
+}
+
+/// a ClusterRoleBinding binding the subjects to the role_kind (normally ClusterRole) named role_name
+pub(crate) fn cluster_role_binding(
+    name: &str,
+    role_kind: &str,
+    role_name: &str,
+    subjects: Vec<Subject>,
+) -> ClusterRoleBinding {
+    ClusterRoleBinding {
+        metadata: metadata(None, name),
+        role_ref: role_ref(role_kind, role_name),
+        subjects: Some(subjects),
+    }
+}
+
+pub(crate) fn role(namespace: &str, name: &str, rules: Vec<PolicyRule>) -> Role {
+    Role {
+        metadata: metadata(Some(namespace), name),
+        rules: Some(rules),
+    }
+}
+
+pub(crate) fn cluster_role(name: &str, rules: Vec<PolicyRule>) -> ClusterRole {
+    ClusterRole {
+        metadata: metadata(None, name),
+        rules: Some(rules),
+        ..ClusterRole::default()
+    }
+}
+
+/// a resource rule allowing the verbs on the resources of the api groups
+pub(crate) fn rule(api_groups: &[&str], resources: &[&str], verbs: &[&str]) -> PolicyRule {
+    PolicyRule {
+        api_groups: Some(strings(api_groups)),
+        resources: Some(strings(resources)),
+        verbs: strings(verbs),
+        ..PolicyRule::default()
+    }
+}
+
+fn metadata(namespace: Option<&str>, name: &str) -> ObjectMeta {
+    ObjectMeta {
+        namespace: namespace.map(|namespace| namespace.to_string()),
+        name: Some(name.to_string()),
+        ..ObjectMeta::default()
+    }
+}
+
+fn role_ref(kind: &str, name: &str) -> RoleRef {
+    RoleRef {
+        api_group: RBAC_API_GROUP.to_string(),
+        kind: kind.to_string(),
+        name: name.to_string(),
+    }
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| value.to_string()).collect()
+}
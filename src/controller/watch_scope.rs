@@ -0,0 +1,379 @@
+use crate::config::{env_flag, env_list};
+use actix_web::rt;
+use kube::{api::ListParams, Api, Client, Resource};
+use log::info;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::time::Duration;
+
+/// file mounted into every pod with the namespace of its service account
+const SERVICE_ACCOUNT_NAMESPACE_PATH: &str =
+    "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// names of the resources the controllers watch, as listed in WATCH_RESOURCES
+pub(crate) const ROLE_BINDINGS: &str = "rolebindings";
+pub(crate) const CLUSTER_ROLE_BINDINGS: &str = "clusterrolebindings";
+pub(crate) const ROLES: &str = "roles";
+pub(crate) const CLUSTER_ROLES: &str = "clusterroles";
+/// resources which can be listed in WATCH_RESOURCES
+const WATCHABLE_RESOURCES: &[&str] = &[ROLE_BINDINGS, CLUSTER_ROLE_BINDINGS, ROLES, CLUSTER_ROLES];
+
+/// delay before polling a watcher again after it first errored, doubled on each further error
+const WATCH_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// longest delay between retries of a failing watch
+const WATCH_MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// Determines which namespaces/resources the controllers watch, and how they watch them
+#[derive(Debug, Clone, Default)]
+pub struct WatchScope {
+    /// namespace that roles/role bindings are watched in - None watches every namespace
+    pub(crate) namespace: Option<String>,
+    /// if true, cluster roles/cluster role bindings aren't watched at all
+    pub(crate) cluster_scope_disabled: bool,
+    /// only watch resources matching this label selector
+    pub(crate) label_selector: Option<String>,
+    /// only watch resources matching this field selector
+    pub(crate) field_selector: Option<String>,
+    /// don't ask the api server for bookmark events
+    pub(crate) bookmarks_disabled: bool,
+    /// resources which were left out of WATCH_RESOURCES, and aren't watched
+    pub(crate) unwatched_resources: Vec<&'static str>,
+}
+
+impl WatchScope {
+    /// Reads the scope from the environment:
+    /// - DISABLE_CLUSTER_SCOPE=true only watches the roles/role bindings in the pod's own namespace (so only a
+    ///   Role/RoleBinding is needed for the controller's service account)
+    /// - WATCH_NAMESPACE only watches the roles/role bindings in that namespace, and takes precedence over the pod's
+    ///   own namespace. Unlike DISABLE_CLUSTER_SCOPE, cluster roles/cluster role bindings are still watched
+    /// - RESOURCE_LABEL_SELECTOR/RESOURCE_FIELD_SELECTOR limit every watch to matching resources
+    /// - DISABLE_WATCH_BOOKMARKS=true stops requesting bookmarks, for api servers which misbehave with them
+    /// - WATCH_RESOURCES limits the watched resources to the listed ones (see WATCHABLE_RESOURCES), all by default
+    pub fn from_env() -> Result<WatchScope, Box<dyn Error>> {
+        let cluster_scope_disabled = env_flag("DISABLE_CLUSTER_SCOPE")?;
+        let watch_namespace = env::var("WATCH_NAMESPACE")
+            .ok()
+            .filter(|namespace| !namespace.is_empty());
+        let namespace = if let Some(namespace) = watch_namespace {
+            info!(
+                "Only watching roles and role bindings in namespace {}",
+                namespace
+            );
+            Some(namespace)
+        } else if cluster_scope_disabled {
+            let namespace = own_namespace()?;
+            info!(
+                "Cluster scope disabled, only watching roles and role bindings in namespace {}",
+                namespace
+            );
+            Some(namespace)
+        } else {
+            None
+        };
+        Ok(WatchScope {
+            namespace,
+            cluster_scope_disabled,
+            label_selector: label_selector_from_env("RESOURCE_LABEL_SELECTOR")?,
+            field_selector: env::var("RESOURCE_FIELD_SELECTOR").ok(),
+            bookmarks_disabled: env_flag("DISABLE_WATCH_BOOKMARKS")?,
+            unwatched_resources: unwatched_resources(env_list("WATCH_RESOURCES"))?,
+        })
+    }
+
+    /// whether the resource (one of WATCHABLE_RESOURCES) is watched. Cluster-scoped resources never are when the
+    /// cluster scope is disabled
+    pub(crate) fn watches(&self, resource: &str) -> bool {
+        let cluster_scoped = resource == CLUSTER_ROLE_BINDINGS || resource == CLUSTER_ROLES;
+        if cluster_scoped && self.cluster_scope_disabled {
+            return false;
+        }
+        !self.unwatched_resources.contains(&resource)
+    }
+
+    /// ListParams shared by every watcher. Page size (limit) is intentionally never set, since the watcher's
+    /// initial list doesn't follow continue tokens and would only see the first page
+    pub(crate) fn list_params(&self) -> ListParams {
+        let mut list_params = ListParams::default();
+        if let Some(label_selector) = &self.label_selector {
+            list_params = list_params.labels(label_selector);
+        }
+        if let Some(field_selector) = &self.field_selector {
+            list_params = list_params.fields(field_selector);
+        }
+        if self.bookmarks_disabled {
+            list_params = list_params.disable_bookmarks();
+        }
+        list_params
+    }
+
+    /// Api for a namespaced resource, limited to the watched namespace if there is one
+    pub(crate) fn namespaced_api<K>(&self, client: Client) -> Api<K>
+    where
+        K: Resource,
+        <K as Resource>::DynamicType: Default,
+    {
+        match &self.namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::all(client),
+        }
+    }
+}
+
+/// capped exponential delay between the retries of a failing watch, reset once the watch yields an event again
+#[derive(Debug)]
+pub(crate) struct WatchBackoff {
+    delay: Duration,
+}
+
+impl Default for WatchBackoff {
+    fn default() -> WatchBackoff {
+        WatchBackoff {
+            delay: WATCH_RETRY_DELAY,
+        }
+    }
+}
+
+impl WatchBackoff {
+    /// how long the next wait takes
+    pub(crate) fn delay(&self) -> Duration {
+        self.delay
+    }
+
+    /// sleeps for the current delay, and doubles it (up to WATCH_MAX_RETRY_DELAY) for the next wait
+    pub(crate) async fn wait(&mut self) {
+        rt::time::sleep(self.delay).await;
+        self.delay = (self.delay * 2).min(WATCH_MAX_RETRY_DELAY);
+    }
+
+    /// the watch works again, the next failure is retried after WATCH_RETRY_DELAY
+    pub(crate) fn reset(&mut self) {
+        self.delay = WATCH_RETRY_DELAY;
+    }
+}
+
+/// namespace this pod runs in - taken from POD_NAMESPACE (usually set through the downward api) or from the
+/// mounted service account
+fn own_namespace() -> Result<String, Box<dyn Error>> {
+    if let Ok(namespace) = env::var("POD_NAMESPACE") {
+        return Ok(namespace);
+    }
+    let namespace = fs::read_to_string(SERVICE_ACCOUNT_NAMESPACE_PATH).map_err(|err| {
+        format!(
+            "unable to determine own namespace from POD_NAMESPACE or {}: {}",
+            SERVICE_ACCOUNT_NAMESPACE_PATH, err
+        )
+    })?;
+    Ok(namespace.trim().to_string())
+}
+
+/// the resources left out of the listed ones, none if the list is empty. Errors on resources which can't be watched
+fn unwatched_resources(listed: Vec<String>) -> Result<Vec<&'static str>, Box<dyn Error>> {
+    if listed.is_empty() {
+        return Ok(Vec::new());
+    }
+    let listed: Vec<String> = listed
+        .iter()
+        .map(|resource| resource.to_lowercase())
+        .collect();
+    if let Some(unknown) = listed
+        .iter()
+        .find(|resource| !WATCHABLE_RESOURCES.contains(&resource.as_str()))
+    {
+        return Err(format!(
+            "invalid WATCH_RESOURCES entry {:?}, expected one of {}",
+            unknown,
+            WATCHABLE_RESOURCES.join(", ")
+        )
+        .into());
+    }
+    let unwatched: Vec<&'static str> = WATCHABLE_RESOURCES
+        .iter()
+        .copied()
+        .filter(|resource| !listed.iter().any(|listed| listed == resource))
+        .collect();
+    if !unwatched.is_empty() {
+        info!("Not watching {}", unwatched.join(", "));
+    }
+    Ok(unwatched)
+}
+
+/// Reads a label selector from the environment, None when unset. Errors if it isn't a valid selector, which the api
+/// server would otherwise reject on every attempt to watch
+fn label_selector_from_env(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let selector = match env::var(name) {
+        Ok(selector) => selector,
+        Err(_) => return Ok(None),
+    };
+    if let Err(reason) = validate_label_selector(&selector) {
+        return Err(format!("invalid {} {:?}: {}", name, selector, reason).into());
+    }
+    Ok(Some(selector))
+}
+
+/// checks the syntax of a label selector: comma separated requirements like `key`, `!key`, `key=value`,
+/// `key==value`, `key!=value`, `key in (a,b)` or `key notin (a,b)`
+fn validate_label_selector(selector: &str) -> Result<(), String> {
+    for requirement in split_requirements(selector)? {
+        let requirement = requirement.trim();
+        if requirement.is_empty() {
+            return Err("empty requirement".to_string());
+        }
+        if let Some(key) = requirement.strip_prefix('!') {
+            validate_label_key(key.trim())?;
+        } else if let Some((key, values)) = split_set_requirement(requirement) {
+            validate_label_key(key)?;
+            let values = values
+                .strip_prefix('(')
+                .and_then(|values| values.strip_suffix(')'))
+                .ok_or_else(|| {
+                    format!(
+                        "expected a parenthesized list of values in {:?}",
+                        requirement
+                    )
+                })?;
+            for value in values.split(',') {
+                validate_label_value(value.trim())?;
+            }
+        } else if let Some((key, value)) = ["!=", "==", "="]
+            .iter()
+            .find_map(|operator| requirement.split_once(operator))
+        {
+            validate_label_key(key.trim())?;
+            validate_label_value(value.trim())?;
+        } else {
+            validate_label_key(requirement)?;
+        }
+    }
+    Ok(())
+}
+
+/// splits the selector on the commas which separate requirements, leaving those within value lists
+fn split_requirements(selector: &str) -> Result<Vec<&str>, String> {
+    let mut requirements = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    for (index, character) in selector.char_indices() {
+        match character {
+            '(' => depth += 1,
+            ')' if depth == 0 => return Err("unbalanced parentheses".to_string()),
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                requirements.push(&selector[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+    if depth != 0 {
+        return Err("unbalanced parentheses".to_string());
+    }
+    requirements.push(&selector[start..]);
+    Ok(requirements)
+}
+
+/// splits a `key in (...)`/`key notin (...)` requirement into its key and value list
+fn split_set_requirement(requirement: &str) -> Option<(&str, &str)> {
+    let (key, rest) = requirement.split_once(char::is_whitespace)?;
+    let rest = rest.trim_start();
+    let values = rest
+        .strip_prefix("notin")
+        .or_else(|| rest.strip_prefix("in"))?;
+    Some((key, values.trim()))
+}
+
+/// a label key is a name, optionally prefixed by a dns subdomain and a slash
+fn validate_label_key(key: &str) -> Result<(), String> {
+    let name = match key.split_once('/') {
+        Some((prefix, name)) => {
+            let valid_prefix = !prefix.is_empty()
+                && prefix.len() <= 253
+                && prefix
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.');
+            if !valid_prefix {
+                return Err(format!("invalid label key prefix {:?}", prefix));
+            }
+            name
+        }
+        None => key,
+    };
+    if name.is_empty() || !is_label_name(name) {
+        return Err(format!("invalid label key {:?}", key));
+    }
+    Ok(())
+}
+
+/// label values are empty, or follow the same rules as names
+fn validate_label_value(value: &str) -> Result<(), String> {
+    if value.is_empty() || is_label_name(value) {
+        return Ok(());
+    }
+    Err(format!("invalid label value {:?}", value))
+}
+
+/// at most 63 alphanumeric characters, '-', '_' or '.', starting and ending with an alphanumeric character
+fn is_label_name(name: &str) -> bool {
+    let alphanumeric_ends = name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && name.ends_with(|c: char| c.is_ascii_alphanumeric());
+    name.len() <= 63
+        && alphanumeric_ends
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller::testing::lock_env;
+
+    #[test]
+    fn unset_watch_namespace_watches_every_namespace() {
+        let _env = lock_env();
+        for name in ["WATCH_NAMESPACE", "DISABLE_CLUSTER_SCOPE"] {
+            env::remove_var(name);
+        }
+        let scope = WatchScope::from_env().unwrap();
+        assert_eq!(scope.namespace, None);
+        assert!(scope.watches(CLUSTER_ROLE_BINDINGS));
+
+        // an empty namespace is the same as an unset one
+        env::set_var("WATCH_NAMESPACE", "");
+        assert_eq!(WatchScope::from_env().unwrap().namespace, None);
+
+        env::set_var("WATCH_NAMESPACE", "team-a");
+        let scope = WatchScope::from_env().unwrap();
+        env::remove_var("WATCH_NAMESPACE");
+        assert_eq!(scope.namespace, Some("team-a".to_string()));
+        assert!(scope.watches(CLUSTER_ROLE_BINDINGS));
+    }
+
+    #[test]
+    fn list_params_carry_the_selectors() {
+        let scope = WatchScope {
+            label_selector: Some("app in (web,api),!legacy".to_string()),
+            ..Default::default()
+        };
+        let list_params = scope.list_params();
+        assert_eq!(
+            list_params.label_selector.as_deref(),
+            Some("app in (web,api),!legacy")
+        );
+        assert_eq!(list_params.field_selector, None);
+        assert!(list_params.bookmarks);
+
+        let scope = WatchScope {
+            field_selector: Some("metadata.name=admin".to_string()),
+            bookmarks_disabled: true,
+            ..Default::default()
+        };
+        let list_params = scope.list_params();
+        assert_eq!(list_params.label_selector, None);
+        assert_eq!(
+            list_params.field_selector.as_deref(),
+            Some("metadata.name=admin")
+        );
+        assert!(!list_params.bookmarks);
+    }
+}
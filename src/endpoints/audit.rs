@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use log::warn;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{IDType, RBACGrant, RBAC_API_GROUP};
+use crate::controller::rules::rule_matches;
+use k8s_openapi::api::rbac::v1::PolicyRule;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::output_types::{OutputGrant, OutputId, OutputSubject};
+use crate::endpoints::response::{json_response, permissions_unavailable};
+
+const CORE_API_GROUP: &str = "";
+
+/// (verbs, api group, resources) combinations which let a subject escalate its own privileges:
+/// - escalate/bind on roles/clusterroles, which bypass the check that a subject can only grant what it has
+/// - create/update/patch on roles/clusterroles/rolebindings/clusterrolebindings, which can grant new permissions
+/// - impersonate on users/groups/serviceaccounts, which lets a subject act as another identity
+const ESCALATION_CHECKS: &[(&[&str], &str, &[&str])] = &[
+    (&["escalate", "bind"], RBAC_API_GROUP, &["roles", "clusterroles"]),
+    (&["create", "update", "patch"], RBAC_API_GROUP, &["roles", "clusterroles", "rolebindings", "clusterrolebindings"]),
+    (&["impersonate"], CORE_API_GROUP, &["users", "groups", "serviceaccounts"]),
+];
+
+/// Configuration shared by the audit endpoints
+#[derive(Debug, Clone, Default)]
+pub struct AuditConfig {
+    /// app.kubernetes.io/managed-by values (from MANAGED_BY_IGNORE) whose grants are left out of audits, so that
+    /// findings focus on RBAC which isn't managed by helm/operators
+    pub managed_by_ignore: Vec<String>,
+}
+
+impl AuditConfig {
+    /// the managers whose grants the audit leaves out, none if the query asks to include managed grants
+    fn excluded_managers(&self, include_managed: Option<bool>) -> &[String] {
+        match include_managed {
+            Some(true) => &[],
+            _ => &self.managed_by_ignore,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone)]
+pub struct AuditQuery {
+    /// include the grants managed by the MANAGED_BY_IGNORE managers
+    pub include_managed: Option<bool>,
+}
+
+/// number of subjects returned by the top subjects audit if n isn't given
+const DEFAULT_TOP_SUBJECTS: usize = 10;
+/// most subjects the top subjects audit will return
+const MAX_TOP_SUBJECTS: usize = 1000;
+
+#[derive(Serialize, Clone)]
+pub struct OutputEscalationAudit {
+    pub subjects: Vec<OutputEscalationSubject>,
+    /// grants whose role couldn't be found (which includes every ClusterRole when DISABLE_CLUSTER_SCOPE is set),
+    /// these couldn't be audited
+    pub unresolved_grants: Vec<OutputGrant>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputEscalationSubject {
+    pub subject: OutputSubject,
+    pub findings: Vec<OutputEscalationFinding>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputEscalationFinding {
+    /// the grant which gave the subject the escalating permissions
+    pub grant: OutputGrant,
+    /// the escalating permissions, in the form of "verb group/resource"
+    pub matched: Vec<String>,
+}
+
+/// lists subjects which can escalate their privileges, see ESCALATION_CHECKS for what is considered escalation
+pub async fn get_escalation_audit(req: HttpRequest, controller: web::Data<Arc<RBACController>>, config: web::Data<AuditConfig>, query: web::Query<AuditQuery>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let rbac_controller = controller.get_ref();
+    let grants = rbac_controller.grant_controller.get_grants_excluding_managed_by(config.excluded_managers(query.include_managed));
+    let mut output_subjects: Vec<OutputEscalationSubject> = Vec::new();
+    let mut unresolved_grants: HashSet<RBACGrant> = HashSet::new();
+    for (subject, grants) in grants{
+        let mut findings: Vec<OutputEscalationFinding> = Vec::new();
+        for grant in grants{
+            let rules = match rbac_controller.permission_controller.get_permission_for_id(&grant.permissions_id){
+                Some(rules) => rules,
+                None => {
+                    warn!("no rules found for {:?} while auditing escalation, skipping", grant.permissions_id);
+                    unresolved_grants.insert(grant);
+                    continue;
+                }
+            };
+            let matched = escalating_permissions(&rules);
+            if !matched.is_empty(){
+                findings.push(OutputEscalationFinding{
+                    grant: OutputGrant::from_rbac_grant(grant),
+                    matched,
+                })
+            }
+        }
+        if !findings.is_empty(){
+            output_subjects.push(OutputEscalationSubject{
+                subject: OutputSubject::from_grant_subject(subject),
+                findings,
+            })
+        }
+    }
+    json_response(&req, &OutputEscalationAudit {
+        subjects: output_subjects,
+        unresolved_grants: unresolved_grants.into_iter().map(OutputGrant::from_rbac_grant).collect(),
+    }, "escalation audit")
+}
+
+/// returns each escalating "verb group/resource" permission granted by any of the rules
+fn escalating_permissions(rules: &[PolicyRule]) -> Vec<String>{
+    let mut matched: Vec<String> = Vec::new();
+    for (verbs, api_group, resources) in ESCALATION_CHECKS{
+        for verb in verbs.iter(){
+            for resource in resources.iter(){
+                if rules.iter().any(|rule| rule_matches(rule, verb, resource, api_group)){
+                    matched.push(format!("{} {}/{}", verb, api_group, resource));
+                }
+            }
+        }
+    }
+    matched
+}
+
+#[derive(Deserialize, Clone)]
+pub struct TopSubjectsQuery {
+    pub n: Option<usize>,
+    /// include the grants managed by the MANAGED_BY_IGNORE managers
+    pub include_managed: Option<bool>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputTopSubjects {
+    pub subjects: Vec<OutputSubjectGrantCount>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputSubjectGrantCount {
+    pub subject: OutputSubject,
+    pub grant_count: usize,
+}
+
+/// lists the n (capped at MAX_TOP_SUBJECTS) subjects with the most grants, most grants first
+pub async fn get_top_subjects(req: HttpRequest, controller: web::Data<Arc<RBACController>>, config: web::Data<AuditConfig>, query: web::Query<TopSubjectsQuery>) -> impl Responder {
+    let n = query.n.unwrap_or(DEFAULT_TOP_SUBJECTS).min(MAX_TOP_SUBJECTS);
+    let top_subjects = controller.get_ref().grant_controller.get_top_subjects(n, config.excluded_managers(query.include_managed));
+    let subjects = top_subjects.into_iter().map(|(subject, grant_count)| OutputSubjectGrantCount{
+        subject: OutputSubject::from_grant_subject(subject),
+        grant_count,
+    }).collect();
+    json_response(&req, &OutputTopSubjects {
+        subjects,
+    }, "top subjects")
+}
+
+#[derive(Deserialize, Clone)]
+pub struct UnusedRolesQuery {
+    /// Role or ClusterRole, only roles of this type are returned
+    pub rbac_type: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputUnusedRoles {
+    pub roles: Vec<OutputId>,
+}
+
+/// lists the roles/cluster roles which no binding references, which are candidates for cleanup
+pub async fn get_unused_roles(req: HttpRequest, controller: web::Data<Arc<RBACController>>, query: web::Query<UnusedRolesQuery>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let rbac_type = match query.rbac_type.as_deref(){
+        None => None,
+        Some("Role") => Some(IDType::Role),
+        Some("ClusterRole") => Some(IDType::ClusterRole),
+        Some(other) => return HttpResponse::BadRequest().body(format!("unsupported rbac_type {}, expected Role or ClusterRole", other)),
+    };
+    let rbac_controller = controller.get_ref();
+    // read the roles before the references, so that a role created (and bound) in between isn't reported as unused
+    let ids = rbac_controller.permission_controller.get_ids();
+    let referenced = rbac_controller.grant_controller.get_referenced_permission_ids();
+    let mut roles: Vec<OutputId> = ids.into_iter()
+        .filter(|id| !referenced.contains(id))
+        .filter(|id| rbac_type.as_ref().is_none_or(|rbac_type| &id.rbac_type == rbac_type))
+        .map(OutputId::from_rbac_id)
+        .collect();
+    roles.sort_by(|a, b| (&a.rbac_type, &a.namespace, &a.name).cmp(&(&b.rbac_type, &b.namespace, &b.name)));
+    json_response(&req, &OutputUnusedRoles {
+        roles,
+    }, "unused roles")
+}
@@ -0,0 +1,279 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{non_empty_namespace, ImplicitGroups, RBACGrant};
+use crate::controller::rules::{resource_name_matches, rule_matches};
+use k8s_openapi::api::rbac::v1::PolicyRule;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::endpoints::output_types::OutputGrant;
+use crate::endpoints::permissions::{unresolvable_grants, OutputUnresolvedGrant};
+use crate::endpoints::response::{json_response, permissions_unavailable};
+use crate::endpoints::structs::GrantInput;
+
+/// most actions a single /can-i/batch request can check, so that one request can't hold the controller's locks for long
+const MAX_BATCH_SIZE: usize = 500;
+
+/// an action to check, mirroring kubectl auth can-i
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CanIInput {
+    pub subject: GrantInput,
+    pub verb: String,
+    pub resource: String,
+    /// api group of the resource, defaults to the core group
+    #[serde(default)]
+    pub api_group: String,
+    /// namespace the action happens in, None for cluster-scoped actions (which only ClusterRoleBindings can allow)
+    pub namespace: Option<String>,
+    /// name of the object acted on, None for actions on every object (list, create, ...)
+    pub resource_name: Option<String>,
+}
+
+impl CanIInput{
+    /// lowercases the verb, resource and api group, which k8s always defines in lowercase, so that callers sending
+    /// "Get" or "LIST" still match the (verbatim) rules. An empty namespace is a cluster-scoped action
+    fn normalized(mut self) -> CanIInput{
+        self.verb = self.verb.to_lowercase();
+        self.resource = self.resource.to_lowercase();
+        self.api_group = self.api_group.to_lowercase();
+        self.namespace = non_empty_namespace(self.namespace);
+        self
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputCanI {
+    pub allowed: bool,
+    /// the grant which allows the action, None if it's denied
+    pub grant: Option<OutputGrant>,
+    /// the rule of the grant which allows the action, None if it's denied
+    pub rule: Option<PolicyRule>,
+    /// the grant allowing the action as <RoleBinding|ClusterRoleBinding>/<name>, or with ?explain=true every grant
+    /// allowing it, sorted. Empty if it's denied
+    pub grant_names: Vec<String>,
+    /// grants of the subject applying to the action which can never resolve to rules, so couldn't be checked
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_grants: Vec<OutputUnresolvedGrant>,
+    /// with ?explain=true, every grant/rule which allows the action
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub explanation: Option<Vec<OutputCanIMatch>>,
+}
+
+/// a rule allowing the action, along with the grant it comes from (whose rbac_id is the role holding the rule)
+#[derive(Serialize, Clone)]
+pub struct OutputCanIMatch {
+    pub grant: OutputGrant,
+    pub rule: PolicyRule,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputCanIBatch {
+    /// the result of each action, in the order of the request
+    pub results: Vec<OutputCanI>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct CanIQuery {
+    /// list every grant/rule allowing the action instead of only the first one found
+    pub explain: Option<bool>,
+}
+
+/// checks whether the subject can perform the action, and if so which grant/rule allows it. With ?explain=true, also
+/// lists every grant/rule allowing it, to find redundant grants
+pub async fn can_i(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, query: web::Query<CanIQuery>, input: web::Json<CanIInput>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let explain = query.explain.unwrap_or(false);
+    let result = evaluate(controller.get_ref(), &implicit_groups, input.into_inner(), explain);
+    json_response(&req, &result, "can-i result")
+}
+
+/// checks each of the actions like /can-i, all in one request. The results are in the order of the actions
+pub async fn can_i_batch(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, query: web::Query<CanIQuery>, input: web::Json<Vec<CanIInput>>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let inputs = input.into_inner();
+    if inputs.len() > MAX_BATCH_SIZE{
+        return HttpResponse::BadRequest().body(format!("a batch can hold at most {} actions, got {}", MAX_BATCH_SIZE, inputs.len()));
+    }
+    let explain = query.explain.unwrap_or(false);
+    let rbac_controller = controller.get_ref();
+    json_response(&req, &OutputCanIBatch{
+        results: inputs.into_iter().map(|input| evaluate(rbac_controller, &implicit_groups, input, explain)).collect(),
+    }, "can-i batch results")
+}
+
+fn evaluate(controller: &RBACController, implicit_groups: &ImplicitGroups, input: CanIInput, explain: bool) -> OutputCanI{
+    let input = input.normalized();
+    // without explain the first allowing rule answers the action, the others aren't looked for
+    let (matches, unresolved_grants) = find_allowing_rules(controller, implicit_groups, &input, explain);
+    let (grant, rule) = match matches.first(){
+        Some((grant, rule)) => (Some(OutputGrant::from_rbac_grant(grant.clone())), Some(rule.clone())),
+        None => (None, None),
+    };
+    let grant_names: BTreeSet<String> = matches.iter()
+        .map(|(grant, _)| format!("{}/{}", grant.grant_type, grant.name))
+        .collect();
+    let explanation = if explain{
+        Some(matches.into_iter().map(|(grant, rule)| OutputCanIMatch{
+            grant: OutputGrant::from_rbac_grant(grant),
+            rule,
+        }).collect())
+    } else {
+        None
+    };
+    OutputCanI{
+        allowed: grant.is_some(),
+        grant,
+        rule,
+        grant_names: grant_names.into_iter().collect(),
+        unresolved_grants,
+        explanation,
+    }
+}
+
+/// finds every grant of the subject, and the rules of those grants, which allow the action. Grants apply to actions in
+/// their namespace, ClusterRoleBindings to actions in any namespace and to cluster-scoped actions. Grants to the groups
+/// the subject is implicitly in also apply if it asks for them. Grants whose role can't be found grant nothing, like in k8s. Also returns
+/// the applicable grants which can never resolve to a role. Unless every match is needed, stops at the first one, in
+/// the order of the grants' type, namespace and name so the same grant answers every time
+fn find_allowing_rules(controller: &RBACController, implicit_groups: &ImplicitGroups, input: &CanIInput, every_match: bool) -> (Vec<(RBACGrant, PolicyRule)>, Vec<OutputUnresolvedGrant>){
+    let grants = controller.grant_controller.get_grants_for_subjects(&input.subject.to_grant_subjects(implicit_groups));
+    let mut applicable_grants: Vec<RBACGrant> = grants.into_iter()
+        .filter(|grant| grant.namespace.is_none() || grant.namespace == input.namespace)
+        .collect();
+    applicable_grants.sort_by_cached_key(|grant| (grant.grant_type.to_string(), grant.namespace.clone(), grant.name.clone()));
+    let unresolved_grants = unresolvable_grants(controller, &applicable_grants);
+    let permissions = controller.permission_controller.get_permissions_for_ids(applicable_grants.iter().map(|grant| &grant.permissions_id));
+    let mut matches: Vec<(RBACGrant, PolicyRule)> = Vec::new();
+    for grant in applicable_grants{
+        let rules = match permissions.get(&grant.permissions_id){
+            Some(rules) => rules,
+            None => continue,
+        };
+        let matching_rules = rules.iter().filter(|rule| {
+            rule_matches(rule, &input.verb, &input.resource, &input.api_group) && resource_name_matches(rule, input.resource_name.as_deref())
+        });
+        matches.extend(matching_rules.map(|rule| (grant.clone(), rule.clone())));
+        if !every_match && !matches.is_empty(){
+            matches.truncate(1);
+            break;
+        }
+    }
+    (matches, unresolved_grants)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controller::testing::{cluster_role, cluster_role_binding, namespaced_rbac_controller, rbac_controller, role, role_binding, rule, subject};
+    use crate::endpoints::permissions::UNWATCHED_ROLE_KIND;
+    use crate::endpoints::structs::UserType;
+
+    fn controller() -> RBACController{
+        rbac_controller(
+            vec![role_binding("default", "read-pods", "Role", "pod-reader", vec![subject("User", "alice", None)])],
+            vec![cluster_role_binding("manage-deployments", "ClusterRole", "deployment-admin", vec![subject("User", "bob", None)])],
+            vec![role("default", "pod-reader", vec![rule(&[""], &["pods"], &["get", "list"])])],
+            vec![cluster_role("deployment-admin", vec![rule(&["apps"], &["deployments"], &["*"])])],
+        )
+    }
+
+    fn input(user: &str, verb: &str, resource: &str, api_group: &str, namespace: Option<&str>) -> CanIInput{
+        CanIInput{
+            subject: GrantInput{
+                name: user.to_string(),
+                namespace: None,
+                user_type: UserType::User,
+                merge: None,
+                expand_implicit_groups: None,
+            },
+            verb: verb.to_string(),
+            resource: resource.to_string(),
+            api_group: api_group.to_string(),
+            namespace: namespace.map(|namespace| namespace.to_string()),
+            resource_name: None,
+        }
+    }
+
+    #[test]
+    fn allow_deny_matrix(){
+        let controller = controller();
+        let cases = [
+            (input("alice", "get", "pods", "", Some("default")), true),
+            (input("alice", "list", "pods", "", Some("default")), true),
+            (input("alice", "delete", "pods", "", Some("default")), false),
+            (input("alice", "get", "pods", "", Some("kube-system")), false),
+            (input("alice", "get", "pods", "", None), false),
+            (input("alice", "get", "secrets", "", Some("default")), false),
+            (input("bob", "create", "deployments", "apps", Some("kube-system")), true),
+            (input("bob", "delete", "deployments", "apps", None), true),
+            (input("bob", "create", "deployments", "", Some("default")), false),
+            (input("bob", "get", "pods", "", Some("default")), false),
+            (input("carol", "get", "pods", "", Some("default")), false),
+        ];
+        for (action, allowed) in cases{
+            let description = format!("{} {} {}/{} in {:?}", action.subject.name, action.verb, action.api_group, action.resource, action.namespace);
+            let result = evaluate(&controller, &ImplicitGroups::default(), action, false);
+            assert_eq!(result.allowed, allowed, "{}", description);
+            assert_eq!(result.grant.is_some(), allowed, "{}", description);
+        }
+    }
+
+    #[test]
+    fn mixed_case_actions_are_normalized(){
+        let normalized = input("alice", "GET", "Pods", "Apps", Some("")).normalized();
+        assert_eq!((normalized.verb.as_str(), normalized.resource.as_str(), normalized.api_group.as_str()), ("get", "pods", "apps"));
+        assert_eq!(normalized.namespace, None);
+
+        let controller = controller();
+        let result = evaluate(&controller, &ImplicitGroups::default(), input("alice", "List", "PODS", "", Some("default")), false);
+        assert!(result.allowed);
+        let result = evaluate(&controller, &ImplicitGroups::default(), input("bob", "Create", "Deployments", "APPS", Some("default")), false);
+        assert!(result.allowed);
+    }
+
+    #[test]
+    fn first_grant_answers_unless_explained(){
+        let alice = || vec![subject("User", "alice", None)];
+        let controller = rbac_controller(
+            vec![
+                role_binding("default", "read-pods", "Role", "pod-reader", alice()),
+                role_binding("default", "edit", "ClusterRole", "edit", alice()),
+            ],
+            vec![cluster_role_binding("view-all", "ClusterRole", "view", alice())],
+            vec![role("default", "pod-reader", vec![rule(&[""], &["pods"], &["get", "list"])])],
+            vec![
+                cluster_role("edit", vec![rule(&[""], &["pods"], &["*"])]),
+                cluster_role("view", vec![rule(&[""], &["pods"], &["get"]), rule(&[""], &["*"], &["get"])]),
+            ],
+        );
+        for _ in 0..10{
+            let result = evaluate(&controller, &ImplicitGroups::default(), input("alice", "get", "pods", "", Some("default")), false);
+            assert!(result.allowed);
+            assert_eq!(result.grant_names, vec!["ClusterRoleBinding/view-all"]);
+            assert!(result.explanation.is_none());
+        }
+
+        let result = evaluate(&controller, &ImplicitGroups::default(), input("alice", "get", "pods", "", Some("default")), true);
+        assert_eq!(result.grant_names, vec!["ClusterRoleBinding/view-all", "RoleBinding/edit", "RoleBinding/read-pods"]);
+        assert_eq!(result.explanation.unwrap().len(), 4);
+    }
+
+    #[test]
+    fn unwatched_cluster_roles_are_listed_as_unresolved(){
+        let controller = namespaced_rbac_controller(
+            vec![role_binding("default", "view", "ClusterRole", "view", vec![subject("User", "alice", None)])],
+            vec![],
+        );
+        let result = evaluate(&controller, &ImplicitGroups::default(), input("alice", "get", "pods", "", Some("default")), false);
+        assert!(!result.allowed);
+        assert_eq!(result.unresolved_grants.len(), 1);
+        assert_eq!(result.unresolved_grants[0].reason, UNWATCHED_ROLE_KIND);
+    }
+}
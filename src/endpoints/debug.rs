@@ -0,0 +1,189 @@
+use std::collections::HashSet;
+use std::fmt::Debug;
+use std::sync::Arc;
+use log::error;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{non_empty_namespace, RBACGrant};
+use k8s_openapi::api::rbac::v1::{ClusterRoleBinding, PolicyRule, RoleBinding};
+use kube::{Api, Client};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::output_types::{OutputGrant, OutputId, OutputSubject};
+use crate::endpoints::response::json_response;
+
+/// reason given for grants whose role couldn't be found
+const ROLE_NOT_FOUND: &str = "RoleNotFound";
+/// reason given for subjects skipped in strict mode, which are always ServiceAccounts without a namespace
+const SERVICE_ACCOUNT_WITHOUT_NAMESPACE: &str = "ServiceAccountWithoutNamespace";
+
+#[derive(Deserialize, Clone)]
+pub struct RawGrantQuery {
+    /// RoleBinding or ClusterRoleBinding
+    pub grant_type: String,
+    /// namespace of the grant, required for RoleBindings
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+/// fetches the live binding behind a grant from the api server and returns it as-is, for comparing the processed
+/// grant against its source. Only registered when DEBUG_ENDPOINTS=true
+pub async fn get_raw_grant(req: HttpRequest, client: web::Data<Client>, query: web::Query<RawGrantQuery>) -> impl Responder {
+    match raw_grant_api(client.get_ref().clone(), &query.grant_type, query.namespace.clone()){
+        Ok(RawGrantApi::RoleBinding(api)) => fetch_raw(&req, api, &query.name).await,
+        Ok(RawGrantApi::ClusterRoleBinding(api)) => fetch_raw(&req, api, &query.name).await,
+        Err(message) => HttpResponse::BadRequest().body(message),
+    }
+}
+
+/// the api a raw grant of grant_type is fetched from
+enum RawGrantApi {
+    RoleBinding(Api<RoleBinding>),
+    ClusterRoleBinding(Api<ClusterRoleBinding>),
+}
+
+/// the api to fetch a raw grant of grant_type in namespace from, or why the query can't be answered
+fn raw_grant_api(client: Client, grant_type: &str, namespace: Option<String>) -> Result<RawGrantApi, String>{
+    match grant_type{
+        "RoleBinding" => match non_empty_namespace(namespace){
+            // always the requested namespace - Api::default_namespaced would silently use the client's default
+            // namespace (the kubeconfig context's, or the service account's in cluster) instead
+            Some(namespace) => Ok(RawGrantApi::RoleBinding(Api::namespaced(client, &namespace))),
+            None => Err("namespace is required for a RoleBinding".to_string()),
+        },
+        "ClusterRoleBinding" => Ok(RawGrantApi::ClusterRoleBinding(Api::all(client))),
+        other => Err(format!("unknown grant_type {}, expected RoleBinding or ClusterRoleBinding", other)),
+    }
+}
+
+async fn fetch_raw<K>(req: &HttpRequest, api: Api<K>, name: &str) -> HttpResponse
+where
+    K: Clone + DeserializeOwned + Serialize + Debug,
+{
+    let object = match api.get_opt(name).await{
+        Ok(Some(object)) => object,
+        Ok(None) => return HttpResponse::NotFound().body(format!("{} not found", name)),
+        Err(err) => {
+            error!("error when attempting to fetch raw grant {}: {:?}", name, err);
+            return HttpResponse::BadGateway().body("unable to fetch the grant from the api server, check logs for details");
+        }
+    };
+    json_response(req, &object, "raw grant")
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputUnresolvedGrants {
+    pub grants: Vec<OutputUnresolvedGrant>,
+    /// subjects which were left out of their grants (only with STRICT_SUBJECTS=true)
+    pub subjects: Vec<OutputUnresolvedSubject>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputUnresolvedSubject {
+    pub grant: OutputGrant,
+    pub subject: OutputSubject,
+    /// why the subject was left out, e.g. ServiceAccountWithoutNamespace
+    pub reason: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputUnresolvedGrant {
+    pub grant: OutputGrant,
+    /// why the grant doesn't resolve to any rules, e.g. RoleNotFound or EmptyRoleRefName
+    pub reason: String,
+}
+
+/// lists the grants which don't give any permissions because their role can't be resolved - either the role isn't
+/// known, or the binding is malformed and was skipped - and the subjects left out of grants in strict mode. Only
+/// registered when DEBUG_ENDPOINTS=true
+pub async fn get_unresolved_grants(req: HttpRequest, controller: web::Data<Arc<RBACController>>) -> impl Responder {
+    let rbac_controller = controller.get_ref();
+    let grants: HashSet<RBACGrant> = rbac_controller.grant_controller.get_grants().into_values().flatten().collect();
+    let permissions = rbac_controller.permission_controller.get_permissions_for_ids(grants.iter().map(|grant| &grant.permissions_id));
+    let mut output_grants: Vec<OutputUnresolvedGrant> = grants.into_iter()
+        .filter(|grant| !permissions.contains_key(&grant.permissions_id))
+        .map(|grant| OutputUnresolvedGrant{
+            grant: OutputGrant::from_rbac_grant(grant),
+            reason: ROLE_NOT_FOUND.to_string(),
+        })
+        .collect();
+    output_grants.extend(rbac_controller.grant_controller.get_malformed_grants().into_iter().map(|(grant, reason)| OutputUnresolvedGrant{
+        grant: OutputGrant::from_rbac_grant(grant),
+        reason: reason.to_string(),
+    }));
+    let subjects = rbac_controller.grant_controller.get_skipped_subjects().into_iter().map(|(grant, subject)| OutputUnresolvedSubject{
+        grant: OutputGrant::from_rbac_grant(grant),
+        subject: OutputSubject::from_grant_subject(subject),
+        reason: SERVICE_ACCOUNT_WITHOUT_NAMESPACE.to_string(),
+    }).collect();
+    json_response(&req, &OutputUnresolvedGrants {
+        grants: output_grants,
+        subjects,
+    }, "unresolved grants")
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputMalformedRules {
+    pub roles: Vec<OutputMalformedRole>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputMalformedRole {
+    pub role: OutputId,
+    /// the rules of the role which mix resources/api groups with non resource urls
+    pub rules: Vec<PolicyRule>,
+}
+
+/// lists the roles with rules which mix resource access with non resource urls. Such rules are still evaluated as
+/// they are, this only surfaces them. Only registered when DEBUG_ENDPOINTS=true
+pub async fn get_malformed_rules(req: HttpRequest, controller: web::Data<Arc<RBACController>>) -> impl Responder {
+    let mut roles = controller.get_ref().permission_controller.get_mixed_rules();
+    roles.sort_by(|(a, _), (b, _)| (a.rbac_type.to_string(), &a.namespace, &a.name).cmp(&(b.rbac_type.to_string(), &b.namespace, &b.name)));
+    json_response(&req, &OutputMalformedRules {
+        roles: roles.into_iter().map(|(id, rules)| OutputMalformedRole{
+            role: OutputId::from_rbac_id(id),
+            rules,
+        }).collect(),
+    }, "malformed rules")
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use std::sync::Mutex;
+    use hyper::{Body, Request, Response};
+    use kube::Config;
+    use tower::service_fn;
+
+    /// a client whose default namespace is a, answering every request with an empty RoleBinding and recording the
+    /// paths it was sent to
+    fn recording_client(paths: Arc<Mutex<Vec<String>>>) -> Client{
+        let mut config = Config::new("https://kubernetes.default.svc".parse().unwrap());
+        config.default_namespace = "a".to_string();
+        let service = service_fn(move |request: Request<Body>| {
+            paths.lock().unwrap().push(request.uri().path().to_string());
+            let body = serde_json::to_vec(&RoleBinding::default()).unwrap();
+            async move { Ok::<_, std::convert::Infallible>(Response::new(Body::from(body))) }
+        });
+        Client::new(service, config.default_namespace)
+    }
+
+    #[actix_web::test]
+    async fn role_binding_is_fetched_from_the_requested_namespace(){
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        let api = match raw_grant_api(recording_client(paths.clone()), "RoleBinding", Some("b".to_string())){
+            Ok(RawGrantApi::RoleBinding(api)) => api,
+            _ => panic!("expected a RoleBinding api"),
+        };
+        api.get_opt("readers").await.unwrap();
+        assert_eq!(*paths.lock().unwrap(), vec!["/apis/rbac.authorization.k8s.io/v1/namespaces/b/rolebindings/readers".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn role_binding_needs_a_namespace(){
+        let paths = Arc::new(Mutex::new(Vec::new()));
+        assert!(raw_grant_api(recording_client(paths.clone()), "RoleBinding", Some("".to_string())).is_err());
+        assert!(raw_grant_api(recording_client(paths), "RoleBinding", None).is_err());
+    }
+}
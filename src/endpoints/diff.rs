@@ -0,0 +1,83 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+use log::error;
+use actix_web::{web, HttpRequest, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::ImplicitGroups;
+use crate::controller::rules::merge_rules;
+use k8s_openapi::api::rbac::v1::PolicyRule;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::endpoints::permissions::{create_permission_output, MissingRulesError};
+use crate::endpoints::response::{json_response, permissions_unavailable};
+use crate::endpoints::structs::GrantInput;
+
+/// the two subjects to compare
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct DiffInput {
+    pub a: GrantInput,
+    pub b: GrantInput,
+}
+
+/// the rules of the two subjects, keyed by the namespace they apply in. Namespaces without rules are left out
+#[derive(Serialize, Clone)]
+pub struct OutputDiff {
+    /// rules a has and b doesn't
+    pub only_a: HashMap<String, Vec<PolicyRule>>,
+    /// rules b has and a doesn't
+    pub only_b: HashMap<String, Vec<PolicyRule>>,
+    /// rules both have
+    pub common: HashMap<String, Vec<PolicyRule>>,
+}
+
+/// compares the permissions of two subjects (like those of POST /permissions, including their implicit groups if asked for), e.g.
+/// to check that a group gives everything an old binding did. Rules are merged before comparing, so rules only
+/// compare equal if they're for the same resources/urls with the same verbs - a rule allowing get and list on pods
+/// isn't split to match a rule allowing only get
+pub async fn diff_permissions(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, input: web::Json<DiffInput>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let rbac_controller = controller.get_ref();
+    let (a, b) = match (subject_rules(rbac_controller, &implicit_groups, &input.a), subject_rules(rbac_controller, &implicit_groups, &input.b)){
+        (Ok(a), Ok(b)) => (a, b),
+        (Err(err), _) | (_, Err(err)) => {
+            error!("unable to resolve permissions to diff: {}", err);
+            return err.to_response()
+        }
+    };
+    let namespaces: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+    let mut output = OutputDiff{
+        only_a: HashMap::new(),
+        only_b: HashMap::new(),
+        common: HashMap::new(),
+    };
+    let no_rules = Vec::new();
+    for namespace in namespaces{
+        let rules_a = a.get(namespace).unwrap_or(&no_rules);
+        let rules_b = b.get(namespace).unwrap_or(&no_rules);
+        let only_a: Vec<PolicyRule> = rules_a.iter().filter(|rule| !rules_b.contains(rule)).cloned().collect();
+        let only_b: Vec<PolicyRule> = rules_b.iter().filter(|rule| !rules_a.contains(rule)).cloned().collect();
+        let common: Vec<PolicyRule> = rules_a.iter().filter(|rule| rules_b.contains(rule)).cloned().collect();
+        for (side, rules) in [(&mut output.only_a, only_a), (&mut output.only_b, only_b), (&mut output.common, common)]{
+            if !rules.is_empty(){
+                side.insert(namespace.clone(), rules);
+            }
+        }
+    }
+    json_response(&req, &output, "permission diff")
+}
+
+/// the merged rules of the subject in each namespace, with their verbs sorted so equal rules compare equal
+fn subject_rules(controller: &RBACController, implicit_groups: &ImplicitGroups, subject: &GrantInput) -> Result<HashMap<String, Vec<PolicyRule>>, MissingRulesError>{
+    let grants = controller.grant_controller.get_grants_for_subjects(&subject.to_grant_subjects(implicit_groups));
+    let permissions = create_permission_output(controller, grants)?;
+    Ok(permissions.into_iter().map(|(namespace, rules)| {
+        let rules = merge_rules(rules).into_iter().map(|mut rule| {
+            rule.verbs.sort();
+            rule
+        }).collect();
+        (namespace, rules)
+    }).collect())
+}
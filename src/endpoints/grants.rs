@@ -1,47 +1,439 @@
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
 use std::sync::Arc;
-use log::error;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::web::Bytes;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use futures::stream;
+use log::{error, warn};
+use tokio::sync::broadcast::error::RecvError;
+use chrono::{DateTime, SecondsFormat, Utc};
 use crate::RBACController;
-use serde::Serialize;
+use crate::controller::rbac_grant::{non_empty_namespace, GrantSubject, ImplicitGroups, RBACGrant};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
 use crate::endpoints::output_types::{OutputGrant, OutputSubject};
+use crate::endpoints::response::{accepts_ndjson, json_response, ndjson_response};
+use crate::endpoints::structs::{GrantInput, UserType};
 
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct OutputAll {
     pub subject_grants: Vec<OutputSubjectGrant>
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct OutputSubjectGrant {
     pub subject: OutputSubject,
     pub grants: Vec<OutputGrant>,
 }
 
-/// simple health check, reports the number of resources in use
-pub async fn get_all_grants(controller: web::Data<Arc<RBACController>>) -> impl Responder {
+/// a single grant of a subject, the record of ndjson outputs
+#[derive(Serialize, Clone)]
+pub struct OutputGrantLine {
+    pub subject: OutputSubject,
+    pub grant: OutputGrant,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct GrantsQuery {
+    /// rfc3339 timestamp, only grants changed since then are returned
+    pub modified_since: Option<String>,
+    /// "table" returns the grants as a kubernetes-style Table, see OutputTable
+    pub output: Option<String>,
+    /// only list the subjects, without their grants
+    pub subjects_only: Option<bool>,
+}
+
+/// column set of the table output, as (name, description)
+const TABLE_COLUMNS: &[(&str, &str)] = &[
+    ("Subject", "name of the subject"),
+    ("Kind", "kind of the subject - User, Group or ServiceAccount"),
+    ("Namespace", "namespace the grant applies in, * for cluster-wide grants"),
+    ("Grant", "the binding giving the grant, as <type>/<name>"),
+    ("Role", "the role the binding references, as <type>/<name>"),
+];
+
+/// a simplified kubernetes Table (meta.k8s.io/v1), as served for kubectl get, with one row per grant of each subject
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputTable {
+    pub kind: String,
+    pub api_version: String,
+    pub column_definitions: Vec<OutputTableColumn>,
+    pub rows: Vec<OutputTableRow>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputTableColumn {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub column_type: String,
+    pub description: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputTableRow {
+    /// one cell per column, in the order of the column definitions
+    pub cells: Vec<String>,
+}
+
+/// subjects bound to roles of the same name by both a RoleBinding and a ClusterRoleBinding
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputGrantConflicts {
+    pub conflicts: Vec<OutputGrantConflict>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputGrantConflict {
+    pub subject: OutputSubject,
+    /// name of the role(s) both types of bindings reference
+    pub role_name: String,
+    /// the grants of either type referencing a role of that name, sorted by type, namespace and name
+    pub grants: Vec<OutputGrant>,
+}
+
+/// every subject with grants, sorted by kind, namespace and name
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputSubjects {
+    pub subjects: Vec<OutputSubject>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputGrantChanges {
+    /// false if the changes since modified_since aren't all known. In that case `changed` holds every grant, and
+    /// should replace (rather than update) what was previously fetched
+    pub complete: bool,
+    pub changed: Vec<OutputChangedGrant>,
+    pub removed: Vec<OutputGrant>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputChangedGrant {
+    pub grant: OutputGrant,
+    pub subjects: Vec<OutputSubject>,
+    /// rfc3339 time the grant was last added/changed
+    pub modified: String,
+}
+
+/// every grant by subject, or with ?modified_since the grants changed since then, for polling
+pub async fn get_all_grants(req: HttpRequest, controller: web::Data<Arc<RBACController>>, query: web::Query<GrantsQuery>) -> impl Responder {
     let rbac_controller = controller.get_ref();
-    let grants = rbac_controller.grant_controller.get_grants();
-    let mut output_subject_grants: Vec<OutputSubjectGrant> = Vec::new(); 
+    if query.modified_since.is_some() && query.output.is_some(){
+        return HttpResponse::BadRequest().body("output can't be combined with modified_since");
+    }
+    let subjects_only = query.subjects_only.unwrap_or(false);
+    if subjects_only && (query.modified_since.is_some() || query.output.is_some()){
+        return HttpResponse::BadRequest().body("subjects_only can't be combined with modified_since or output");
+    }
+    if subjects_only{
+        return json_response(&req, &OutputSubjects {
+            subjects: sorted_subjects(rbac_controller.grant_controller.get_subjects()),
+        }, "subjects");
+    }
+    if let Some(modified_since) = &query.modified_since{
+        let since = match DateTime::parse_from_rfc3339(modified_since){
+            Ok(since) => since.with_timezone(&Utc),
+            Err(err) => return HttpResponse::BadRequest().body(format!("invalid modified_since {:?}, expected an rfc3339 timestamp: {}", modified_since, err)),
+        };
+        return get_grant_changes(&req, rbac_controller, since);
+    }
+    match query.output.as_deref(){
+        None | Some("table") => (),
+        Some(other) => return HttpResponse::BadRequest().body(format!("unsupported output {}, expected table", other)),
+    }
+    // get_grants returns a copy, so the lock is released before the (potentially large) output is built and serialized
+    let subject_grants = sorted_subject_grants(rbac_controller.grant_controller.get_grants());
+    if query.output.is_some(){
+        return json_response(&req, &grant_table(grant_lines(subject_grants)), "grant table");
+    }
+    if accepts_ndjson(&req){
+        return ndjson_response(&grant_lines(subject_grants), "grants");
+    }
+    json_response(&req, &OutputAll {
+        subject_grants,
+    }, "grants")
+}
+
+fn get_grant_changes(req: &HttpRequest, controller: &RBACController, since: DateTime<Utc>) -> HttpResponse {
+    let changes = controller.grant_controller.get_changes_since(since);
+    let changed = changes.changed.into_iter().map(|(grant, subjects, modified)| OutputChangedGrant{
+        grant: OutputGrant::from_rbac_grant(grant),
+        subjects: subjects.into_iter().map(OutputSubject::from_grant_subject).collect(),
+        modified: modified.to_rfc3339_opts(SecondsFormat::Millis, true),
+    }).collect();
+    json_response(req, &OutputGrantChanges {
+        complete: changes.complete,
+        changed,
+        removed: changes.removed.into_iter().map(OutputGrant::from_rbac_grant).collect(),
+    }, "grant changes")
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputSelectedGrants {
+    /// the requested subjects which have grants, with their grants
+    pub subject_grants: Vec<OutputSubjectGrant>,
+    /// the requested subjects which don't have any grants
+    pub missing_subjects: Vec<OutputSubject>,
+}
+
+/// the grants of each of the requested subjects, read in one go
+pub async fn get_grants_for_subjects(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, input: web::Json<Vec<GrantInput>>) -> impl Responder {
+    let subjects: Vec<_> = input.iter().map(|input| {
+        let subject = input.to_grant_subject();
+        let groups = input.implicit_groups(&subject, &implicit_groups, false);
+        (subject, groups)
+    }).collect();
+    let grants = controller.get_ref().grant_controller.get_grants_for_each_subject(&subjects);
+    let mut subject_grants: Vec<OutputSubjectGrant> = Vec::new();
+    let mut missing_subjects: Vec<OutputSubject> = Vec::new();
     for (subject, grants) in grants{
         let output_subject = OutputSubject::from_grant_subject(subject);
-        let mut output_grants: Vec<OutputGrant> = Vec::new();
-        for grant in grants{
-            let output_grant = OutputGrant::from_rbac_grant(grant);
-            output_grants.push(output_grant);
+        match grants{
+            Some(grants) if !grants.is_empty() => subject_grants.push(OutputSubjectGrant{
+                subject: output_subject,
+                grants: sorted_grants(grants),
+            }),
+            _ => missing_subjects.push(output_subject),
         }
-        output_subject_grants.push(OutputSubjectGrant{
-            subject: output_subject,
-            grants: output_grants,
+    }
+    if accepts_ndjson(&req){
+        // subjects without grants have no lines
+        return ndjson_response(&grant_lines(subject_grants), "grants of the requested subjects");
+    }
+    json_response(&req, &OutputSelectedGrants {
+        subject_grants,
+        missing_subjects,
+    }, "grants of the requested subjects")
+}
+
+/// identifies a subject in the path. The namespace is only given for ServiceAccounts, `_` stands for no namespace
+#[derive(Deserialize, Clone, Debug)]
+pub struct SubjectPath {
+    /// User, Group or ServiceAccount
+    pub kind: String,
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+/// the grants of a single subject identified by the path, a GET alternative to POST /grants/subjects. 404s if the
+/// subject has no grants
+pub async fn get_subject_grants(req: HttpRequest, controller: web::Data<Arc<RBACController>>, path: web::Path<SubjectPath>) -> impl Responder {
+    let user_type = match path.kind.as_str(){
+        "User" => UserType::User,
+        "Group" => UserType::Group,
+        "ServiceAccount" => UserType::ServiceAccount,
+        other => return HttpResponse::BadRequest().body(format!("unsupported kind {}, expected User, Group or ServiceAccount", other)),
+    };
+    let namespace = non_empty_namespace(path.namespace.clone().filter(|namespace| namespace != "_"));
+    if user_type == UserType::ServiceAccount && namespace.is_none(){
+        return HttpResponse::BadRequest().body("namespace is required for a ServiceAccount");
+    }
+    let input = GrantInput{
+        name: path.name.clone(),
+        namespace,
+        user_type,
+        merge: None,
+        expand_implicit_groups: None,
+    };
+    let subject = input.to_grant_subject();
+    let grants = controller.get_ref().grant_controller.get_grants_for_subjects(std::slice::from_ref(&subject));
+    if grants.is_empty(){
+        return HttpResponse::NotFound().body(format!("no grants found for {} {}", subject.kind, subject.name));
+    }
+    let subject_grant = OutputSubjectGrant {
+        subject: OutputSubject::from_grant_subject(subject),
+        grants: sorted_grants(grants),
+    };
+    if accepts_ndjson(&req){
+        return ndjson_response(&grant_lines(vec![subject_grant]), "grants of the subject");
+    }
+    json_response(&req, &subject_grant, "grants of the subject")
+}
+
+/// the grants as output grants, sorted by type, namespace and name
+fn sorted_grants(grants: impl IntoIterator<Item = RBACGrant>) -> Vec<OutputGrant>{
+    let mut grants: Vec<RBACGrant> = grants.into_iter().collect();
+    grants.sort_by(|a, b| (a.grant_type.to_string(), &a.namespace, &a.name).cmp(&(b.grant_type.to_string(), &b.namespace, &b.name)));
+    grants.into_iter().map(OutputGrant::from_rbac_grant).collect()
+}
+
+/// lists the subjects bound to roles of the same name through both a RoleBinding and a ClusterRoleBinding, which is
+/// often a misconfiguration (e.g. a binding left behind after moving to the other kind). Sorted by subject then role
+pub async fn get_grant_conflicts(req: HttpRequest, controller: web::Data<Arc<RBACController>>) -> impl Responder {
+    let mut conflicts: Vec<OutputGrantConflict> = controller.grant_controller.get_conflicting_grants().into_iter()
+        .map(|(subject, role_name, grants)| OutputGrantConflict{
+            subject: OutputSubject::from_grant_subject(subject),
+            role_name,
+            grants: sorted_grants(grants),
         })
+        .collect();
+    conflicts.sort_by(|a, b| (subject_order(&a.subject), &a.role_name).cmp(&(subject_order(&b.subject), &b.role_name)));
+    json_response(&req, &OutputGrantConflicts {
+        conflicts,
+    }, "grant conflicts")
+}
+
+/// streams an `event: grants` server-sent event with the subject and its (sorted) grants whenever a subject gains or
+/// loses a grant, until the client disconnects. A subject left without grants gets an empty list. Clients reading
+/// too slowly to keep up are disconnected, the watchers never wait for them
+pub async fn watch_grants(controller: web::Data<Arc<RBACController>>) -> impl Responder {
+    let controller = controller.get_ref().clone();
+    let changes = controller.grant_controller.subscribe();
+    let events = stream::unfold((changes, controller), |(mut changes, controller)| async move {
+        loop{
+            let subject = match changes.recv().await{
+                Ok(subject) => subject,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("disconnecting a /grants/watch client which fell {} changes behind", missed);
+                    return None;
+                },
+                Err(RecvError::Closed) => return None,
+            };
+            let grants = controller.grant_controller.get_grants_for_subjects(std::slice::from_ref(&subject));
+            let output = OutputSubjectGrant{
+                subject: OutputSubject::from_grant_subject(subject),
+                grants: sorted_grants(grants),
+            };
+            match serde_json::to_string(&output){
+                Ok(data) => {
+                    let event = Bytes::from(format!("event: grants\ndata: {}\n\n", data));
+                    return Some((Ok::<_, Infallible>(event), (changes, controller)));
+                },
+                Err(err) => error!("error when attempting to serialize a grant change {:?}", err),
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+/// the subjects as output subjects, sorted by kind, namespace and name (and api group, to keep the order stable)
+pub(crate) fn sorted_subjects(subjects: Vec<GrantSubject>) -> Vec<OutputSubject>{
+    let mut subjects: Vec<OutputSubject> = subjects.into_iter().map(OutputSubject::from_grant_subject).collect();
+    subjects.sort_by(|a, b| subject_order(a).cmp(&subject_order(b)));
+    subjects.dedup_by(|a, b| subject_order(a) == subject_order(b));
+    subjects
+}
+
+/// every subject with its grants, the subjects sorted like sorted_subjects and their grants like sorted_grants, so
+/// that listing the same state twice gives the same output
+fn sorted_subject_grants(grants: HashMap<GrantSubject, HashSet<RBACGrant>>) -> Vec<OutputSubjectGrant>{
+    let mut subject_grants: Vec<OutputSubjectGrant> = grants.into_iter().map(|(subject, grants)| OutputSubjectGrant{
+        subject: OutputSubject::from_grant_subject(subject),
+        grants: sorted_grants(grants),
+    }).collect();
+    subject_grants.sort_by(|a, b| subject_order(&a.subject).cmp(&subject_order(&b.subject)));
+    subject_grants
+}
+
+/// the key subjects are sorted by: kind, namespace, name and api group
+fn subject_order(subject: &OutputSubject) -> (&String, &String, &String, &String){
+    (&subject.kind, &subject.namespace, &subject.name, &subject.api_group)
+}
+
+/// one line per grant of each subject, in the order of the subjects and their grants
+fn grant_lines(subject_grants: Vec<OutputSubjectGrant>) -> Vec<OutputGrantLine>{
+    let mut lines: Vec<OutputGrantLine> = Vec::new();
+    for subject_grant in subject_grants{
+        for grant in subject_grant.grants{
+            lines.push(OutputGrantLine{
+                subject: subject_grant.subject.clone(),
+                grant,
+            });
+        }
     }
-    match serde_json::to_string(&OutputAll {
-        subject_grants: output_subject_grants,
-    }){
-        Ok(output) => HttpResponse::Ok().body(output),
-        Err(err) => {
-            error!("error when attempting to serialize health check {:?}", err);
-            HttpResponse::InternalServerError().body("internal server error, check logs for details")
+    lines
+}
+
+/// the grant lines as a table, sorted by subject then grant
+fn grant_table(lines: Vec<OutputGrantLine>) -> OutputTable{
+    let mut rows: Vec<Vec<String>> = lines.into_iter().map(|line| vec![
+        line.subject.name,
+        line.subject.kind,
+        line.grant.namespace,
+        format!("{}/{}", line.grant.grant_type, line.grant.name),
+        format!("{}/{}", line.grant.rbac_id.rbac_type, line.grant.rbac_id.name),
+    ]).collect();
+    rows.sort();
+    OutputTable{
+        kind: "Table".to_string(),
+        api_version: "meta.k8s.io/v1".to_string(),
+        column_definitions: TABLE_COLUMNS.iter().map(|(name, description)| OutputTableColumn{
+            name: name.to_string(),
+            column_type: "string".to_string(),
+            description: description.to_string(),
+        }).collect(),
+        rows: rows.into_iter().map(|cells| OutputTableRow{ cells }).collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use actix_web::App;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use crate::controller::testing::{cluster_role_binding, rbac_controller, role_binding, subject};
+
+    /// bindings naming enough subjects that hash map order would show in an unsorted listing
+    fn controller(reversed: bool) -> RBACController{
+        let mut role_bindings = Vec::new();
+        for namespace in ["default", "prod", "dev"]{
+            for name in ["view", "edit", "admin"]{
+                let subjects = vec![
+                    subject("User", &format!("{}-{}", namespace, name), None),
+                    subject("Group", "devs", None),
+                    subject("ServiceAccount", "deployer", Some(namespace)),
+                ];
+                role_bindings.push(role_binding(namespace, name, "Role", name, subjects));
+            }
+        }
+        let mut cluster_role_bindings = vec![
+            cluster_role_binding("view-all", "ClusterRole", "view", vec![subject("Group", "devs", None), subject("User", "alice", None)]),
+            cluster_role_binding("admin-all", "ClusterRole", "admin", vec![subject("User", "alice", None)]),
+        ];
+        if reversed{
+            role_bindings.reverse();
+            cluster_role_bindings.reverse();
+        }
+        rbac_controller(role_bindings, cluster_role_bindings, vec![], vec![])
+    }
+
+    async fn list(controller: RBACController, query: &str) -> Bytes{
+        let app = init_service(App::new()
+            .app_data(web::Data::new(Arc::new(controller)))
+            .route("/grants", web::get().to(get_all_grants))).await;
+        let response = call_service(&app, TestRequest::get().uri(&format!("/grants{}", query)).to_request()).await;
+        assert_eq!(response.status(), 200);
+        read_body(response).await
+    }
+
+    #[actix_web::test]
+    async fn listings_are_byte_identical(){
+        for query in ["", "?output=table", "?subjects_only=true"]{
+            let first = list(controller(false), query).await;
+            for reversed in [false, true]{
+                assert_eq!(list(controller(reversed), query).await, first, "{}", query);
+            }
         }
     }
+
+    #[actix_web::test]
+    async fn subject_grants_are_listed_as_ndjson(){
+        let app = init_service(App::new()
+            .app_data(web::Data::new(Arc::new(controller(false))))
+            .route("/grants/{kind}/{name}", web::get().to(get_subject_grants))).await;
+        let request = TestRequest::get().uri("/grants/User/alice").insert_header(("Accept", "application/x-ndjson")).to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+        let body = read_body(response).await;
+        let lines: Vec<serde_json::Value> = std::str::from_utf8(&body).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let grants: Vec<&str> = lines.iter().map(|line| line["grant"]["name"].as_str().unwrap()).collect();
+        assert_eq!(grants, vec!["admin-all", "view-all"]);
+        assert!(lines.iter().all(|line| line["subject"]["name"] == "alice"));
+    }
 }
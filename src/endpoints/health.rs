@@ -1,29 +1,60 @@
 use std::sync::Arc;
-use log::error;
-use actix_web::{web, HttpResponse, Responder};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use crate::RBACController;
+use crate::controller::api_server_check::ApiServerCheck;
 use serde::Serialize;
+use utoipa::ToSchema;
 
-#[derive(Serialize, Clone)]
+use crate::endpoints::response::json_response;
+
+#[derive(Serialize, Clone, ToSchema)]
 pub struct HealthCheck{
     /// simple HealthCheck response, reports the number of resources in use
     num_grants: usize,
-    num_permissions: usize
+    num_permissions: usize,
+    /// true until every watch has synced, data may be incomplete or (when loaded from the cache) out of date
+    stale: bool,
+    /// number of audit log entries which couldn't be written, only reported when AUDIT_LOG_PATH is set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    audit_log_write_failures: Option<u64>,
 }
 
 /// simple health check, reports the number of resources in use
-pub async fn health(controller: web::Data<Arc<RBACController>>) -> impl Responder {
+pub async fn health(req: HttpRequest, controller: web::Data<Arc<RBACController>>) -> impl Responder {
     let rbac_controller = controller.get_ref();
-    let num_grants = rbac_controller.grant_controller.get_grants().len();
-    let num_permissions = rbac_controller.permission_controller.get_permissions().len();
-    match serde_json::to_string(&HealthCheck {
+    // one lock per controller, and no copy of the state - this is the most frequently hit endpoint
+    let (num_grants, grants_synced) = rbac_controller.grant_controller.get_status();
+    let (num_permissions, permissions_synced) = rbac_controller.permission_controller.get_status();
+    let stale = !(grants_synced && permissions_synced);
+    json_response(&req, &HealthCheck {
         num_grants,
-        num_permissions
-    }){
-        Ok(output) => HttpResponse::Ok().body(output),
-        Err(err) => {
-            error!("error when attempting to serialize health check {:?}", err);
-            HttpResponse::InternalServerError().body("internal server error, check logs for details")
-        }
+        num_permissions,
+        stale,
+        audit_log_write_failures: rbac_controller.grant_controller.get_audit_log_write_failures(),
+    }, "health check")
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct Readiness{
+    /// every watch has synced
+    synced: bool,
+    /// the api server answered recently, see ApiServerCheck
+    api_server_reachable: bool,
+}
+
+/// readiness check - ready (200) once every watch has synced, as long as the api server keeps answering. Otherwise 503,
+/// so that a prolonged api server outage takes the controller out of service even if its watches haven't failed yet
+pub async fn readiness(req: HttpRequest, controller: web::Data<Arc<RBACController>>, api_server: web::Data<ApiServerCheck>) -> impl Responder {
+    let rbac_controller = controller.get_ref();
+    let (_, grants_synced) = rbac_controller.grant_controller.get_status();
+    let (_, permissions_synced) = rbac_controller.permission_controller.get_status();
+    let readiness = Readiness {
+        synced: grants_synced && permissions_synced,
+        api_server_reachable: api_server.is_reachable(),
+    };
+    if readiness.synced && readiness.api_server_reachable {
+        json_response(&req, &readiness, "readiness")
+    } else {
+        HttpResponse::ServiceUnavailable().json(&readiness)
     }
-}
\ No newline at end of file
+}
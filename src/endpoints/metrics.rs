@@ -0,0 +1,26 @@
+use std::sync::Arc;
+use actix_web::{web, HttpResponse, Responder};
+use log::error;
+use prometheus::TEXT_FORMAT;
+use crate::RBACController;
+use crate::controller::metrics::{Metrics, StateCounts};
+
+/// Prometheus metrics, with the size of the state read from the controllers at scrape time
+pub async fn get_metrics(controller: web::Data<Arc<RBACController>>, metrics: web::Data<Metrics>) -> impl Responder {
+    let rbac_controller = controller.get_ref();
+    let (subjects, _) = rbac_controller.grant_controller.get_status();
+    let (permissions, _) = rbac_controller.permission_controller.get_status();
+    let counts = StateCounts {
+        grants: rbac_controller.grant_controller.get_grant_count(),
+        permissions,
+        subjects,
+        audit_log_write_failures: rbac_controller.grant_controller.get_audit_log_write_failures(),
+    };
+    match metrics.render(counts) {
+        Ok(output) => HttpResponse::Ok().content_type(TEXT_FORMAT).body(output),
+        Err(err) => {
+            error!("error when attempting to render metrics {:?}", err);
+            HttpResponse::InternalServerError().body("internal server error, check logs for details")
+        }
+    }
+}
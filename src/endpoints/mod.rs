@@ -1,5 +1,18 @@
+pub mod audit;
+pub mod can_i;
+pub mod debug;
+pub mod diff;
 pub mod grants;
 pub mod health;
+pub mod metrics;
+pub mod openapi;
 pub mod output_types;
 pub mod permissions;
+pub mod response;
+pub mod roles;
+pub mod simulate;
+pub mod stats;
+pub mod structs;
+pub mod subjects;
 pub mod users;
+pub mod version;
@@ -0,0 +1,146 @@
+use actix_web::{HttpRequest, Responder};
+use utoipa::openapi::path::{OperationBuilder, ParameterBuilder, ParameterIn, PathItem, PathItemType};
+use utoipa::openapi::request_body::RequestBodyBuilder;
+use utoipa::openapi::schema::{Array, ObjectBuilder, SchemaType};
+use utoipa::openapi::{ContentBuilder, OpenApi as OpenApiDocument, PathsBuilder, Ref, RefOr, Required, ResponseBuilder, Schema};
+use utoipa::OpenApi;
+
+use crate::API_PREFIX;
+use crate::endpoints::can_i::CanIInput;
+use crate::endpoints::diff::DiffInput;
+use crate::endpoints::grants::{OutputAll, OutputGrantConflict, OutputGrantConflicts, OutputSelectedGrants, OutputSubjectGrant, OutputSubjects};
+use crate::endpoints::health::{HealthCheck, Readiness};
+use crate::endpoints::output_types::{OutputGrant, OutputId, OutputSubject};
+use crate::endpoints::permissions::{Filter, PermissionsInput};
+use crate::endpoints::response::{json_response, OutputRequestError};
+use crate::endpoints::simulate::SimulateDeleteInput;
+use crate::endpoints::stats::OutputStats;
+use crate::endpoints::structs::{GrantInput, UserType};
+use crate::endpoints::subjects::{OutputSubjectsForPermission, SubjectsForPermissionInput};
+use crate::endpoints::version::OutputVersion;
+use crate::controller::rbac_grant::{GrantType, IDType};
+
+const JSON: &str = "application/json";
+
+/// the schemas of the document, derived from the serde types so they can't drift apart
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "user-manifest", description = "Answers who can do what in a k8s cluster, from its RBAC bindings and roles"),
+    components(schemas(
+        CanIInput, DiffInput, Filter, GrantInput, GrantType, HealthCheck, IDType, OutputAll, OutputGrant,
+        OutputGrantConflict, OutputGrantConflicts, OutputId, OutputRequestError, OutputSelectedGrants, OutputStats,
+        OutputSubject, OutputSubjectGrant, OutputSubjects, OutputSubjectsForPermission, OutputVersion,
+        PermissionsInput, Readiness, SimulateDeleteInput, SubjectsForPermissionInput, UserType,
+    ))
+)]
+struct ApiDoc;
+
+/// an endpoint as described in the document
+struct Endpoint {
+    method: PathItemType,
+    /// path as routed in main, {name} segments are path parameters
+    path: &'static str,
+    summary: &'static str,
+    /// schema of the json body, for endpoints taking one
+    request: Option<Body>,
+    /// schema of the successful response, None for those without a schema (e.g. those holding k8s rules)
+    response: Option<&'static str>,
+}
+
+/// the json body an endpoint takes, by schema name
+enum Body {
+    One(&'static str),
+    List(&'static str),
+}
+
+/// endpoints served at the root: health, metrics and this document
+const UNVERSIONED_ENDPOINTS: &[Endpoint] = &[
+    Endpoint{method: PathItemType::Get, path: "/health", summary: "number of grants and permissions tracked (on ADMIN_PORT when it's set)", request: None, response: Some("HealthCheck")},
+    Endpoint{method: PathItemType::Get, path: "/readyz", summary: "readiness probe, 503 until synced or while the API server is unreachable (on ADMIN_PORT when it's set)", request: None, response: Some("Readiness")},
+    Endpoint{method: PathItemType::Get, path: "/ready", summary: "same as /readyz (on ADMIN_PORT when it's set)", request: None, response: Some("Readiness")},
+    Endpoint{method: PathItemType::Get, path: "/metrics", summary: "metrics in the Prometheus text format (on ADMIN_PORT when it's set)", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/version", summary: "build information and uptime", request: None, response: Some("OutputVersion")},
+    Endpoint{method: PathItemType::Get, path: "/openapi.json", summary: "this document", request: None, response: None},
+];
+
+/// endpoints served under API_PREFIX
+const API_ENDPOINTS: &[Endpoint] = &[
+    Endpoint{method: PathItemType::Get, path: "/grants", summary: "every subject with its grants, or with ?modified_since the grants changed since then", request: None, response: Some("OutputAll")},
+    Endpoint{method: PathItemType::Post, path: "/grants/subjects", summary: "the grants of the requested subjects", request: Some(Body::One("GrantInput")), response: Some("OutputSelectedGrants")},
+    Endpoint{method: PathItemType::Get, path: "/grants/watch", summary: "server-sent events with the grants of each subject whose grants change", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/grants/conflicts", summary: "subjects bound to roles of the same name by both a RoleBinding and a ClusterRoleBinding", request: None, response: Some("OutputGrantConflicts")},
+    Endpoint{method: PathItemType::Get, path: "/grants/{kind}/{namespace}/{name}", summary: "the grants of a single subject", request: None, response: Some("OutputSubjectGrant")},
+    Endpoint{method: PathItemType::Get, path: "/grants/{kind}/{name}", summary: "the grants of a single User or Group", request: None, response: Some("OutputSubjectGrant")},
+    Endpoint{method: PathItemType::Get, path: "/subjects", summary: "the subjects with grants, optionally of one kind", request: None, response: Some("OutputSubjects")},
+    Endpoint{method: PathItemType::Post, path: "/can-i", summary: "whether a subject can perform an action, and which grant allows it", request: Some(Body::One("CanIInput")), response: None},
+    Endpoint{method: PathItemType::Post, path: "/can-i/batch", summary: "checks a list of actions like /can-i", request: Some(Body::List("CanIInput")), response: None},
+    Endpoint{method: PathItemType::Post, path: "/subjects-for-permission", summary: "every subject allowed to perform an action", request: Some(Body::One("SubjectsForPermissionInput")), response: Some("OutputSubjectsForPermission")},
+    Endpoint{method: PathItemType::Get, path: "/audit/escalation", summary: "subjects able to escalate their privileges", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/audit/top-subjects", summary: "the subjects with the most grants", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/audit/unused-roles", summary: "roles no grant references", request: None, response: None},
+    Endpoint{method: PathItemType::Post, path: "/simulate/delete", summary: "what each subject of a binding would lose if it was deleted", request: Some(Body::One("SimulateDeleteInput")), response: None},
+    Endpoint{method: PathItemType::Post, path: "/diff", summary: "compares the permissions of two subjects", request: Some(Body::One("DiffInput")), response: None},
+    Endpoint{method: PathItemType::Get, path: "/roles/{rbac_type}/{namespace}/{name}/affected-subjects", summary: "the subjects affected by changes to a Role", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/roles/{rbac_type}/{name}/affected-subjects", summary: "the subjects affected by changes to a ClusterRole", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/stats", summary: "how many of each type of binding, role and subject are tracked", request: None, response: Some("OutputStats")},
+    Endpoint{method: PathItemType::Get, path: "/stats/breakdown", summary: "the number of grants by namespace and subject kind", request: None, response: None},
+    Endpoint{method: PathItemType::Post, path: "/permissions", summary: "the rules a subject has, keyed by namespace", request: Some(Body::One("PermissionsInput")), response: None},
+    Endpoint{method: PathItemType::Get, path: "/permissions/subject/effective", summary: "everything a User or ServiceAccount can do", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/permissions/subject/matrix", summary: "the effective permissions as a matrix of verbs", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/permissions/subject/namespaces", summary: "the effective permissions in each namespace", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/debug/grant/raw", summary: "the binding of a grant as stored in k8s (only with DEBUG_ENDPOINTS)", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/debug/unresolved", summary: "grants which give no permissions (only with DEBUG_ENDPOINTS)", request: None, response: None},
+    Endpoint{method: PathItemType::Get, path: "/debug/malformed-rules", summary: "rules mixing resources and non resource urls (only with DEBUG_ENDPOINTS)", request: None, response: None},
+];
+
+/// the OpenAPI 3 document describing every endpoint, for generating clients
+pub async fn get_openapi(req: HttpRequest) -> impl Responder {
+    json_response(&req, &openapi_document(), "openapi document")
+}
+
+fn openapi_document() -> OpenApiDocument {
+    let mut paths = PathsBuilder::new();
+    let endpoints = UNVERSIONED_ENDPOINTS.iter().map(|endpoint| (String::new(), endpoint))
+        .chain(API_ENDPOINTS.iter().map(|endpoint| (API_PREFIX.to_string(), endpoint)));
+    for (prefix, endpoint) in endpoints{
+        paths = paths.path(format!("{}{}", prefix, endpoint.path), path_item(endpoint));
+    }
+    let mut document = ApiDoc::openapi();
+    document.info.version = env!("CARGO_PKG_VERSION").to_string();
+    document.info.license = None;
+    document.paths = paths.build();
+    document
+}
+
+fn path_item(endpoint: &Endpoint) -> PathItem {
+    let mut operation = OperationBuilder::new().summary(Some(endpoint.summary));
+    for segment in endpoint.path.split('/'){
+        if let Some(name) = segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')){
+            operation = operation.parameter(ParameterBuilder::new()
+                .name(name)
+                .parameter_in(ParameterIn::Path)
+                .required(Required::True)
+                .schema(Some(ObjectBuilder::new().schema_type(SchemaType::String))));
+        }
+    }
+    if let Some(request) = &endpoint.request{
+        let schema: RefOr<Schema> = match request{
+            Body::One(name) => Ref::from_schema_name(*name).into(),
+            Body::List(name) => Array::new(Ref::from_schema_name(*name)).into(),
+        };
+        // JsonConfig's error handler answers bodies which can't be read
+        operation = operation
+            .request_body(Some(RequestBodyBuilder::new()
+                .required(Some(Required::True))
+                .content(JSON, ContentBuilder::new().schema(schema).build())
+                .build()))
+            .response("400", ResponseBuilder::new()
+                .description("the body can't be read")
+                .content(JSON, ContentBuilder::new().schema(Ref::from_schema_name("OutputRequestError")).build()));
+    }
+    let mut response = ResponseBuilder::new().description("success");
+    if let Some(schema) = endpoint.response{
+        response = response.content(JSON, ContentBuilder::new().schema(Ref::from_schema_name(schema)).build());
+    }
+    PathItem::new(endpoint.method.clone(), operation.response("200", response))
+}
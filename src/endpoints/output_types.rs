@@ -1,38 +1,53 @@
+use k8s_openapi::api::rbac::v1::PolicyRule;
 use serde::Serialize;
+use utoipa::ToSchema;
 use crate::controller::rbac_grant::{RBACGrant, RBACId, GrantSubject};
 
 // To maintain proper encapsulation the user-facing versions of structs
 // differ from the internal-facing versions of the structs
 
 // OutputGrant is the user-facing version of RBACGrant
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct OutputGrant{
     pub grant_type: String,
+    /// namespace of the grant, * for cluster-wide grants
     pub namespace: String,
     pub name: String,
     pub rbac_id: OutputId,
 }
 
 // OutputID is the user-facing version of RBACId
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct OutputId{
     pub name: String,
+    /// namespace of the role, empty for cluster-scoped roles
     pub namespace: String,
     pub rbac_type: String,
 }
 
 // OutputSubject is the user-facing version of GrantSubject
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Clone, ToSchema)]
 pub struct OutputSubject{
     pub api_group: String,
     pub kind: String,
     pub name: String,
+    /// namespace of a ServiceAccount, empty for users and groups
     pub namespace: String,
 }
 
+// OutputRuleSource is a grant along with the rules of the role it references
+#[derive(Serialize, Clone)]
+pub struct OutputRuleSource{
+    pub grant_name: String,
+    pub grant_type: String,
+    pub role_name: String,
+    pub role_type: String,
+    pub rules: Vec<PolicyRule>,
+}
+
 impl OutputGrant {
     pub(crate) fn from_rbac_grant(grant: RBACGrant) -> OutputGrant{
-        return OutputGrant { 
+        OutputGrant { 
             grant_type: grant.grant_type.to_string(), 
             namespace: grant.namespace.unwrap_or("*".to_string()), 
             name: grant.name, 
@@ -41,9 +56,21 @@ impl OutputGrant {
     }
 }
 
+impl OutputRuleSource {
+    pub(crate) fn from_rbac_grant(grant: RBACGrant, rules: Vec<PolicyRule>) -> OutputRuleSource{
+        OutputRuleSource {
+            grant_name: grant.name,
+            grant_type: grant.grant_type.to_string(),
+            role_name: grant.permissions_id.name,
+            role_type: grant.permissions_id.rbac_type.to_string(),
+            rules,
+        }
+    }
+}
+
 impl OutputId {
     pub(crate) fn from_rbac_id(id: RBACId) -> OutputId{
-        return OutputId { 
+        OutputId { 
             name: id.name, 
             namespace: id.namespace.unwrap_or("".to_string()), 
             rbac_type: id.rbac_type.to_string(),
@@ -53,7 +80,7 @@ impl OutputId {
 
 impl OutputSubject{
     pub(crate) fn from_grant_subject(subject: GrantSubject) -> OutputSubject{
-        return OutputSubject { 
+        OutputSubject { 
             api_group: subject.api_group, 
             kind: subject.kind.to_string(), 
             name: subject.name, 
@@ -61,3 +88,24 @@ impl OutputSubject{
         }
     }
 }
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::controller::rbac_grant::RBACGrant;
+    use crate::controller::testing::{cluster_role_binding, role_binding};
+
+    #[test]
+    fn cluster_scoped_namespaces_are_output_as_such(){
+        let grant = OutputGrant::from_rbac_grant(RBACGrant::from_cluster_role_binding(&cluster_role_binding("view", "ClusterRole", "view", vec![])));
+        assert_eq!(grant.namespace, "*");
+        assert_eq!(grant.rbac_id.namespace, "");
+
+        let grant = OutputGrant::from_rbac_grant(RBACGrant::from_role_binding(&role_binding("prod", "view", "ClusterRole", "view", vec![])));
+        assert_eq!(grant.namespace, "prod");
+        assert_eq!(grant.rbac_id.namespace, "");
+
+        let grant = OutputGrant::from_rbac_grant(RBACGrant::from_role_binding(&role_binding("prod", "edit", "Role", "editor", vec![])));
+        assert_eq!(grant.rbac_id.namespace, "prod");
+    }
+}
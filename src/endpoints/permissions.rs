@@ -0,0 +1,1044 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::fmt::Formatter;
+use std::sync::Arc;
+use log::error;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{non_empty_namespace, GrantSubject, GrantType, IDType, ImplicitGroups, RBACGrant, SubjectKind};
+use crate::controller::rules::{merge_rules, write_rule, WILDCARD};
+use k8s_openapi::api::rbac::v1::PolicyRule;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::endpoints::output_types::{OutputGrant, OutputRuleSource, OutputSubject};
+use crate::endpoints::response::{json_response, permissions_unavailable};
+use crate::endpoints::structs::GrantInput;
+
+/// namespace key used for grants which apply in every namespace (ClusterRoleBindings)
+pub const ALL_NAMESPACES: &str = "*";
+
+/// Error returned when a grant references a role that we don't have rules for
+#[derive(Debug, Clone)]
+pub struct MissingRulesError {
+    /// name of the grant referencing the role
+    pub grant: String,
+    /// the role which couldn't be found, in the form of type/namespace/name
+    pub role: String,
+    pub namespace: String,
+}
+
+impl fmt::Display for MissingRulesError{
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "missing rules for {} referenced by grant {} in namespace {}", self.role, self.grant, self.namespace)
+    }
+}
+
+/// body of the response to a MissingRulesError
+#[derive(Serialize, Clone)]
+pub struct OutputMissingRules {
+    pub error: &'static str,
+    pub grant: String,
+    pub role: String,
+    pub namespace: String,
+}
+
+impl MissingRulesError{
+    /// the grant's role is missing, namespace being where the grant applies
+    fn for_grant(grant: RBACGrant, namespace: String) -> MissingRulesError{
+        let id = &grant.permissions_id;
+        MissingRulesError{
+            role: format!("{}/{}/{}", id.rbac_type, id.namespace.clone().unwrap_or_default(), id.name),
+            grant: grant.name,
+            namespace,
+        }
+    }
+
+    /// 409 describing the grant and missing role, since the error means the grants and roles we know of disagree (e.g.
+    /// a role that was deleted, or isn't watched) rather than that the server failed
+    pub fn to_response(&self) -> HttpResponse{
+        HttpResponse::Conflict().json(OutputMissingRules{
+            error: "missing rules",
+            grant: self.grant.clone(),
+            role: self.role.clone(),
+            namespace: self.namespace.clone(),
+        })
+    }
+}
+
+/// why grants referencing a kind of role other than Role and ClusterRole can't be resolved
+pub const UNKNOWN_ROLE_KIND: &str = "the binding references a kind of role other than Role or ClusterRole";
+/// why grants referencing a kind of role which isn't watched (e.g. ClusterRoles with DISABLE_CLUSTER_SCOPE) can't be
+/// resolved
+pub const UNWATCHED_ROLE_KIND: &str = "the binding references a kind of role which isn't watched, see DISABLE_CLUSTER_SCOPE and WATCH_RESOURCES";
+
+/// a grant which can never resolve to rules, and why
+#[derive(Serialize, Clone)]
+pub struct OutputUnresolvedGrant {
+    pub grant: OutputGrant,
+    pub reason: &'static str,
+}
+
+/// why the grant's role can never be found, None if it can. Grants referencing a kind of role that isn't Role or
+/// ClusterRole, or one the controller doesn't watch, can't - the permission outputs leave them out instead of failing
+/// on their missing rules
+pub fn unresolvable_reason(controller: &RBACController, grant: &RBACGrant) -> Option<&'static str>{
+    match &grant.permissions_id.rbac_type{
+        IDType::Unknown => Some(UNKNOWN_ROLE_KIND),
+        id_type if !controller.permission_controller.watches(id_type) => Some(UNWATCHED_ROLE_KIND),
+        _ => None,
+    }
+}
+
+/// whether the grant's role can ever be found, see unresolvable_reason
+pub fn is_resolvable(controller: &RBACController, grant: &RBACGrant) -> bool{
+    unresolvable_reason(controller, grant).is_none()
+}
+
+/// the grants which can never resolve to rules (see unresolvable_reason) with the reason, sorted by type, namespace
+/// and name
+pub fn unresolvable_grants<'a>(controller: &RBACController, grants: impl IntoIterator<Item = &'a RBACGrant>) -> Vec<OutputUnresolvedGrant>{
+    let mut unresolvable: Vec<(&RBACGrant, &'static str)> = grants.into_iter()
+        .filter_map(|grant| unresolvable_reason(controller, grant).map(|reason| (grant, reason)))
+        .collect();
+    unresolvable.sort_by(|(a, _), (b, _)| (a.grant_type.to_string(), &a.namespace, &a.name).cmp(&(b.grant_type.to_string(), &b.namespace, &b.name)));
+    unresolvable.into_iter().map(|(grant, reason)| OutputUnresolvedGrant{
+        grant: OutputGrant::from_rbac_grant(grant.clone()),
+        reason,
+    }).collect()
+}
+
+/// the namespace the rules of a grant apply in, ALL_NAMESPACES for cluster-wide grants
+pub fn grant_namespace(grant: &RBACGrant) -> String{
+    grant.namespace.clone().unwrap_or_else(|| ALL_NAMESPACES.to_string())
+}
+
+/// flattens the grants into the rules they give, keyed by the namespace the rules apply in. Errors if any grant
+/// references a role we don't have rules for, grants which can never resolve (see is_resolvable) are left out.
+/// The rules are copied out under one lock acquisition up front, so no lock is held while the output is built or
+/// later serialized - serializing a large output under the lock would block the watchers from applying updates
+pub fn create_permission_output(controller: &RBACController, grants: HashSet<RBACGrant>) -> Result<HashMap<String, Vec<PolicyRule>>, MissingRulesError>{
+    let output = create_annotated_permission_output(controller, grants)?;
+    Ok(output.into_iter().map(|(namespace, group)| (namespace, group.rules)).collect())
+}
+
+/// rules which apply in a namespace, along with the types of the grants they come from
+#[derive(Serialize, Clone, Default)]
+pub struct OutputRuleGroup {
+    pub grant_types: BTreeSet<String>,
+    pub rules: Vec<PolicyRule>,
+}
+
+/// like create_permission_output, but also records which grant types contributed the rules of each namespace
+pub fn create_annotated_permission_output(controller: &RBACController, grants: HashSet<RBACGrant>) -> Result<HashMap<String, OutputRuleGroup>, MissingRulesError>{
+    let permissions = controller.permission_controller.get_permissions_for_ids(grants.iter().map(|grant| &grant.permissions_id));
+    let mut output: HashMap<String, OutputRuleGroup> = HashMap::new();
+    for grant in grants.into_iter().filter(|grant| is_resolvable(controller, grant)){
+        let namespace = grant_namespace(&grant);
+        // several grants can reference the same role, so the rules can't be moved out of the map
+        let rules = match permissions.get(&grant.permissions_id){
+            Some(rules) => rules.clone(),
+            None => return Err(MissingRulesError::for_grant(grant, namespace)),
+        };
+        let group = output.entry(namespace).or_default();
+        group.grant_types.insert(grant.grant_type.to_string());
+        group.rules.extend(rules);
+    }
+    Ok(output)
+}
+
+/// like create_permission_output, but keeps the rules of each grant apart, along with the grant and role they come
+/// from. The grants of each namespace are sorted by type and name
+pub fn create_provenance_output(controller: &RBACController, grants: HashSet<RBACGrant>) -> Result<HashMap<String, Vec<OutputRuleSource>>, MissingRulesError>{
+    let permissions = controller.permission_controller.get_permissions_for_ids(grants.iter().map(|grant| &grant.permissions_id));
+    let mut grants: Vec<RBACGrant> = grants.into_iter().filter(|grant| is_resolvable(controller, grant)).collect();
+    grants.sort_by(|a, b| (a.grant_type.to_string(), &a.name).cmp(&(b.grant_type.to_string(), &b.name)));
+    let mut output: HashMap<String, Vec<OutputRuleSource>> = HashMap::new();
+    for grant in grants{
+        let namespace = grant_namespace(&grant);
+        let rules = match permissions.get(&grant.permissions_id){
+            Some(rules) => rules.clone(),
+            None => return Err(MissingRulesError::for_grant(grant, namespace)),
+        };
+        output.entry(namespace).or_default().push(OutputRuleSource::from_rbac_grant(grant, rules));
+    }
+    Ok(output)
+}
+
+/// body of POST /permissions: the subject to look up, optionally narrowed down by a filter
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct PermissionsInput {
+    #[serde(flatten)]
+    pub subject: GrantInput,
+    pub filter: Option<Filter>,
+}
+
+/// narrows down the output of POST /permissions
+#[derive(Deserialize, Clone, Debug, Default, ToSchema)]
+pub struct Filter {
+    /// only return the rules which apply in this namespace, along with the cluster-wide rules (which apply in it too)
+    pub namespace: Option<String>,
+    /// only return the rules of this type of grant
+    pub grant_type: Option<GrantType>,
+    /// only return the rules of grants referencing this type of role
+    pub role_type: Option<IDType>,
+    /// only return the rules (and verbs of those rules) which allow modifying resources, see WRITE_VERBS
+    pub writes_only: Option<bool>,
+}
+
+/// whether the grant passes the filter - it applies in the filter's namespace (or cluster-wide), is of its type and
+/// references its type of role
+pub fn grant_filter_applies(filter: &Filter, grant: &RBACGrant) -> bool{
+    let namespace_applies = match non_empty_namespace(filter.namespace.clone()){
+        Some(namespace) => grant.namespace.is_none() || grant.namespace.as_ref() == Some(&namespace),
+        None => true,
+    };
+    let grant_type_applies = filter.grant_type.as_ref().is_none_or(|grant_type| *grant_type == grant.grant_type);
+    let role_type_applies = filter.role_type.as_ref().is_none_or(|role_type| *role_type == grant.permissions_id.rbac_type);
+    namespace_applies && grant_type_applies && role_type_applies
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PermissionsQuery {
+    /// "provenance" lists the grants of each namespace with their rules, see create_provenance_output
+    pub output: Option<String>,
+    /// return a summary instead of the rules, only "apigroups" is supported, like EffectiveOptions::summary
+    pub summary: Option<String>,
+    /// wrap the rules in an envelope echoing the resolved subject and its grants, like EffectiveOptions::include_subject.
+    /// Without it the rules are returned as the bare map keyed by namespace
+    pub include_subject: Option<bool>,
+    /// list the grant types contributing the rules of each namespace, like EffectiveOptions::annotate_sources. Also
+    /// returns the envelope
+    pub annotate_sources: Option<bool>,
+}
+
+/// the rules a subject has through its own grants (and those of the groups k8s implicitly places it in, if it asks for
+/// them), keyed by the namespace they apply in and merged (unless the input sets merge to false). 404s if neither the
+/// subject nor those groups have grants. Unresolvable grants are left out, only the envelopes list them (the bare map has nowhere to)
+pub async fn get_permissions(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, query: web::Query<PermissionsQuery>, input: web::Json<PermissionsInput>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let provenance = match query.output.as_deref(){
+        None => false,
+        Some("provenance") => true,
+        Some(other) => return HttpResponse::BadRequest().body(format!("unsupported output {}, expected provenance", other)),
+    };
+    let api_group_summary_requested = match query.summary.as_deref(){
+        None => false,
+        Some("apigroups") => true,
+        Some(other) => return HttpResponse::BadRequest().body(format!("unsupported summary {}, expected apigroups", other)),
+    };
+    let include_subject = query.include_subject.unwrap_or(false);
+    let annotate_sources = query.annotate_sources.unwrap_or(false);
+    if provenance && (include_subject || annotate_sources){
+        return HttpResponse::BadRequest().body("include_subject and annotate_sources can't be combined with output=provenance");
+    }
+    let rbac_controller = controller.get_ref();
+    let subject = input.subject.to_grant_subject();
+    let groups = input.subject.implicit_groups(&subject, &implicit_groups, false);
+    let group_names: Vec<String> = groups.iter().map(|group| group.name.clone()).collect();
+    // grants through the implicit groups count, e.g. a ServiceAccount only bound through system:serviceaccounts:<ns>
+    let mut grants = match rbac_controller.grant_controller.get_grants_of_subject_and_groups(&subject, &groups){
+        Some(grants) => grants,
+        None => return HttpResponse::NotFound().body("no grants found for the subject"),
+    };
+    if let Some(filter) = &input.filter{
+        grants.retain(|grant| grant_filter_applies(filter, grant));
+    }
+    let writes_only = input.filter.as_ref().and_then(|filter| filter.writes_only).unwrap_or(false);
+    if provenance{
+        let mut sources = match create_provenance_output(rbac_controller, grants){
+            Ok(sources) => sources,
+            Err(err) => {
+                error!("unable to resolve permission provenance: {}", err);
+                return err.to_response()
+            }
+        };
+        if writes_only{
+            retain_write_sources(&mut sources);
+        }
+        return json_response(&req, &sources, "permission provenance");
+    }
+    let source = match include_subject{
+        true => Some(permission_source(&subject, &grants)),
+        false => None,
+    };
+    let unresolved_grants = unresolvable_grants(rbac_controller, &grants);
+    let mut permissions = match create_annotated_permission_output(rbac_controller, grants){
+        Ok(permissions) => permissions,
+        Err(err) => {
+            error!("unable to resolve permissions: {}", err);
+            return err.to_response()
+        }
+    };
+    if writes_only{
+        retain_writes(&mut permissions);
+    }
+    if api_group_summary_requested{
+        return json_response(&req, &OutputApiGroupSummary {
+            groups: group_names,
+            api_groups: api_group_summary(&permissions),
+            source,
+            unresolved_grants,
+        }, "api group summary");
+    }
+    if input.subject.merge.unwrap_or(true){
+        for group in permissions.values_mut(){
+            group.rules = merge_rules(std::mem::take(&mut group.rules));
+        }
+    }
+    if annotate_sources{
+        return json_response(&req, &OutputAnnotatedEffectivePermissions {
+            groups: group_names,
+            permissions,
+            truncation: None,
+            source,
+            unresolved_grants,
+        }, "permissions");
+    }
+    let permissions: HashMap<String, Vec<PolicyRule>> = permissions.into_iter().map(|(namespace, group)| (namespace, group.rules)).collect();
+    if source.is_some(){
+        return json_response(&req, &OutputEffectivePermissions {
+            groups: group_names,
+            permissions,
+            truncation: None,
+            source,
+            unresolved_grants,
+        }, "permissions");
+    }
+    json_response(&req, &permissions, "permissions")
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EffectiveQuery {
+    /// User or ServiceAccount
+    pub kind: String,
+    pub name: String,
+    /// namespace of the ServiceAccount
+    pub namespace: Option<String>,
+    /// comma-separated groups the subject is a member of, on top of the implicit groups
+    pub groups: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EffectiveOptions {
+    /// list the grant types (RoleBinding/ClusterRoleBinding) contributing the rules of each namespace
+    pub annotate_sources: Option<bool>,
+    /// most rules returned per namespace, the rest are left out. Unlimited if not given
+    pub max_rules: Option<usize>,
+    /// return a summary instead of the rules, only "apigroups" is supported
+    pub summary: Option<String>,
+    /// only keep the rules (and verbs of those rules) which allow modifying resources, see WRITE_VERBS
+    pub writes_only: Option<bool>,
+    /// echo the resolved subject and the grants the permissions come from, so the output describes itself
+    pub include_subject: Option<bool>,
+}
+
+/// the subject an output is for and the grants its permissions come from, see EffectiveOptions::include_subject
+#[derive(Serialize, Clone)]
+pub struct OutputPermissionSource {
+    pub subject: OutputSubject,
+    /// the grants of the subject and its groups, sorted by type, namespace and name
+    pub grants: Vec<OutputGrant>,
+}
+
+/// how many rules were left out of an output limited by max_rules
+#[derive(Serialize, Clone)]
+pub struct OutputTruncation {
+    /// whether any rules were left out
+    pub truncated: bool,
+    /// number of rules left out, for each namespace which had some left out
+    pub omitted_rules: HashMap<String, usize>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputEffectivePermissions {
+    /// every group whose grants were included, implicit or supplied
+    pub groups: Vec<String>,
+    /// rules the subject has, keyed by the namespace they apply in
+    pub permissions: HashMap<String, Vec<PolicyRule>>,
+    /// only present when the output was limited with max_rules
+    #[serde(flatten)]
+    pub truncation: Option<OutputTruncation>,
+    /// only present with include_subject
+    #[serde(flatten)]
+    pub source: Option<OutputPermissionSource>,
+    /// grants which can never resolve to rules, left out of the permissions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_grants: Vec<OutputUnresolvedGrant>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputAnnotatedEffectivePermissions {
+    /// every group whose grants were included, implicit or supplied
+    pub groups: Vec<String>,
+    /// rules the subject has along with the grant types they come from, keyed by the namespace they apply in
+    pub permissions: HashMap<String, OutputRuleGroup>,
+    /// only present when the output was limited with max_rules
+    #[serde(flatten)]
+    pub truncation: Option<OutputTruncation>,
+    /// only present with include_subject
+    #[serde(flatten)]
+    pub source: Option<OutputPermissionSource>,
+    /// grants which can never resolve to rules, left out of the permissions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_grants: Vec<OutputUnresolvedGrant>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputApiGroupSummary {
+    /// every group whose grants were included, implicit or supplied
+    pub groups: Vec<String>,
+    /// the api groups the subject has any access to, keyed by the namespace the access applies in
+    pub api_groups: HashMap<String, OutputNamespaceApiGroups>,
+    /// only present with include_subject
+    #[serde(flatten)]
+    pub source: Option<OutputPermissionSource>,
+    /// grants which can never resolve to rules, left out of the permissions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_grants: Vec<OutputUnresolvedGrant>,
+}
+
+#[derive(Serialize, Clone, Default)]
+pub struct OutputNamespaceApiGroups {
+    /// true if a rule applies to every api group (through the * wildcard), which isn't listed in `api_groups`
+    pub all_api_groups: bool,
+    pub api_groups: BTreeSet<String>,
+}
+
+/// everything a User/ServiceAccount can do: the union of its own grants, those of the groups k8s implicitly places it
+/// in (see GrantSubject::implicit_groups) and those of any groups given in the query
+pub async fn get_effective_permissions(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, query: web::Query<EffectiveQuery>, options: web::Query<EffectiveOptions>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let rbac_controller = controller.get_ref();
+    let (subjects, group_names) = match effective_subjects(&query, &implicit_groups){
+        Ok(subjects) => subjects,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let grants = rbac_controller.grant_controller.get_grants_for_subjects(&subjects);
+    let source = match options.include_subject.unwrap_or(false){
+        // effective_subjects lists the subject itself last
+        true => subjects.last().map(|subject| permission_source(subject, &grants)),
+        false => None,
+    };
+    let unresolved_grants = unresolvable_grants(rbac_controller, &grants);
+    let mut permissions = match create_annotated_permission_output(rbac_controller, grants){
+        Ok(permissions) => permissions,
+        Err(err) => {
+            error!("unable to resolve effective permissions: {}", err);
+            return err.to_response()
+        }
+    };
+    if options.writes_only.unwrap_or(false){
+        retain_writes(&mut permissions);
+    }
+    match options.summary.as_deref(){
+        None => (),
+        Some("apigroups") => return json_response(&req, &OutputApiGroupSummary {
+            groups: group_names,
+            api_groups: api_group_summary(&permissions),
+            source,
+            unresolved_grants,
+        }, "api group summary"),
+        Some(other) => return HttpResponse::BadRequest().body(format!("unsupported summary {}, expected apigroups", other)),
+    }
+    let truncation = options.max_rules.map(|max_rules| truncate_rules(&mut permissions, max_rules));
+    if options.annotate_sources.unwrap_or(false){
+        return json_response(&req, &OutputAnnotatedEffectivePermissions {
+            groups: group_names,
+            permissions,
+            truncation,
+            source,
+            unresolved_grants,
+        }, "effective permissions")
+    }
+    json_response(&req, &OutputEffectivePermissions {
+        groups: group_names,
+        permissions: permissions.into_iter().map(|(namespace, group)| (namespace, group.rules)).collect(),
+        truncation,
+        source,
+        unresolved_grants,
+    }, "effective permissions")
+}
+
+fn permission_source(subject: &GrantSubject, grants: &HashSet<RBACGrant>) -> OutputPermissionSource{
+    let mut grants: Vec<RBACGrant> = grants.iter().cloned().collect();
+    grants.sort_by(|a, b| (a.grant_type.to_string(), &a.namespace, &a.name).cmp(&(b.grant_type.to_string(), &b.namespace, &b.name)));
+    OutputPermissionSource{
+        subject: OutputSubject::from_grant_subject(subject.clone()),
+        grants: grants.into_iter().map(OutputGrant::from_rbac_grant).collect(),
+    }
+}
+
+/// the api groups appearing in the resource rules of each namespace. Non resource rules have no api groups
+fn api_group_summary(permissions: &HashMap<String, OutputRuleGroup>) -> HashMap<String, OutputNamespaceApiGroups>{
+    permissions.iter().map(|(namespace, group)| {
+        let mut summary = OutputNamespaceApiGroups::default();
+        for api_group in group.rules.iter().filter_map(|rule| rule.api_groups.as_ref()).flatten(){
+            if api_group == WILDCARD{
+                summary.all_api_groups = true;
+            } else {
+                summary.api_groups.insert(api_group.clone());
+            }
+        }
+        (namespace.clone(), summary)
+    }).collect()
+}
+
+/// splits the rules down to their write verbs, dropping rules (and namespaces) which don't allow any writes
+fn retain_writes(permissions: &mut HashMap<String, OutputRuleGroup>){
+    for group in permissions.values_mut(){
+        group.rules = group.rules.iter().filter_map(write_rule).collect();
+    }
+    permissions.retain(|_, group| !group.rules.is_empty());
+}
+
+/// like retain_writes, for the rules of each grant of a provenance output. Grants which don't allow any writes are
+/// dropped along with their rules
+fn retain_write_sources(sources: &mut HashMap<String, Vec<OutputRuleSource>>){
+    for namespace_sources in sources.values_mut(){
+        for source in namespace_sources.iter_mut(){
+            source.rules = source.rules.iter().filter_map(write_rule).collect();
+        }
+        namespace_sources.retain(|source| !source.rules.is_empty());
+    }
+    sources.retain(|_, namespace_sources| !namespace_sources.is_empty());
+}
+
+/// keeps at most max_rules rules in each namespace, returning how many were left out
+fn truncate_rules(permissions: &mut HashMap<String, OutputRuleGroup>, max_rules: usize) -> OutputTruncation{
+    let mut omitted_rules: HashMap<String, usize> = HashMap::new();
+    for (namespace, group) in permissions.iter_mut(){
+        if group.rules.len() > max_rules{
+            omitted_rules.insert(namespace.clone(), group.rules.len() - max_rules);
+            group.rules.truncate(max_rules);
+        }
+    }
+    OutputTruncation{
+        truncated: !omitted_rules.is_empty(),
+        omitted_rules,
+    }
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputNamespaceAccess {
+    /// every group whose grants were included, implicit or supplied
+    pub groups: Vec<String>,
+    /// for each namespace the subject has grants in, the rules of those grants along with the cluster-wide rules
+    pub namespaces: HashMap<String, Vec<PolicyRule>>,
+    /// rules which apply in every namespace, and so are all the subject can do in namespaces not listed in `namespaces`
+    pub cluster_wide: Vec<PolicyRule>,
+    /// grants which can never resolve to rules, left out of the permissions
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unresolved_grants: Vec<OutputUnresolvedGrant>,
+}
+
+/// the effective permissions of a subject (see get_effective_permissions) in each namespace it has grants in, with the
+/// cluster-wide rules folded into every namespace since they apply there too
+pub async fn get_namespace_access(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, query: web::Query<EffectiveQuery>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let rbac_controller = controller.get_ref();
+    let (subjects, group_names) = match effective_subjects(&query, &implicit_groups){
+        Ok(subjects) => subjects,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let grants = rbac_controller.grant_controller.get_grants_for_subjects(&subjects);
+    let unresolved_grants = unresolvable_grants(rbac_controller, &grants);
+    let permissions = match create_permission_output(rbac_controller, grants){
+        Ok(permissions) => permissions,
+        Err(err) => {
+            error!("unable to resolve permissions by namespace: {}", err);
+            return err.to_response()
+        }
+    };
+    let (namespaces, cluster_wide) = fold_cluster_wide(permissions);
+    json_response(&req, &OutputNamespaceAccess {
+        groups: group_names,
+        namespaces,
+        cluster_wide,
+        unresolved_grants,
+    }, "namespace access")
+}
+
+/// splits the cluster-wide rules (under ALL_NAMESPACES) out of the permissions, and adds them to every other namespace
+pub fn fold_cluster_wide(mut permissions: HashMap<String, Vec<PolicyRule>>) -> (HashMap<String, Vec<PolicyRule>>, Vec<PolicyRule>){
+    let cluster_wide = permissions.remove(ALL_NAMESPACES).unwrap_or_default();
+    for rules in permissions.values_mut(){
+        rules.extend(cluster_wide.iter().cloned());
+    }
+    (permissions, cluster_wide)
+}
+
+#[derive(Deserialize, Clone)]
+pub struct MatrixQuery {
+    #[serde(flatten)]
+    pub subject: EffectiveQuery,
+    /// comma-separated resources, only these rows are returned. Wildcard rules are expanded into these resources
+    pub only: Option<String>,
+    /// comma-separated resources whose rows are left out
+    pub exclude: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputPermissionMatrix {
+    pub rows: Vec<OutputMatrixRow>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputMatrixRow {
+    pub namespace: String,
+    pub api_group: String,
+    pub resource: String,
+    pub verbs: Vec<String>,
+}
+
+/// the effective permissions of a subject (see get_effective_permissions) as a matrix of the verbs allowed on each
+/// (namespace, api group, resource), optionally limited to/excluding some resources
+pub async fn get_permission_matrix(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, query: web::Query<MatrixQuery>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let rbac_controller = controller.get_ref();
+    let (subjects, _) = match effective_subjects(&query.subject, &implicit_groups){
+        Ok(subjects) => subjects,
+        Err(reason) => return HttpResponse::BadRequest().body(reason),
+    };
+    let grants = rbac_controller.grant_controller.get_grants_for_subjects(&subjects);
+    let permissions = match create_permission_output(rbac_controller, grants){
+        Ok(permissions) => permissions,
+        Err(err) => {
+            error!("unable to resolve permissions for the matrix: {}", err);
+            return err.to_response()
+        }
+    };
+    let only = query.only.as_deref().map(split_list);
+    let exclude = query.exclude.as_deref().map(split_list).unwrap_or_default();
+    json_response(&req, &OutputPermissionMatrix {
+        rows: permission_matrix(&permissions, only.as_deref(), &exclude),
+    }, "permission matrix")
+}
+
+/// builds the matrix rows, sorted by namespace/api group/resource. With `only`, a wildcard resource is expanded into
+/// each of the listed resources. `exclude` can't expand a wildcard (the full set of resources isn't known), so wildcard
+/// rows stay in the output
+pub fn permission_matrix(permissions: &HashMap<String, Vec<PolicyRule>>, only: Option<&[String]>, exclude: &[String]) -> Vec<OutputMatrixRow>{
+    let mut matrix: BTreeMap<(String, String, String), BTreeSet<String>> = BTreeMap::new();
+    for (namespace, rules) in permissions{
+        for rule in rules{
+            let (api_groups, resources) = match (&rule.api_groups, &rule.resources){
+                (Some(api_groups), Some(resources)) => (api_groups, resources),
+                // non resource rules don't belong in the matrix
+                _ => continue,
+            };
+            for resource in resources{
+                let row_resources: Vec<&String> = match only{
+                    Some(only) if resource == WILDCARD => only.iter().collect(),
+                    Some(only) => only.iter().filter(|r| *r == resource).collect(),
+                    None => vec![resource],
+                };
+                for row_resource in row_resources{
+                    if exclude.contains(row_resource){
+                        continue;
+                    }
+                    for api_group in api_groups{
+                        matrix.entry((namespace.clone(), api_group.clone(), row_resource.clone()))
+                            .or_default()
+                            .extend(rule.verbs.iter().cloned());
+                    }
+                }
+            }
+        }
+    }
+    matrix.into_iter().map(|((namespace, api_group, resource), verbs)| OutputMatrixRow{
+        namespace,
+        api_group,
+        resource,
+        verbs: verbs.into_iter().collect(),
+    }).collect()
+}
+
+/// builds the subject of an effective permissions query along with all of its groups (implicit and supplied). Returns
+/// every subject to collect grants for, and the names of the groups. Errors with the reason the query is invalid
+fn effective_subjects(query: &EffectiveQuery, implicit_groups: &ImplicitGroups) -> Result<(Vec<GrantSubject>, Vec<String>), String>{
+    let kind = SubjectKind::from_kind(&query.kind);
+    if kind != SubjectKind::User && kind != SubjectKind::ServiceAccount{
+        return Err(format!("unsupported kind {}, expected User or ServiceAccount", query.kind));
+    }
+    let namespace = non_empty_namespace(query.namespace.clone());
+    if kind == SubjectKind::ServiceAccount && namespace.is_none(){
+        return Err("namespace is required for a ServiceAccount".to_string());
+    }
+    let subject = match kind{
+        // users may be named like the ServiceAccount they authenticate as
+        SubjectKind::User => GrantSubject::from_user_name(&query.name),
+        _ => GrantSubject{
+            api_group: kind.default_api_group(),
+            namespace,
+            kind,
+            name: query.name.clone(),
+        },
+    };
+    let mut groups = subject.implicit_groups(implicit_groups);
+    if let Some(supplied_groups) = &query.groups{
+        groups.extend(split_list(supplied_groups).iter().map(|group| GrantSubject::group(group)));
+    }
+    let group_names: Vec<String> = groups.iter().map(|group| group.name.clone()).collect();
+    let mut subjects = groups;
+    subjects.push(subject);
+    Ok((subjects, group_names))
+}
+
+/// splits a comma-separated query parameter, ignoring empty entries
+fn split_list(list: &str) -> Vec<String>{
+    list.split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(str::to_string).collect()
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use actix_web::App;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use crate::controller::testing::{cluster_role, cluster_role_binding, namespaced_rbac_controller, rbac_controller, role, role_binding, rule, subject};
+
+    /// calls POST /permissions with the body, returning the status and the json response
+    async fn post_permissions(controller: RBACController, query: &str, body: serde_json::Value) -> (u16, serde_json::Value){
+        let app = init_service(App::new()
+            .app_data(web::Data::new(Arc::new(controller)))
+            .app_data(web::Data::new(ImplicitGroups::default()))
+            .route("/permissions", web::post().to(get_permissions))).await;
+        let request = TestRequest::post().uri(&format!("/permissions{}", query)).set_json(body).to_request();
+        let response = call_service(&app, request).await;
+        let status = response.status().as_u16();
+        let body = read_body(response).await;
+        (status, serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null))
+    }
+
+    fn permissions() -> HashMap<String, Vec<PolicyRule>>{
+        HashMap::from([
+            ("default".to_string(), vec![rule(&[""], &["pods", "secrets"], &["get"])]),
+            ("kube-system".to_string(), vec![rule(&["apps"], &[WILDCARD], &["list"])]),
+        ])
+    }
+
+    fn rows(matrix: &[OutputMatrixRow]) -> Vec<(&str, &str, &str)>{
+        matrix.iter().map(|row| (row.namespace.as_str(), row.api_group.as_str(), row.resource.as_str())).collect()
+    }
+
+    #[test]
+    fn matrix_only_keeps_listed_resources(){
+        let only = vec!["pods".to_string(), "deployments".to_string()];
+        let matrix = permission_matrix(&permissions(), Some(&only), &[]);
+        // the wildcard rule expands to every listed resource
+        assert_eq!(rows(&matrix), vec![
+            ("default", "", "pods"),
+            ("kube-system", "apps", "deployments"),
+            ("kube-system", "apps", "pods"),
+        ]);
+    }
+
+    #[test]
+    fn matrix_exclude_drops_listed_resources(){
+        let exclude = vec!["secrets".to_string()];
+        let matrix = permission_matrix(&permissions(), None, &exclude);
+        assert_eq!(rows(&matrix), vec![
+            ("default", "", "pods"),
+            ("kube-system", "apps", WILDCARD),
+        ]);
+    }
+
+    #[test]
+    fn matrix_exclude_applies_after_only(){
+        let only = vec!["pods".to_string(), "deployments".to_string()];
+        let exclude = vec!["pods".to_string()];
+        let matrix = permission_matrix(&permissions(), Some(&only), &exclude);
+        assert_eq!(rows(&matrix), vec![("kube-system", "apps", "deployments")]);
+        assert_eq!(matrix[0].verbs, vec!["list".to_string()]);
+    }
+
+    #[test]
+    fn role_and_cluster_role_of_the_same_name_stay_apart(){
+        let pods = rule(&[""], &["pods"], &["get"]);
+        let secrets = rule(&[""], &["secrets"], &["list"]);
+        let controller = rbac_controller(
+            vec![
+                role_binding("default", "to-role", "Role", "shared", vec![subject("User", "alice", None)]),
+                role_binding("other", "to-cluster-role", "ClusterRole", "shared", vec![subject("User", "alice", None)]),
+            ],
+            vec![],
+            vec![role("default", "shared", vec![pods.clone()])],
+            vec![cluster_role("shared", vec![secrets.clone()])],
+        );
+        let grants = controller.grant_controller.get_grants_for_subjects(&[GrantSubject::from_user_name("alice")]);
+        let output = create_permission_output(&controller, grants).unwrap();
+        assert_eq!(output.len(), 2);
+        assert_eq!(output["default"], vec![pods]);
+        assert_eq!(output["other"], vec![secrets]);
+    }
+
+    #[test]
+    fn wildcard_api_group_is_summarized_apart(){
+        let permissions = HashMap::from([("*".to_string(), OutputRuleGroup{
+            grant_types: BTreeSet::new(),
+            rules: vec![rule(&["*"], &["pods"], &["get"]), rule(&["apps"], &["deployments"], &["get"])],
+        })]);
+        let summary = api_group_summary(&permissions);
+        assert!(summary["*"].all_api_groups);
+        assert_eq!(summary["*"].api_groups, BTreeSet::from(["apps".to_string()]));
+    }
+
+    #[actix_web::test]
+    async fn permissions_summarize_api_groups(){
+        let controller = rbac_controller(
+            vec![role_binding("default", "edit-apps", "Role", "apps-editor", vec![subject("User", "alice", None)])],
+            vec![cluster_role_binding("read-all", "ClusterRole", "reader", vec![subject("User", "alice", None)])],
+            vec![role("default", "apps-editor", vec![rule(&["apps"], &["deployments"], &["update"])])],
+            vec![cluster_role("reader", vec![rule(&["*"], &["*"], &["get"])])],
+        );
+        let body = serde_json::json!({"name": "alice", "user_type": "User"});
+        let (status, output) = post_permissions(controller, "?summary=apigroups", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["api_groups"]["default"], serde_json::json!({"all_api_groups": false, "api_groups": ["apps"]}));
+        assert_eq!(output["api_groups"]["*"], serde_json::json!({"all_api_groups": true, "api_groups": []}));
+        // implicit groups are only included on request
+        assert_eq!(output["groups"], serde_json::json!([]));
+    }
+
+    #[actix_web::test]
+    async fn writes_only_filter_drops_reads(){
+        let controller = || rbac_controller(
+            vec![role_binding("default", "edit", "Role", "editor", vec![subject("User", "alice", None)])],
+            vec![],
+            vec![role("default", "editor", vec![
+                rule(&[""], &["pods"], &["get", "list"]),
+                rule(&["apps"], &["deployments"], &["get", "update"]),
+            ])],
+            vec![],
+        );
+        let (status, output) = post_permissions(controller(), "", serde_json::json!({"name": "alice", "user_type": "User"})).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["default"].as_array().map(Vec::len), Some(2));
+
+        let body = serde_json::json!({"name": "alice", "user_type": "User", "filter": {"writes_only": true}});
+        let (status, output) = post_permissions(controller(), "", body.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["default"], serde_json::json!([{"apiGroups": ["apps"], "resources": ["deployments"], "verbs": ["update"]}]));
+
+        let (status, output) = post_permissions(controller(), "?output=provenance", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["default"][0]["rules"], serde_json::json!([{"apiGroups": ["apps"], "resources": ["deployments"], "verbs": ["update"]}]));
+    }
+
+    #[actix_web::test]
+    async fn role_type_filter_splits_role_bindings(){
+        let pods = rule(&[""], &["pods"], &["get"]);
+        let secrets = rule(&[""], &["secrets"], &["list"]);
+        let controller = || rbac_controller(
+            vec![
+                role_binding("default", "to-role", "Role", "pod-reader", vec![subject("User", "alice", None)]),
+                role_binding("default", "to-cluster-role", "ClusterRole", "secret-lister", vec![subject("User", "alice", None)]),
+            ],
+            vec![],
+            vec![role("default", "pod-reader", vec![pods.clone()])],
+            vec![cluster_role("secret-lister", vec![secrets.clone()])],
+        );
+        let body = |role_type: &str| serde_json::json!({"name": "alice", "user_type": "User", "filter": {"role_type": role_type}});
+
+        let (status, output) = post_permissions(controller(), "", body("Role")).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["default"], serde_json::to_value(vec![&pods]).unwrap());
+        let (status, output) = post_permissions(controller(), "", body("ClusterRole")).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["default"], serde_json::to_value(vec![&secrets]).unwrap());
+    }
+
+    #[actix_web::test]
+    async fn unknown_role_kinds_are_listed_as_unresolved(){
+        let pods = rule(&[""], &["pods"], &["get"]);
+        let controller = || rbac_controller(
+            vec![
+                role_binding("default", "read-pods", "Role", "pod-reader", vec![subject("User", "alice", None)]),
+                role_binding("default", "custom", "Foo", "bar", vec![subject("User", "alice", None)]),
+            ],
+            vec![],
+            vec![role("default", "pod-reader", vec![pods.clone()])],
+            vec![],
+        );
+        let body = serde_json::json!({"name": "alice", "user_type": "User"});
+
+        // the bare map leaves the grant out without failing
+        let (status, output) = post_permissions(controller(), "", body.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(output, serde_json::json!({"default": [&pods]}));
+
+        for query in ["?include_subject=true", "?annotate_sources=true", "?summary=apigroups"]{
+            let (status, output) = post_permissions(controller(), query, body.clone()).await;
+            assert_eq!(status, 200, "{}", query);
+            let unresolved = output["unresolved_grants"].as_array().unwrap();
+            assert_eq!(unresolved.len(), 1, "{}", query);
+            assert_eq!(unresolved[0]["grant"]["name"], "custom", "{}", query);
+            assert_eq!(unresolved[0]["reason"], UNKNOWN_ROLE_KIND, "{}", query);
+        }
+    }
+
+    #[actix_web::test]
+    async fn unwatched_cluster_roles_are_unresolved(){
+        let pods = rule(&[""], &["pods"], &["get"]);
+        let controller = || namespaced_rbac_controller(
+            vec![
+                role_binding("default", "read-pods", "Role", "pod-reader", vec![subject("User", "alice", None)]),
+                role_binding("default", "view", "ClusterRole", "view", vec![subject("User", "alice", None)]),
+            ],
+            vec![role("default", "pod-reader", vec![pods.clone()])],
+        );
+        let body = serde_json::json!({"name": "alice", "user_type": "User"});
+        let (status, output) = post_permissions(controller(), "", body.clone()).await;
+        assert_eq!(status, 200);
+        assert_eq!(output, serde_json::json!({"default": [&pods]}));
+
+        let (status, output) = post_permissions(controller(), "?include_subject=true", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["unresolved_grants"][0]["grant"]["name"], "view");
+        assert_eq!(output["unresolved_grants"][0]["reason"], UNWATCHED_ROLE_KIND);
+
+        let grants = controller().grant_controller.get_grants_for_subjects(&[GrantSubject::from_user_name("alice")]);
+        assert!(create_provenance_output(&controller(), grants).is_ok());
+    }
+
+    #[test]
+    fn namespace_filter_keeps_cluster_wide_grants(){
+        let namespaced = RBACGrant::from_role_binding(&role_binding("prod", "edit", "Role", "editor", vec![]));
+        let cluster_wide = RBACGrant::from_cluster_role_binding(&cluster_role_binding("view", "ClusterRole", "view", vec![]));
+        let filter = |namespace: Option<&str>| Filter{ namespace: namespace.map(str::to_string), ..Filter::default() };
+
+        assert!(grant_filter_applies(&filter(Some("prod")), &namespaced));
+        assert!(grant_filter_applies(&filter(Some("prod")), &cluster_wide));
+        assert!(!grant_filter_applies(&filter(Some("dev")), &namespaced));
+        assert!(grant_filter_applies(&filter(Some("dev")), &cluster_wide));
+        // an empty namespace is no namespace filter rather than a namespace of its own
+        assert!(grant_filter_applies(&filter(Some("")), &namespaced));
+        assert!(grant_filter_applies(&filter(None), &namespaced));
+    }
+
+    #[actix_web::test]
+    async fn include_subject_wraps_the_permissions(){
+        let controller = rbac_controller(
+            vec![role_binding("default", "view", "Role", "viewer", vec![subject("User", "alice", None)])],
+            vec![],
+            vec![role("default", "viewer", vec![rule(&[""], &["pods"], &["get"])])],
+            vec![],
+        );
+        let body = serde_json::json!({"name": "alice", "user_type": "User"});
+        let (status, output) = post_permissions(controller, "?include_subject=true", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["subject"]["name"], "alice");
+        assert_eq!(output["subject"]["kind"], "User");
+        assert_eq!(output["grants"][0]["name"], "view");
+        assert_eq!(output["groups"], serde_json::json!([]));
+        assert_eq!(output["permissions"]["default"], serde_json::json!([{"apiGroups": [""], "resources": ["pods"], "verbs": ["get"]}]));
+    }
+
+    #[actix_web::test]
+    async fn annotate_sources_lists_grant_types(){
+        let controller = rbac_controller(
+            vec![
+                role_binding("default", "view", "Role", "viewer", vec![subject("User", "alice", None)]),
+                role_binding("default", "view-again", "Role", "viewer", vec![subject("User", "alice", None)]),
+            ],
+            vec![cluster_role_binding("read-nodes", "ClusterRole", "node-reader", vec![subject("User", "alice", None)])],
+            vec![role("default", "viewer", vec![rule(&[""], &["pods"], &["get"])])],
+            vec![cluster_role("node-reader", vec![rule(&[""], &["nodes"], &["get"])])],
+        );
+        let body = serde_json::json!({"name": "alice", "user_type": "User"});
+        let (status, output) = post_permissions(controller, "?annotate_sources=true", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output["permissions"]["default"]["grant_types"], serde_json::json!(["RoleBinding"]));
+        // the rules are still merged
+        assert_eq!(output["permissions"]["default"]["rules"], serde_json::json!([{"apiGroups": [""], "resources": ["pods"], "verbs": ["get"]}]));
+        assert_eq!(output["permissions"]["*"]["grant_types"], serde_json::json!(["ClusterRoleBinding"]));
+        assert!(output.get("subject").is_none());
+    }
+
+    #[actix_web::test]
+    async fn grants_of_implicit_groups_are_found(){
+        let controller = || rbac_controller(
+            vec![role_binding("ci", "view", "Role", "viewer", vec![subject("Group", "system:serviceaccounts:ci", None)])],
+            vec![cluster_role_binding("discovery", "ClusterRole", "discoverer", vec![subject("Group", "system:authenticated", None)])],
+            vec![role("ci", "viewer", vec![rule(&[""], &["pods"], &["get"])])],
+            vec![cluster_role("discoverer", vec![rule(&[""], &["namespaces"], &["list"])])],
+        );
+        // without its groups, a subject no binding names has no grants
+        let body = serde_json::json!({"name": "nobody", "user_type": "User"});
+        let (status, _) = post_permissions(controller(), "", body).await;
+        assert_eq!(status, 404);
+
+        let body = serde_json::json!({"name": "nobody", "user_type": "User", "expand_implicit_groups": true});
+        let (status, output) = post_permissions(controller(), "", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output, serde_json::json!({"*": [{"apiGroups": [""], "resources": ["namespaces"], "verbs": ["list"]}]}));
+    }
+
+    #[actix_web::test]
+    async fn service_account_bound_through_its_namespace_group(){
+        let controller = || rbac_controller(
+            vec![role_binding("ci", "view", "Role", "viewer", vec![subject("Group", "system:serviceaccounts:ci", None)])],
+            vec![],
+            vec![role("ci", "viewer", vec![rule(&[""], &["pods"], &["get"])])],
+            vec![],
+        );
+        let body = serde_json::json!({"name": "deployer", "namespace": "ci", "user_type": "ServiceAccount", "expand_implicit_groups": true});
+        let (status, output) = post_permissions(controller(), "", body).await;
+        assert_eq!(status, 200);
+        assert_eq!(output, serde_json::json!({"ci": [{"apiGroups": [""], "resources": ["pods"], "verbs": ["get"]}]}));
+
+        let body = serde_json::json!({"name": "deployer", "namespace": "other", "user_type": "ServiceAccount", "expand_implicit_groups": true});
+        let (status, _) = post_permissions(controller(), "", body).await;
+        assert_eq!(status, 404);
+    }
+
+    #[test]
+    fn cluster_wide_rules_fold_into_each_namespace(){
+        let pods = rule(&[""], &["pods"], &["get"]);
+        let nodes = rule(&[""], &["nodes"], &["list"]);
+        let controller = rbac_controller(
+            vec![role_binding("ci", "view", "Role", "viewer", vec![subject("ServiceAccount", "deployer", Some("ci"))])],
+            vec![cluster_role_binding("read-nodes", "ClusterRole", "node-reader", vec![subject("ServiceAccount", "deployer", Some("ci"))])],
+            vec![role("ci", "viewer", vec![pods.clone()])],
+            vec![cluster_role("node-reader", vec![nodes.clone()])],
+        );
+        let account = GrantSubject::from_user_name("system:serviceaccount:ci:deployer");
+        let grants = controller.grant_controller.get_grants_for_subjects(&[account]);
+        let (namespaces, cluster_wide) = fold_cluster_wide(create_permission_output(&controller, grants).unwrap());
+        assert_eq!(namespaces.len(), 1);
+        assert_eq!(namespaces["ci"], vec![pods, nodes.clone()]);
+        assert_eq!(cluster_wide, vec![nodes]);
+    }
+
+    #[test]
+    fn filter_by_type_and_namespace(){
+        let in_default = RBACGrant::from_role_binding(&role_binding("default", "view", "Role", "view", vec![]));
+        let in_other = RBACGrant::from_role_binding(&role_binding("other", "view", "Role", "view", vec![]));
+        let cluster_wide = RBACGrant::from_cluster_role_binding(&cluster_role_binding("view", "ClusterRole", "view", vec![]));
+        let grants = [&in_default, &in_other, &cluster_wide];
+        let passing = |filter: Filter| -> Vec<&RBACGrant>{
+            grants.iter().copied().filter(|grant| grant_filter_applies(&filter, grant)).collect()
+        };
+
+        assert_eq!(passing(Filter::default()), grants);
+        let by_type = Filter{ grant_type: Some(GrantType::RoleBinding), ..Filter::default() };
+        assert_eq!(passing(by_type), vec![&in_default, &in_other]);
+        // cluster-wide grants apply in every namespace
+        let by_namespace = Filter{ namespace: Some("default".to_string()), ..Filter::default() };
+        assert_eq!(passing(by_namespace), vec![&in_default, &cluster_wide]);
+        let empty_namespace = Filter{ namespace: Some("".to_string()), ..Filter::default() };
+        assert_eq!(passing(empty_namespace), grants);
+        let combined = Filter{ namespace: Some("default".to_string()), grant_type: Some(GrantType::ClusterRoleBinding), ..Filter::default() };
+        assert_eq!(passing(combined), vec![&cluster_wide]);
+        let nothing = Filter{ namespace: Some("missing".to_string()), grant_type: Some(GrantType::RoleBinding), ..Filter::default() };
+        assert!(passing(nothing).is_empty());
+    }
+}
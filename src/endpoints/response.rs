@@ -0,0 +1,196 @@
+use actix_web::error::{InternalError, JsonPayloadError};
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use log::error;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+#[derive(Deserialize, Default)]
+struct FormatQuery {
+    /// pretty print the json output - meant for humans, defaults to compact output
+    pretty: Option<bool>,
+}
+
+/// content types which request yaml output
+const YAML_CONTENT_TYPES: &[&str] = &["application/yaml", "application/x-yaml"];
+/// media types which accept json output, the default when there's no Accept header
+const JSON_CONTENT_TYPES: &[&str] = &["application/json", "application/*", "*/*"];
+/// content type of newline delimited json, for endpoints offering one record per line
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// serializes the value into a 200 json response, pretty printed if the request had ?pretty=true. Requests accepting
+/// application/yaml get yaml instead (see yaml_response), and those accepting neither get a 406. Logs and returns a 500
+/// if the value can't be serialized, with `description` naming what was being serialized
+pub fn json_response<T: Serialize>(req: &HttpRequest, value: &T, description: &str) -> HttpResponse {
+    if accepts_yaml(req) {
+        return yaml_response(value, description);
+    }
+    // endpoints without an ndjson output answer ndjson requests with json, as documented for /grants?modified_since
+    if req.headers().contains_key(header::ACCEPT) && !accepts_any(req, JSON_CONTENT_TYPES) && !accepts_ndjson(req) {
+        return HttpResponse::NotAcceptable().body("unsupported Accept header, expected application/json or application/yaml");
+    }
+    let pretty = web::Query::<FormatQuery>::from_query(req.query_string())
+        .map(|query| query.pretty.unwrap_or(false))
+        .unwrap_or(false);
+    let output = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    match output {
+        Ok(output) => HttpResponse::Ok().content_type("application/json").body(output),
+        Err(err) => {
+            error!("error when attempting to serialize {} {:?}", description, err);
+            HttpResponse::InternalServerError().body("internal server error, check logs for details")
+        }
+    }
+}
+
+/// body of the response to a request which couldn't be read
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputRequestError {
+    pub error: &'static str,
+    /// what was wrong with the request, e.g. serde's description of an unknown user_type
+    pub detail: String,
+}
+
+/// handles json bodies which can't be read (registered through web::JsonConfig), answering with an OutputRequestError
+/// instead of actix's plain text. Bodies which aren't valid json or don't match the input (like an unknown user_type)
+/// get a 400, other failures keep actix's status, e.g. 413 for bodies which are too large
+pub fn json_error_handler(err: JsonPayloadError, _req: &HttpRequest) -> actix_web::Error {
+    let detail = match &err {
+        JsonPayloadError::Deserialize(err) if err.is_data() => format!("invalid value in the request body: {}", err),
+        JsonPayloadError::Deserialize(err) => format!("the request body isn't valid json: {}", err),
+        err => err.to_string(),
+    };
+    let response = HttpResponse::build(err.status_code()).json(OutputRequestError {
+        error: "invalid request",
+        detail,
+    });
+    InternalError::from_response(err, response).into()
+}
+
+/// 501 for endpoints which resolve grants into permissions when neither roles nor cluster roles are watched, so that
+/// an empty result isn't mistaken for a subject without permissions
+pub fn permissions_unavailable() -> HttpResponse {
+    HttpResponse::NotImplemented().body("permissions aren't available, roles and clusterroles aren't watched - see WATCH_RESOURCES")
+}
+
+/// whether the Accept header asks for yaml
+fn accepts_yaml(req: &HttpRequest) -> bool {
+    accepts_any(req, YAML_CONTENT_TYPES)
+}
+
+/// whether the Accept header asks for newline delimited json
+pub fn accepts_ndjson(req: &HttpRequest) -> bool {
+    accepts_any(req, &[NDJSON_CONTENT_TYPE])
+}
+
+/// whether one of the Accept header's media types (ignoring their parameters) is one of the content types
+fn accepts_any(req: &HttpRequest, content_types: &[&str]) -> bool {
+    let accept = match req.headers().get(header::ACCEPT).and_then(|accept| accept.to_str().ok()) {
+        Some(accept) => accept,
+        None => return false,
+    };
+    accept.split(',')
+        .map(|media_type| media_type.split(';').next().unwrap_or_default().trim())
+        .any(|media_type| content_types.contains(&media_type))
+}
+
+/// serializes each value as compact json on its own line into a 200 ndjson response, so that clients can process large
+/// outputs line by line. Logs and returns a 500 if a value can't be serialized
+pub fn ndjson_response<T: Serialize>(values: &[T], description: &str) -> HttpResponse {
+    let mut output = String::new();
+    for value in values {
+        match serde_json::to_string(value) {
+            Ok(line) => {
+                output.push_str(&line);
+                output.push('\n');
+            }
+            Err(err) => {
+                error!("error when attempting to serialize {} {:?}", description, err);
+                return HttpResponse::InternalServerError().body("internal server error, check logs for details");
+            }
+        }
+    }
+    HttpResponse::Ok().content_type(NDJSON_CONTENT_TYPE).body(output)
+}
+
+/// serializes the value into a 200 yaml response
+#[cfg(feature = "yaml")]
+fn yaml_response<T: Serialize>(value: &T, description: &str) -> HttpResponse {
+    match serde_yaml::to_string(value) {
+        Ok(output) => HttpResponse::Ok().content_type("application/yaml").body(output),
+        Err(err) => {
+            error!("error when attempting to serialize {} {:?}", description, err);
+            HttpResponse::InternalServerError().body("internal server error, check logs for details")
+        }
+    }
+}
+
+/// yaml output needs the yaml feature, without it yaml requests are refused
+#[cfg(not(feature = "yaml"))]
+fn yaml_response<T: Serialize>(_value: &T, _description: &str) -> HttpResponse {
+    HttpResponse::NotAcceptable().body("yaml output is not available, this build was compiled without the yaml feature - request application/json instead")
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn respond(accept: Option<&str>) -> HttpResponse{
+        let mut req = TestRequest::get().uri("/grants");
+        if let Some(accept) = accept{
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        json_response(&req.to_http_request(), &vec!["alice"], "subjects")
+    }
+
+    fn content_type(response: &HttpResponse) -> &str{
+        response.headers().get(header::CONTENT_TYPE).unwrap().to_str().unwrap()
+    }
+
+    #[test]
+    fn json_is_served_by_default(){
+        for accept in [None, Some("application/json"), Some("*/*"), Some("application/*")]{
+            let response = respond(accept);
+            assert_eq!(response.status(), 200, "{:?}", accept);
+            assert_eq!(content_type(&response), "application/json", "{:?}", accept);
+        }
+    }
+
+    #[test]
+    fn media_type_parameters_are_ignored(){
+        let response = respond(Some("text/html;q=0.9, application/json;q=0.8"));
+        assert_eq!(response.status(), 200);
+        assert_eq!(content_type(&response), "application/json");
+    }
+
+    #[test]
+    fn yaml_needs_the_yaml_feature(){
+        for accept in ["application/yaml", "application/x-yaml;q=1.0"]{
+            let response = respond(Some(accept));
+            if cfg!(feature = "yaml"){
+                assert_eq!(response.status(), 200, "{}", accept);
+                assert_eq!(content_type(&response), "application/yaml", "{}", accept);
+            } else {
+                assert_eq!(response.status(), 406, "{}", accept);
+            }
+        }
+    }
+
+    #[test]
+    fn ndjson_requests_get_json(){
+        let response = respond(Some(NDJSON_CONTENT_TYPE));
+        assert_eq!(response.status(), 200);
+        assert_eq!(content_type(&response), "application/json");
+    }
+
+    #[test]
+    fn unsupported_types_are_not_acceptable(){
+        for accept in ["text/html", "text/csv;q=0.5", "application/xml, text/plain"]{
+            assert_eq!(respond(Some(accept)).status(), 406, "{}", accept);
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::sync::Arc;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{non_empty_namespace, IDType, RBACId};
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::output_types::{OutputGrant, OutputId, OutputSubject};
+use crate::endpoints::response::{accepts_ndjson, json_response, ndjson_response};
+
+/// identifies a role in the path, namespace is only given for Roles
+#[derive(Deserialize, Clone, Debug)]
+pub struct RolePath {
+    /// Role or ClusterRole
+    pub rbac_type: String,
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputAffectedSubjects {
+    pub role: OutputId,
+    /// the grants referencing the role
+    pub grants: Vec<OutputGrant>,
+    /// the distinct subjects of those grants, who would lose the role's permissions if it was deleted
+    pub subjects: Vec<OutputSubject>,
+}
+
+/// who depends on a role: every grant referencing it, and the subjects of those grants. Subjects which also get the
+/// same permissions through another role are still listed, since they'd lose them through this one
+pub async fn get_affected_subjects(req: HttpRequest, controller: web::Data<Arc<RBACController>>, path: web::Path<RolePath>) -> impl Responder {
+    let namespace = non_empty_namespace(path.namespace.clone());
+    let (rbac_type, namespace) = match (path.rbac_type.as_str(), namespace){
+        ("Role", Some(namespace)) => (IDType::Role, Some(namespace)),
+        ("Role", None) => return HttpResponse::BadRequest().body("namespace is required for a Role"),
+        ("ClusterRole", None) => (IDType::ClusterRole, None),
+        ("ClusterRole", Some(_)) => return HttpResponse::BadRequest().body("ClusterRoles are cluster-scoped and don't take a namespace"),
+        (other, _) => return HttpResponse::BadRequest().body(format!("unsupported rbac_type {}, expected Role or ClusterRole", other)),
+    };
+    let id = RBACId{
+        rbac_type,
+        namespace,
+        name: path.name.clone(),
+    };
+    let rbac_controller = controller.get_ref();
+    let (mut grants, subjects) = rbac_controller.grant_controller.get_grants_for_role(&id);
+    if grants.is_empty() && rbac_controller.permission_controller.get_permission_for_id(&id).is_none(){
+        return HttpResponse::NotFound().body(format!("no {} {} found, and no grant references it", id.rbac_type, id.name));
+    }
+    grants.sort_by(|a, b| (a.grant_type.to_string(), &a.namespace, &a.name).cmp(&(b.grant_type.to_string(), &b.namespace, &b.name)));
+    let mut subjects: Vec<OutputSubject> = subjects.into_iter().map(OutputSubject::from_grant_subject).collect();
+    subjects.sort_by(|a, b| (&a.kind, &a.namespace, &a.name).cmp(&(&b.kind, &b.namespace, &b.name)));
+    let grants: Vec<OutputGrant> = grants.into_iter().map(OutputGrant::from_rbac_grant).collect();
+    if accepts_ndjson(&req){
+        // one line per grant referencing the role
+        return ndjson_response(&grants, "affected subjects");
+    }
+    json_response(&req, &OutputAffectedSubjects {
+        role: OutputId::from_rbac_id(id),
+        grants,
+        subjects,
+    }, "affected subjects")
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use actix_web::App;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use crate::controller::testing::{cluster_role_binding, rbac_controller, role_binding, subject};
+
+    #[actix_web::test]
+    async fn affected_grants_are_listed_as_ndjson(){
+        let controller = rbac_controller(
+            vec![role_binding("prod", "prod-view", "ClusterRole", "view", vec![subject("User", "bob", None)])],
+            vec![cluster_role_binding("view-all", "ClusterRole", "view", vec![subject("Group", "devs", None)])],
+            vec![],
+            vec![],
+        );
+        let app = init_service(App::new()
+            .app_data(web::Data::new(Arc::new(controller)))
+            .route("/roles/{rbac_type}/{name}/affected-subjects", web::get().to(get_affected_subjects))).await;
+        let request = TestRequest::get().uri("/roles/ClusterRole/view/affected-subjects").insert_header(("Accept", "application/x-ndjson")).to_request();
+        let response = call_service(&app, request).await;
+        assert_eq!(response.status(), 200);
+        let body = read_body(response).await;
+        let grants: Vec<String> = std::str::from_utf8(&body).unwrap().lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(grants, vec!["view-all", "prod-view"]);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use log::error;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{non_empty_namespace, GrantType, ImplicitGroups};
+use crate::controller::rules::rule_matches;
+use k8s_openapi::api::rbac::v1::PolicyRule;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::endpoints::output_types::{OutputGrant, OutputSubject};
+use crate::endpoints::permissions::{create_permission_output, permission_matrix, OutputMatrixRow, ALL_NAMESPACES};
+use crate::endpoints::response::{json_response, permissions_unavailable};
+
+/// identifies the grant whose deletion is simulated
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct SimulateDeleteInput {
+    /// RoleBinding or ClusterRoleBinding
+    pub grant_type: String,
+    /// namespace of the RoleBinding, None for ClusterRoleBindings
+    pub namespace: Option<String>,
+    pub name: String,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputDeleteSimulation {
+    pub grant: OutputGrant,
+    pub subjects: Vec<OutputLostPermissions>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct OutputLostPermissions {
+    pub subject: OutputSubject,
+    /// permissions the subject only has through the grant, as matrix rows of the verbs it would lose
+    pub lost: Vec<OutputMatrixRow>,
+}
+
+/// the permissions each subject of a grant would lose if the grant was deleted - those which none of the subject's
+/// other grants (including those of its implicit groups) also give. Rules limited to resource names are treated like
+/// rules on the whole resource, and non resource rules aren't compared
+pub async fn simulate_delete(req: HttpRequest, controller: web::Data<Arc<RBACController>>, implicit_groups: web::Data<ImplicitGroups>, input: web::Json<SimulateDeleteInput>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let grant_type = match input.grant_type.as_str(){
+        "RoleBinding" => GrantType::RoleBinding,
+        "ClusterRoleBinding" => GrantType::ClusterRoleBinding,
+        other => return HttpResponse::BadRequest().body(format!("unknown grant_type {}, expected RoleBinding or ClusterRoleBinding", other)),
+    };
+    let namespace = match grant_type{
+        GrantType::RoleBinding => match non_empty_namespace(input.namespace.clone()){
+            Some(namespace) => Some(namespace),
+            None => return HttpResponse::BadRequest().body("namespace is required for a RoleBinding"),
+        },
+        GrantType::ClusterRoleBinding => None,
+    };
+    let rbac_controller = controller.get_ref();
+    let (target, subjects) = match rbac_controller.grant_controller.get_grant(&grant_type, &namespace, &input.name){
+        Some(grant) => grant,
+        None => return HttpResponse::NotFound().body(format!("no {} {} found", input.grant_type, input.name)),
+    };
+    let mut output_subjects: Vec<OutputLostPermissions> = Vec::new();
+    for subject in subjects{
+        let mut subject_and_groups = subject.implicit_groups(&implicit_groups);
+        subject_and_groups.push(subject.clone());
+        let grants = rbac_controller.grant_controller.get_grants_for_subjects(&subject_and_groups);
+        let remaining_grants = grants.iter().filter(|grant| **grant != target).cloned().collect();
+        let (current, remaining) = match (create_permission_output(rbac_controller, grants), create_permission_output(rbac_controller, remaining_grants)){
+            (Ok(current), Ok(remaining)) => (current, remaining),
+            (Err(err), _) | (_, Err(err)) => {
+                error!("unable to resolve permissions while simulating the deletion of {}: {}", input.name, err);
+                return err.to_response()
+            }
+        };
+        output_subjects.push(OutputLostPermissions{
+            lost: lost_permissions(&current, &remaining),
+            subject: OutputSubject::from_grant_subject(subject),
+        });
+    }
+    json_response(&req, &OutputDeleteSimulation {
+        grant: OutputGrant::from_rbac_grant(target),
+        subjects: output_subjects,
+    }, "delete simulation")
+}
+
+/// the verbs of the current permissions' matrix which the remaining permissions don't allow. Cluster-wide rules
+/// still allow actions in every namespace
+fn lost_permissions(current: &HashMap<String, Vec<PolicyRule>>, remaining: &HashMap<String, Vec<PolicyRule>>) -> Vec<OutputMatrixRow>{
+    let cluster_rules = remaining.get(ALL_NAMESPACES).map(Vec::as_slice).unwrap_or_default();
+    permission_matrix(current, None, &[]).into_iter().filter_map(|row| {
+        let namespace_rules = match row.namespace.as_str(){
+            ALL_NAMESPACES => &[],
+            namespace => remaining.get(namespace).map(Vec::as_slice).unwrap_or_default(),
+        };
+        let verbs: Vec<String> = row.verbs.iter()
+            .filter(|verb| !namespace_rules.iter().chain(cluster_rules).any(|rule| rule_matches(rule, verb, &row.resource, &row.api_group)))
+            .cloned()
+            .collect();
+        if verbs.is_empty(){
+            return None;
+        }
+        Some(OutputMatrixRow{
+            verbs,
+            ..row
+        })
+    }).collect()
+}
+
@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use actix_web::{web, HttpRequest, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{GrantType, IDType, SubjectKind};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::endpoints::permissions::ALL_NAMESPACES;
+use crate::endpoints::response::json_response;
+
+#[derive(Serialize, Clone)]
+pub struct OutputBreakdown {
+    /// number of grants keyed by the namespace they apply in (* for cluster-wide grants), then by subject kind. A
+    /// grant is counted once for each of its subjects
+    pub grants: BTreeMap<String, BTreeMap<String, usize>>,
+}
+
+/// how many of each type of binding, role and subject are tracked
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputStats {
+    pub role_bindings: usize,
+    pub cluster_role_bindings: usize,
+    /// roles/cluster roles with rules, whether or not they're referenced
+    pub roles: usize,
+    pub cluster_roles: usize,
+    /// subjects with grants
+    pub users: usize,
+    pub groups: usize,
+    pub service_accounts: usize,
+    /// subjects of a kind k8s doesn't define
+    pub unknown_subjects: usize,
+}
+
+/// counts the bindings by type, roles by type and subjects by kind, without listing them
+pub async fn get_stats(req: HttpRequest, controller: web::Data<Arc<RBACController>>) -> impl Responder {
+    let rbac_controller = controller.get_ref();
+    let (grant_types, subject_kinds) = rbac_controller.grant_controller.get_type_counts();
+    let id_types = rbac_controller.permission_controller.get_type_counts();
+    json_response(&req, &OutputStats {
+        role_bindings: grant_types.get(&GrantType::RoleBinding).copied().unwrap_or_default(),
+        cluster_role_bindings: grant_types.get(&GrantType::ClusterRoleBinding).copied().unwrap_or_default(),
+        roles: id_types.get(&IDType::Role).copied().unwrap_or_default(),
+        cluster_roles: id_types.get(&IDType::ClusterRole).copied().unwrap_or_default(),
+        users: subject_kinds.get(&SubjectKind::User).copied().unwrap_or_default(),
+        groups: subject_kinds.get(&SubjectKind::Group).copied().unwrap_or_default(),
+        service_accounts: subject_kinds.get(&SubjectKind::ServiceAccount).copied().unwrap_or_default(),
+        unknown_subjects: subject_kinds.get(&SubjectKind::Unknown).copied().unwrap_or_default(),
+    }, "stats")
+}
+
+/// counts the grants in each namespace by the kind of subject they're for, sorted by namespace and kind
+pub async fn get_breakdown(req: HttpRequest, controller: web::Data<Arc<RBACController>>) -> impl Responder {
+    let counts = controller.get_ref().grant_controller.get_grant_breakdown();
+    let mut grants: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+    for ((namespace, kind), count) in counts{
+        let namespace = namespace.unwrap_or_else(|| ALL_NAMESPACES.to_string());
+        *grants.entry(namespace).or_default().entry(kind.to_string()).or_default() += count;
+    }
+    json_response(&req, &OutputBreakdown {
+        grants,
+    }, "grant breakdown")
+}
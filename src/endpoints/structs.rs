@@ -0,0 +1,75 @@
+use serde::Deserialize;
+use utoipa::ToSchema;
+use crate::controller::rbac_grant::{non_empty_namespace, GrantSubject, ImplicitGroups, SubjectKind};
+
+/// kinds of subject which can be looked up
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq, ToSchema)]
+pub enum UserType{
+    User,
+    Group,
+    ServiceAccount,
+}
+
+impl From<&UserType> for SubjectKind{
+    fn from(user_type: &UserType) -> SubjectKind{
+        match user_type{
+            UserType::User => SubjectKind::User,
+            UserType::Group => SubjectKind::Group,
+            UserType::ServiceAccount => SubjectKind::ServiceAccount,
+        }
+    }
+}
+
+/// user-facing identifier of a subject, used as the input of lookups
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct GrantInput{
+    pub name: String,
+    /// namespace of the subject, only used for ServiceAccounts
+    pub namespace: Option<String>,
+    pub user_type: UserType,
+    /// whether POST /permissions merges the rules of each namespace (see merge_rules), true if not given. With false,
+    /// the rules of every grant are returned as-is
+    pub merge: Option<bool>,
+    /// whether grants to the groups k8s implicitly places the subject in (see GrantSubject::implicit_groups) apply
+    /// to it. False if not given, so that lookups only cover the grants naming the subject unless asked otherwise
+    pub expand_implicit_groups: Option<bool>,
+}
+
+impl GrantInput{
+    /// the subject this input identifies, as it would be stored from a binding. Users named like
+    /// system:serviceaccount:<namespace>:<name> are the ServiceAccount they authenticate as
+    pub fn to_grant_subject(&self) -> GrantSubject{
+        let kind = SubjectKind::from(&self.user_type);
+        if kind == SubjectKind::User{
+            return GrantSubject::from_user_name(&self.name);
+        }
+        GrantSubject{
+            api_group: kind.default_api_group(),
+            namespace: match kind{
+                SubjectKind::ServiceAccount => non_empty_namespace(self.namespace.clone()),
+                _ => None,
+            },
+            kind,
+            name: self.name.clone(),
+        }
+    }
+
+    /// the subject this input identifies along with the groups k8s implicitly places it in, so that grants to broad
+    /// groups like system:authenticated apply to it. Just the subject with expand_implicit_groups set to false
+    pub fn to_grant_subjects(&self, config: &ImplicitGroups) -> Vec<GrantSubject>{
+        let subject = self.to_grant_subject();
+        let mut subjects = self.implicit_groups(&subject, config, false);
+        subjects.push(subject);
+        subjects
+    }
+
+    /// the groups k8s implicitly places the subject in if expand_implicit_groups (or the default when it isn't
+    /// given) is set, none otherwise
+    pub fn implicit_groups(&self, subject: &GrantSubject, config: &ImplicitGroups, default: bool) -> Vec<GrantSubject>{
+        if self.expand_implicit_groups.unwrap_or(default){
+            subject.implicit_groups(config)
+        }else{
+            Vec::new()
+        }
+    }
+}
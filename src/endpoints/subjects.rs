@@ -0,0 +1,79 @@
+use std::sync::Arc;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use crate::RBACController;
+use crate::controller::rbac_grant::{non_empty_namespace, GrantSubject, SubjectKind};
+use crate::controller::rules::{resource_name_matches, rule_matches};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::endpoints::grants::{sorted_subjects, OutputSubjects};
+use crate::endpoints::output_types::OutputSubject;
+use crate::endpoints::response::{json_response, permissions_unavailable};
+
+/// an action to find the subjects allowed to perform, like the body of /can-i without the subject
+#[derive(Deserialize, Clone, Debug, ToSchema)]
+pub struct SubjectsForPermissionInput {
+    pub verb: String,
+    pub resource: String,
+    /// api group of the resource, defaults to the core group
+    #[serde(default)]
+    pub api_group: String,
+    /// namespace the action happens in, None for cluster-scoped actions (which only ClusterRoleBindings can allow)
+    pub namespace: Option<String>,
+}
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputSubjectsForPermission {
+    /// every subject with a grant allowing the action, sorted by kind, namespace and name
+    pub subjects: Vec<OutputSubject>,
+}
+
+/// the reverse of /can-i: every subject (including groups) which has a grant allowing the action. Matches like /can-i
+/// does - wildcards match anything, the verb/resource/api group are lowercased and rules limited to resource names
+/// don't count, since they don't allow the action on every object. Members of the groups aren't known, so a group is
+/// listed rather than the subjects in it
+pub async fn get_subjects_for_permission(req: HttpRequest, controller: web::Data<Arc<RBACController>>, input: web::Json<SubjectsForPermissionInput>) -> impl Responder {
+    if !controller.permission_controller.resolves_permissions(){
+        return permissions_unavailable();
+    }
+    let verb = input.verb.to_lowercase();
+    let resource = input.resource.to_lowercase();
+    let api_group = input.api_group.to_lowercase();
+    let namespace = non_empty_namespace(input.namespace.clone());
+    let rbac_controller = controller.get_ref();
+    let grants = rbac_controller.grant_controller.get_grants();
+    let permissions = rbac_controller.permission_controller.get_permissions_for_ids(
+        grants.values().flatten().map(|grant| &grant.permissions_id),
+    );
+    let subjects: Vec<GrantSubject> = grants.into_iter()
+        .filter(|(_, grants)| grants.iter()
+            .filter(|grant| grant.namespace.is_none() || grant.namespace == namespace)
+            .filter_map(|grant| permissions.get(&grant.permissions_id))
+            .flatten()
+            .any(|rule| rule_matches(rule, &verb, &resource, &api_group) && resource_name_matches(rule, None)))
+        .map(|(subject, _)| subject)
+        .collect();
+    json_response(&req, &OutputSubjectsForPermission {
+        subjects: sorted_subjects(subjects),
+    }, "subjects for permission")
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SubjectsQuery {
+    /// only list subjects of this kind - User, Group or ServiceAccount
+    pub kind: Option<String>,
+}
+
+/// every subject with grants, optionally only those of one kind
+pub async fn get_subjects(req: HttpRequest, controller: web::Data<Arc<RBACController>>, query: web::Query<SubjectsQuery>) -> impl Responder {
+    let kind = match query.kind.as_deref().map(SubjectKind::from_kind){
+        Some(SubjectKind::Unknown) => return HttpResponse::BadRequest().body(format!("unsupported kind {}, expected User, Group or ServiceAccount", query.kind.clone().unwrap_or_default())),
+        kind => kind,
+    };
+    let subjects: Vec<GrantSubject> = controller.get_ref().grant_controller.get_subjects().into_iter()
+        .filter(|subject| kind.as_ref().is_none_or(|kind| subject.kind == *kind))
+        .collect();
+    json_response(&req, &OutputSubjects {
+        subjects: sorted_subjects(subjects),
+    }, "subjects")
+}
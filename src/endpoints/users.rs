@@ -1 +0,0 @@
-pub async fn health(controller: web::Data<Arc<RBACController>>) -> impl Responder {}
@@ -0,0 +1,30 @@
+use std::time::Instant;
+use actix_web::{web, HttpRequest, Responder};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::endpoints::response::json_response;
+
+/// when the process started, shared with the handlers to report the uptime
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessStart(pub Instant);
+
+#[derive(Serialize, Clone, ToSchema)]
+pub struct OutputVersion {
+    pub version: String,
+    /// commit the controller was built from, "unknown" if it couldn't be determined at build time
+    pub git_commit: String,
+    /// version of the compiler the controller was built with
+    pub rust_version: String,
+    pub uptime_seconds: u64,
+}
+
+/// build information and uptime of the controller, for correlating reported behavior with a build
+pub async fn get_version(req: HttpRequest, start: web::Data<ProcessStart>) -> impl Responder {
+    json_response(&req, &OutputVersion {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_commit: env!("GIT_COMMIT").to_string(),
+        rust_version: env!("RUST_VERSION").to_string(),
+        uptime_seconds: start.0.elapsed().as_secs(),
+    }, "version")
+}
@@ -1,69 +1,532 @@
+mod config;
 mod controller;
 mod endpoints;
 
+use crate::config::{
+    access_log_enabled, env_flag, env_list, env_millis, env_parse, env_positive, env_secs,
+    init_logger, kube_client, listen_addr,
+};
+use crate::controller::api_server_check::ApiServerCheck;
+use crate::controller::audit_log::AuditLog;
+use crate::controller::cache::Cache;
+use crate::controller::generation::Generation;
 use crate::controller::grant_controller::GrantController;
+use crate::controller::metrics::Metrics;
 use crate::controller::permission_controller::PermissionController;
 use crate::controller::rbac_controller::RBACController;
-use crate::endpoints::health::health;
-use actix_web::{web, App, HttpServer};
-use endpoints::grants::get_all_grants;
-use env_logger;
-use k8s_openapi::api::certificates::v1::CertificateSigningRequest;
-use kube::Client;
-use log::info;
-use rustls::{Certificate, PrivateKey, ServerConfig};
-use rustls_pemfile::{certs, pkcs8_private_keys};
+use crate::controller::rbac_grant::ImplicitGroups;
+use crate::controller::watch_scope::WatchScope;
+use crate::endpoints::audit::{get_escalation_audit, get_top_subjects, get_unused_roles, AuditConfig};
+use crate::endpoints::can_i::{can_i, can_i_batch};
+use crate::endpoints::debug::{get_malformed_rules, get_raw_grant, get_unresolved_grants};
+use crate::endpoints::diff::diff_permissions;
+use crate::endpoints::health::{health, readiness};
+use crate::endpoints::metrics::get_metrics;
+use crate::endpoints::openapi::get_openapi;
+use crate::endpoints::response::json_error_handler;
+use crate::endpoints::permissions::{
+    get_effective_permissions, get_namespace_access, get_permission_matrix, get_permissions,
+};
+use crate::endpoints::roles::get_affected_subjects;
+use crate::endpoints::simulate::simulate_delete;
+use crate::endpoints::stats::{get_breakdown, get_stats};
+use crate::endpoints::subjects::{get_subjects, get_subjects_for_permission};
+use crate::endpoints::version::{get_version, ProcessStart};
+use actix_web::dev::{Server, ServerHandle, Service};
+use actix_web::http::header::{HeaderName, HeaderValue, LOCATION};
+use actix_web::middleware::{Condition, Logger};
+use actix_web::{rt, web, App, HttpRequest, HttpResponse, HttpServer};
+use endpoints::grants::{
+    get_all_grants, get_grant_conflicts, get_grants_for_subjects, get_subject_grants, watch_grants,
+};
+use futures::future::{join, join_all, select_all};
+use log::{debug, error, info, warn};
+use rustls::sign::any_supported_type;
+use rustls::{Certificate, PrivateKey, ServerConfig, SignatureScheme};
+use rustls_pemfile::{certs, ec_private_keys, pkcs8_private_keys, rsa_private_keys};
+use std::collections::HashSet;
 use std::env;
 use std::error::Error;
-use std::sync::Arc;
-use std::{fs::File, io::BufReader};
+use std::fmt;
+use std::io::BufRead;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+/// header holding the generation of the state a response was built from
+const CACHE_GENERATION_HEADER: &str = "x-cache-generation";
+/// format of the access log: the request line (method, path and protocol), the status and the latency
+const ACCESS_LOG_FORMAT: &str = "\"%r\" %s %Dms";
+/// how long a client has to send the headers of a request by default, like actix's default
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_millis(5000);
+/// how long idle connections are kept open by default, like actix's default
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(5);
+/// connections each worker accepts at once by default, well below actix's default of 25k as the API isn't meant for
+/// large numbers of clients
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+/// prefix of the versioned data endpoints, the health and metrics endpoints and /version aren't versioned
+const API_PREFIX: &str = "/api/v1";
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
-    let client_result = Client::try_default().await;
-    let client = match client_result {
-        Ok(new_client) => new_client,
-        Err(result) => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                result.to_string(),
-            ))
-        }
+    let start = ProcessStart(Instant::now());
+    if let Err(err) = init_logger() {
+        return Err(std::io::Error::other(err.to_string()));
+    }
+    let client = match kube_client().await {
+        Ok(client) => client,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let scope = match WatchScope::from_env() {
+        Ok(scope) => scope,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let cache = match Cache::from_env() {
+        Ok(cache) => cache,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
     };
-    let grant_controller = GrantController::new(client.clone());
-    let permission_controller = PermissionController::new(client.clone());
+    let strict_subjects = match env_flag("STRICT_SUBJECTS") {
+        Ok(enabled) => enabled,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    // cancels the background tasks (watches, cache persistence, api server checks) on shutdown
+    let shutdown = CancellationToken::new();
+    let api_server_check = match ApiServerCheck::start(client.clone(), shutdown.clone()) {
+        Ok(check) => check,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let audit_log = match AuditLog::from_env() {
+        Ok(audit_log) => audit_log,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let metrics = match Metrics::new() {
+        Ok(metrics) => metrics,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let generation = Generation::default();
+    let grant_controller = GrantController::new(
+        client.clone(),
+        &scope,
+        cache.clone(),
+        strict_subjects,
+        audit_log,
+        generation.clone(),
+        metrics.clone(),
+        shutdown.clone(),
+    );
+    let permission_controller = PermissionController::new(
+        client.clone(),
+        &scope,
+        cache,
+        generation.clone(),
+        metrics.clone(),
+        shutdown.clone(),
+    );
     let rbac_controller = Arc::new(RBACController {
         grant_controller,
         permission_controller,
     });
-    let server = HttpServer::new(move || {
+    rbac_controller.report_permission_changes(shutdown.clone());
+    let debug_endpoints = match env_flag("DEBUG_ENDPOINTS") {
+        Ok(enabled) => enabled,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let audit_config = AuditConfig {
+        managed_by_ignore: env_list("MANAGED_BY_IGNORE"),
+    };
+    // set but empty puts users in no groups at all
+    let implicit_groups = match env::var("USER_IMPLICIT_GROUPS") {
+        Ok(_) => ImplicitGroups {
+            users: env_list("USER_IMPLICIT_GROUPS"),
+        },
+        Err(_) => ImplicitGroups::default(),
+    };
+    let tcp_backlog = match env_parse::<u32>("TCP_BACKLOG") {
+        Ok(Some(0)) => {
+            return Err(std::io::Error::other(
+                "invalid TCP_BACKLOG 0, expected a positive number",
+            ))
+        }
+        Ok(backlog) => backlog,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let tcp_nodelay = match env_parse::<bool>("TCP_NODELAY") {
+        Ok(nodelay) => nodelay,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let request_timeout = match env_millis("REQUEST_TIMEOUT_MS", DEFAULT_REQUEST_TIMEOUT) {
+        Ok(timeout) => timeout,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let keep_alive = match env_secs("KEEP_ALIVE_SECS", DEFAULT_KEEP_ALIVE) {
+        Ok(keep_alive) => keep_alive,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let max_connections = match env_positive("MAX_CONNECTIONS", DEFAULT_MAX_CONNECTIONS) {
+        Ok(max_connections) => max_connections,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    // actix's default, one worker per cpu
+    let default_workers = std::thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    let workers = match env_positive("WORKERS", default_workers) {
+        Ok(workers) => workers,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let access_log = match access_log_enabled() {
+        Ok(enabled) => enabled,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let admin_port = match env_parse::<u16>("ADMIN_PORT") {
+        Ok(port) => port,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let redirect_port = match env_parse::<u16>("HTTP_REDIRECT_PORT") {
+        Ok(port) => port,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let admin_app_data = (
+        Arc::clone(&rbac_controller),
+        api_server_check.clone(),
+        metrics.clone(),
+    );
+    // patterns of the unprefixed routes whose deprecation was already logged, shared by the workers
+    let deprecated_routes: Arc<Mutex<HashSet<String>>> = Arc::default();
+    let mut server = HttpServer::new(move || {
+        let generation = generation.clone();
+        let deprecated_routes = Arc::clone(&deprecated_routes);
         App::new()
+            // the generation the response reflects, left out if the state changed while it was built
+            .wrap_fn(move |req, srv| {
+                let snapshot = generation.snapshot();
+                let generation = generation.clone();
+                let response = srv.call(req);
+                async move {
+                    let mut response = response.await?;
+                    if let Some(current) = generation.unchanged_since(snapshot) {
+                        response.headers_mut().insert(
+                            HeaderName::from_static(CACHE_GENERATION_HEADER),
+                            HeaderValue::from(current),
+                        );
+                    }
+                    Ok(response)
+                }
+            })
+            .wrap(Condition::new(access_log, Logger::new(ACCESS_LOG_FORMAT)))
             .app_data(web::Data::new(Arc::clone(&rbac_controller)))
-            .route("/health", web::get().to(health))
-            .route("/grants", web::get().to(get_all_grants))
+            .app_data(web::Data::new(client.clone()))
+            .app_data(web::Data::new(start))
+            .app_data(web::Data::new(audit_config.clone()))
+            .app_data(web::Data::new(implicit_groups.clone()))
+            .app_data(web::Data::new(api_server_check.clone()))
+            .app_data(web::Data::new(metrics.clone()))
+            .app_data(web::JsonConfig::default().error_handler(json_error_handler))
+            .configure(|config| {
+                if admin_port.is_none() {
+                    admin_endpoints(config)
+                }
+            })
+            .route("/version", web::get().to(get_version))
+            .route("/openapi.json", web::get().to(get_openapi))
+            .service(
+                web::scope(API_PREFIX).configure(|config| api_endpoints(config, debug_endpoints)),
+            )
+            // the paths from before API_PREFIX, kept working for one more release
+            .service(
+                web::scope("")
+                    .wrap_fn(move |req, srv| {
+                        let deprecated_routes = Arc::clone(&deprecated_routes);
+                        let response = srv.call(req);
+                        async move {
+                            let response = response.await?;
+                            // unknown paths also end up here, but they never existed
+                            if let Some(pattern) = response.request().match_pattern() {
+                                let path = response.request().path();
+                                // warned about once per route, so that polling clients don't flood the log
+                                let first_use = deprecated_routes.lock().unwrap().insert(pattern);
+                                if first_use {
+                                    warn!(
+                                        "{} is deprecated and will be removed, use {}{} instead",
+                                        path, API_PREFIX, path
+                                    );
+                                } else {
+                                    debug!(
+                                        "{} is deprecated and will be removed, use {}{} instead",
+                                        path, API_PREFIX, path
+                                    );
+                                }
+                            }
+                            Ok(response)
+                        }
+                    })
+                    .configure(|config| api_endpoints(config, debug_endpoints)),
+            )
     });
-    match get_ssl_config() {
+    // both have to be set before binding, unset values keep actix's defaults
+    if let Some(backlog) = tcp_backlog {
+        server = server.backlog(backlog);
+    }
+    if let Some(nodelay) = tcp_nodelay {
+        server = server.tcp_nodelay(nodelay);
+    }
+    info!(
+        "TCP backlog: {}, TCP_NODELAY: {}",
+        tcp_backlog.map_or("default".to_string(), |backlog| backlog.to_string()),
+        tcp_nodelay.map_or("default".to_string(), |nodelay| nodelay.to_string())
+    );
+    // slow or idle clients can't hold on to connections for long, and the connections are limited
+    server = server
+        .client_request_timeout(request_timeout)
+        .keep_alive(keep_alive)
+        .max_connections(max_connections)
+        .workers(workers);
+    info!(
+        "Request timeout: {:?}, keep-alive: {:?}, max connections: {} per worker, workers: {}",
+        request_timeout, keep_alive, max_connections, workers
+    );
+    let addr = match listen_addr() {
+        Ok(addr) => addr,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    let tls_required = match env_flag("TLS_REQUIRED") {
+        Ok(required) => required,
+        Err(err) => return Err(std::io::Error::other(err.to_string())),
+    };
+    // signals are handled by stop_on_signal, so that the background tasks are stopped along with the server
+    server = server.disable_signals();
+    let mut servers = Vec::new();
+    if let Some(port) = admin_port {
+        let admin_addr = SocketAddr::new(addr.ip(), port);
+        let (rbac_controller, api_server_check, metrics) = admin_app_data;
+        let admin_server = HttpServer::new(move || {
+            App::new()
+                .app_data(web::Data::new(Arc::clone(&rbac_controller)))
+                .app_data(web::Data::new(api_server_check.clone()))
+                .app_data(web::Data::new(metrics.clone()))
+                .configure(admin_endpoints)
+        })
+        .client_request_timeout(request_timeout)
+        .keep_alive(keep_alive)
+        .max_connections(max_connections)
+        .workers(1)
+        .disable_signals();
+        info!(
+            "Serving /health, /readyz, /ready and /metrics without ssl on {}",
+            admin_addr
+        );
+        servers.push(admin_server.bind(admin_addr)?.run());
+    }
+    let server = match get_ssl_config() {
         Ok(config) => {
-            info!("Using openssl");
-            server.bind_rustls("127.0.0.1:8080", config)?.run().await
+            info!("Using openssl, listening on {}", addr);
+            if let Some(port) = redirect_port {
+                let redirect_addr = SocketAddr::new(addr.ip(), port);
+                let tls_port = addr.port();
+                let redirect_server = HttpServer::new(move || {
+                    App::new().default_service(web::to(move |req: HttpRequest| {
+                        redirect_to_https(req, tls_port)
+                    }))
+                })
+                .client_request_timeout(request_timeout)
+                .keep_alive(keep_alive)
+                .workers(1)
+                .disable_signals();
+                info!(
+                    "Redirecting plaintext requests on {} to https on port {}",
+                    redirect_addr, tls_port
+                );
+                servers.push(redirect_server.bind(redirect_addr)?.run());
+            }
+            server.bind_rustls(addr, config)?.run()
+        }
+        Err(err) if tls_required => {
+            return Err(std::io::Error::other(format!(
+                "Unable to configure ssl and TLS_REQUIRED is set: {}",
+                err
+            )))
         }
         Err(err) => {
-            info!(
-                "Unable to configure ssl with err {}, will run without ssl",
+            if err.downcast_ref::<CertKeyMismatch>().is_some() {
+                error!("{}, will run without ssl", err);
+            } else {
+                info!(
+                    "Unable to configure ssl with err {}, will run without ssl",
+                    err
+                );
+            }
+            if redirect_port.is_some() {
+                warn!("HTTP_REDIRECT_PORT is set but ssl isn't configured, not redirecting");
+            }
+            info!("Listening on {}", addr);
+            server.bind(addr)?.run()
+        }
+    };
+    servers.push(server);
+    serve_until_signal(servers, shutdown).await
+}
+
+/// permanently redirects a plaintext request on HTTP_REDIRECT_PORT to the same path and query over https on tls_port,
+/// on the host the client asked for
+async fn redirect_to_https(req: HttpRequest, tls_port: u16) -> HttpResponse {
+    let host = req.connection_info().host().to_string();
+    // ipv6 hosts are bracketed, so a colon after the closing bracket is the only one starting a port
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.ends_with(']') => name.to_string(),
+        _ => host,
+    };
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    HttpResponse::PermanentRedirect()
+        .insert_header((LOCATION, format!("https://{}:{}{}", host, tls_port, path)))
+        .finish()
+}
+
+/// the health and metrics endpoints, served on ADMIN_PORT when it's set and along with the others otherwise
+fn admin_endpoints(config: &mut web::ServiceConfig) {
+    config
+        .route("/health", web::get().to(health))
+        .route("/readyz", web::get().to(readiness))
+        .route("/ready", web::get().to(readiness))
+        .route("/metrics", web::get().to(get_metrics));
+}
+
+/// the endpoints serving data, which are versioned under API_PREFIX
+fn api_endpoints(config: &mut web::ServiceConfig, debug_endpoints: bool) {
+    config
+        .route("/grants", web::get().to(get_all_grants))
+        .route("/grants/subjects", web::post().to(get_grants_for_subjects))
+        .route("/grants/watch", web::get().to(watch_grants))
+        .route("/grants/conflicts", web::get().to(get_grant_conflicts))
+        .route(
+            "/grants/{kind}/{namespace}/{name}",
+            web::get().to(get_subject_grants),
+        )
+        .route("/grants/{kind}/{name}", web::get().to(get_subject_grants))
+        .route("/subjects", web::get().to(get_subjects))
+        .route("/can-i", web::post().to(can_i))
+        .route("/can-i/batch", web::post().to(can_i_batch))
+        .route(
+            "/subjects-for-permission",
+            web::post().to(get_subjects_for_permission),
+        )
+        .route("/audit/escalation", web::get().to(get_escalation_audit))
+        .route("/audit/top-subjects", web::get().to(get_top_subjects))
+        .route("/audit/unused-roles", web::get().to(get_unused_roles))
+        .route("/simulate/delete", web::post().to(simulate_delete))
+        .route("/diff", web::post().to(diff_permissions))
+        .route(
+            "/roles/{rbac_type}/{namespace}/{name}/affected-subjects",
+            web::get().to(get_affected_subjects),
+        )
+        .route(
+            "/roles/{rbac_type}/{name}/affected-subjects",
+            web::get().to(get_affected_subjects),
+        )
+        .route("/stats", web::get().to(get_stats))
+        .route("/stats/breakdown", web::get().to(get_breakdown))
+        .route("/permissions", web::post().to(get_permissions))
+        .route(
+            "/permissions/subject/effective",
+            web::get().to(get_effective_permissions),
+        )
+        .route(
+            "/permissions/subject/matrix",
+            web::get().to(get_permission_matrix),
+        )
+        .route(
+            "/permissions/subject/namespaces",
+            web::get().to(get_namespace_access),
+        );
+    if debug_endpoints {
+        config
+            .route("/debug/grant/raw", web::get().to(get_raw_grant))
+            .route("/debug/unresolved", web::get().to(get_unresolved_grants))
+            .route("/debug/malformed-rules", web::get().to(get_malformed_rules));
+    }
+}
+
+/// runs the servers until they're stopped by SIGTERM/SIGINT. Open connections get actix's shutdown timeout to finish,
+/// and the background tasks are cancelled so they don't log errors about the api server going away mid-shutdown
+async fn serve_until_signal(
+    servers: Vec<Server>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let handles: Vec<ServerHandle> = servers.iter().map(Server::handle).collect();
+    rt::spawn(stop_on_signal(handles.clone(), shutdown.clone()));
+    let (first, _, others) = select_all(servers).await;
+    // also stops the other servers and the background tasks if a server stopped by itself
+    shutdown.cancel();
+    // the other servers have to be polled to finish stopping
+    let (_, others) = join(stop_servers(&handles), join_all(others)).await;
+    let result = others
+        .into_iter()
+        .fold(first, |result, other| result.and(other));
+    if result.is_ok() {
+        info!("Shut down cleanly");
+    }
+    result
+}
+
+async fn stop_on_signal(handles: Vec<ServerHandle>, shutdown: CancellationToken) {
+    let signal = wait_for_signal().await;
+    info!("Received {}, shutting down", signal);
+    shutdown.cancel();
+    stop_servers(&handles).await;
+}
+
+/// gracefully stops every server, those which already stopped are skipped by actix
+async fn stop_servers(handles: &[ServerHandle]) {
+    join_all(handles.iter().map(|handle| handle.stop(true))).await;
+}
+
+/// waits for SIGTERM (sent by kubernetes on pod termination) or SIGINT, returning the name of the one received
+#[cfg(unix)]
+async fn wait_for_signal() -> &'static str {
+    use futures::future::{select, Either};
+    use futures::pin_mut;
+    use rt::signal::unix::{signal, SignalKind};
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(err) => {
+            error!(
+                "Unable to listen for SIGTERM, only SIGINT stops the server: {}",
                 err
             );
-            server.bind(("127.0.0.1", 8080))?.run().await
+            let _ = rt::signal::ctrl_c().await;
+            return "SIGINT";
         }
+    };
+    let sigterm = terminate.recv();
+    let sigint = rt::signal::ctrl_c();
+    pin_mut!(sigterm, sigint);
+    match select(sigterm, sigint).await {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
     }
 }
 
-fn get_ssl_config() -> Result<ServerConfig, Box<dyn Error>> {
-    // adapted from https://github.com/actix/examples/blob/ce10427457ea187b9c189367d136e7504fef0c2d/https-tls/rustls/src/main.rs#L44
-    let config = ServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth();
+#[cfg(not(unix))]
+async fn wait_for_signal() -> &'static str {
+    let _ = rt::signal::ctrl_c().await;
+    "SIGINT"
+}
+
+/// the key in TLS_CERT_DIR doesn't belong to the certificate. Usually one of the two was replaced without the other
+#[derive(Debug)]
+struct CertKeyMismatch(String);
+
+impl fmt::Display for CertKeyMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "key.pem doesn't match cert.pem in TLS_CERT_DIR ({}) - check that both come from the same certificate/secret",
+            self.0
+        )
+    }
+}
+
+impl Error for CertKeyMismatch {}
 
+fn get_ssl_config() -> Result<ServerConfig, Box<dyn Error>> {
     // try to read the location of the certs from the TLS_CERT_DIR directory
     let dir_path = env::var("TLS_CERT_DIR")?;
     let key_path: String;
@@ -77,23 +540,105 @@ fn get_ssl_config() -> Result<ServerConfig, Box<dyn Error>> {
         cert_path = dir_path + "/cert.pem";
     };
 
-    let cert_file = File::open(cert_path)?;
-    let key_file = File::open(key_path)?;
+    let cert_pem = std::fs::read(cert_path)?;
+    let key_pem = std::fs::read(key_path)?;
+    ssl_config_from_pem(&cert_pem, &key_pem)
+}
 
-    let cert_reader = &mut BufReader::new(cert_file);
-    let key_reader = &mut BufReader::new(key_file);
-    let cert_chain: Vec<Certificate> = certs(cert_reader)
-        .unwrap()
+/// the server config serving the certificate chain of cert_pem with the private key of key_pem
+fn ssl_config_from_pem(cert_pem: &[u8], key_pem: &[u8]) -> Result<ServerConfig, Box<dyn Error>> {
+    // adapted from https://github.com/actix/examples/blob/ce10427457ea187b9c189367d136e7504fef0c2d/https-tls/rustls/src/main.rs#L44
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth();
+
+    let cert_chain: Vec<Certificate> = certs(&mut &cert_pem[..])?
         .into_iter()
         .map(Certificate)
         .collect();
+    if cert_chain.is_empty() {
+        return Err("cert.pem contains no certificate".into());
+    }
+    let key = read_private_key(key_pem)?;
+    // rustls only notices a mismatched key during the first handshake, check it up front
+    check_key_matches_cert(&cert_chain[0], &key)?;
 
-    let mut keys: Vec<PrivateKey> = pkcs8_private_keys(key_reader)
-        .unwrap()
-        .into_iter()
-        .map(PrivateKey)
-        .collect();
+    let config = config.with_single_cert(cert_chain, key)?;
+    Ok(config)
+}
+
+/// reads every key of one format out of a pem
+type KeyParser = fn(&mut dyn BufRead) -> std::io::Result<Vec<Vec<u8>>>;
+
+/// key formats accepted in key.pem, in the order they're tried: PKCS#8 (`BEGIN PRIVATE KEY`), PKCS#1 RSA
+/// (`BEGIN RSA PRIVATE KEY`) and SEC1 EC (`BEGIN EC PRIVATE KEY`)
+const KEY_PARSERS: &[KeyParser] = &[pkcs8_private_keys, rsa_private_keys, ec_private_keys];
+
+/// the first private key of the pem, in the first of KEY_PARSERS' formats it has a key in
+fn read_private_key(pem: &[u8]) -> Result<PrivateKey, Box<dyn Error>> {
+    for parse in KEY_PARSERS {
+        if let Some(key) = parse(&mut &pem[..])?.into_iter().next() {
+            return Ok(PrivateKey(key));
+        }
+    }
+    Err("key.pem contains no PKCS#8, PKCS#1 (RSA) or EC private key".into())
+}
+
+/// signature algorithms of the schemes check_key_matches_cert can sign with, one per type of key
+const KEY_CHECK_SCHEMES: &[(SignatureScheme, &webpki::SignatureAlgorithm)] = &[
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+    (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+    (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256),
+];
+
+/// checks that the key belongs to the certificate by signing a message with the key and verifying the signature
+/// with the certificate's public key. Errors with CertKeyMismatch if it doesn't
+fn check_key_matches_cert(cert: &Certificate, key: &PrivateKey) -> Result<(), Box<dyn Error>> {
+    const MESSAGE: &[u8] = b"user-manifest tls key check";
+    let signing_key = any_supported_type(key)?;
+    let schemes: Vec<SignatureScheme> = KEY_CHECK_SCHEMES.iter().map(|(scheme, _)| *scheme).collect();
+    let signer = signing_key
+        .choose_scheme(&schemes)
+        .ok_or("key.pem holds an unsupported type of key")?;
+    let signature = signer.sign(MESSAGE)?;
+    let algorithm = KEY_CHECK_SCHEMES
+        .iter()
+        .find(|(scheme, _)| *scheme == signer.scheme())
+        .map(|(_, algorithm)| *algorithm)
+        .ok_or("key.pem holds an unsupported type of key")?;
+    let end_entity = webpki::EndEntityCert::try_from(cert.0.as_slice())
+        .map_err(|err| format!("unable to parse cert.pem: {:?}", err))?;
+    end_entity
+        .verify_signature(algorithm, MESSAGE, &signature)
+        .map_err(|err| CertKeyMismatch(format!("{:?}", err)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // testdata/tls holds a self-signed certificate with its (PKCS#8) key, and an unrelated key, made with openssl
+    const CERT: &[u8] = include_bytes!("../testdata/tls/cert.pem");
+    const KEY: &[u8] = include_bytes!("../testdata/tls/key.pem");
+    /// the same key as KEY, in the PKCS#1 format
+    const KEY_RSA: &[u8] = include_bytes!("../testdata/tls/key-rsa.pem");
+    const OTHER_KEY: &[u8] = include_bytes!("../testdata/tls/other-key.pem");
 
-    let config = config.with_single_cert(cert_chain, keys.remove(0))?;
-    return Ok(config);
+    #[test]
+    fn matching_key_builds_config() {
+        assert!(ssl_config_from_pem(CERT, KEY).is_ok());
+    }
+
+    #[test]
+    fn pkcs1_key_builds_config() {
+        assert!(ssl_config_from_pem(CERT, KEY_RSA).is_ok());
+    }
+
+    #[test]
+    fn mismatched_key_is_reported() {
+        let err = ssl_config_from_pem(CERT, OTHER_KEY).unwrap_err();
+        assert!(err.downcast_ref::<CertKeyMismatch>().is_some(), "{}", err);
+    }
 }